@@ -1,23 +1,1343 @@
-use crate::ohua_types::{ArcIdentifier, ArcSource, DirectArc, OhuaData};
+use crate::ohua_types::{
+    ArcIdentifier, ArcSource, DirectArc, Envs, Index, NodeType, OhuaData, OpId, Operator,
+};
+use std::collections::{BTreeMap, BTreeSet};
 // use typedgen::get_out_arcs;
 
+const OHUA_RUNTIME_NAMESPACE: [&str; 2] = ["ohua_runtime", "lang"];
+
+/// The optimization passes of `run_backend_optimizations`, which can be switched off one by one
+/// via `#[ohua(skip_passes = [...])]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Pass {
+    /// Merges a `collect` into a `reduce`, `somes` or `until_break` consuming its result.
+    CollectFusion,
+    /// Runs the functions inside the branches of a conditional only when the branch is taken.
+    BranchGating,
+    /// Evaluates runtime functions that only depend on literals.
+    ConstantFolding,
+    /// Removes the `id` operators for arguments of the algorithm that are used only once.
+    RedundantIds,
+    /// Takes a tuple apart with a single `destruct` instead of one `nth` per element.
+    NthFusion,
+    /// Removes runtime operators whose results are never used.
+    DeadOperators,
+}
+
+impl Pass {
+    pub const ALL: [Pass; 6] = [
+        Pass::CollectFusion,
+        Pass::BranchGating,
+        Pass::ConstantFolding,
+        Pass::RedundantIds,
+        Pass::NthFusion,
+        Pass::DeadOperators,
+    ];
+
+    /// The name of the pass as used in `#[ohua(skip_passes = [...])]`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Pass::CollectFusion => "collect_fusion",
+            Pass::BranchGating => "branch_gating",
+            Pass::ConstantFolding => "constant_folding",
+            Pass::RedundantIds => "redundant_ids",
+            Pass::NthFusion => "nth_fusion",
+            Pass::DeadOperators => "dead_operators",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Pass> {
+        Pass::ALL.iter().cloned().find(|pass| pass.name() == name)
+    }
+}
+
+/// The passes to run, all of them unless skipped.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Passes {
+    skipped: BTreeSet<Pass>,
+}
+
+impl Passes {
+    pub fn skip(&mut self, pass: Pass) {
+        self.skipped.insert(pass);
+    }
+
+    pub fn runs(&self, pass: Pass) -> bool {
+        !self.skipped.contains(&pass)
+    }
+}
+
 /// HACK: Removes dead end arcs and appends them to the direct arc list with
 /// target (0, 0). This is due to the fact that at the point when DeadArcs were
 /// introduced, DirectArcs were already so deeply embedded into the arc generation,
 /// that this seemed simpler.
 fn process_dead_ends(compiled_algo: &mut OhuaData) {
     for dead_arc in compiled_algo.graph.arcs.dead.drain(..) {
+        compiled_algo.graph.arcs.direct.push(dead_end(dead_arc.source));
+    }
+}
+
+/// An arc discarding everything sent via `source`, see `process_dead_ends`.
+fn dead_end(source: ArcIdentifier) -> DirectArc {
+    DirectArc {
+        target: ArcIdentifier {
+            operator: 0,
+            index: 0,
+        },
+        source: ArcSource::Local(source),
+    }
+}
+
+fn is_from(source: &ArcSource, op: OpId) -> bool {
+    match *source {
+        ArcSource::Local(ref src) => src.operator == op,
+        _ => false,
+    }
+}
+
+/// The name of `op` if it is a function or operator of the runtime.
+fn runtime_name(op: &Operator) -> Option<&str> {
+    if op.operatorType.qbNamespace == OHUA_RUNTIME_NAMESPACE {
+        Some(op.operatorType.qbName.as_str())
+    } else {
+        None
+    }
+}
+
+fn operator_exists(compiled_algo: &OhuaData, op: OpId) -> bool {
+    compiled_algo
+        .graph
+        .operators
+        .iter()
+        .any(|o| o.operatorId == op)
+}
+
+/// Whether anything but a dead end receives the results of `op`.
+fn has_consumers(compiled_algo: &OhuaData, op: OpId) -> bool {
+    compiled_algo.graph.arcs.state.iter().any(|arc| is_from(&arc.source, op))
+        || compiled_algo.graph.arcs.direct.iter().any(|arc| {
+            is_from(&arc.source, op) && operator_exists(compiled_algo, arc.target.operator)
+        })
+}
+
+/// Removes `op` along with all arcs leading to and from it. Outputs of other operators that
+/// are left without any consumer lead to a dead end instead, as every output of an operator
+/// needs an arc.
+fn remove_operator(compiled_algo: &mut OhuaData, op: OpId) {
+    let graph = &mut compiled_algo.graph;
+    graph.operators.retain(|o| o.operatorId != op);
+    graph
+        .arcs
+        .state
+        .retain(|arc| arc.target != op && !is_from(&arc.source, op));
+
+    let (removed, kept): (Vec<DirectArc>, Vec<DirectArc>) = graph
+        .arcs
+        .direct
+        .drain(..)
+        .partition(|arc| arc.target.operator == op || is_from(&arc.source, op));
+    graph.arcs.direct = kept;
+    for arc in removed {
+        if let ArcSource::Local(src) = arc.source {
+            let served = graph.arcs.direct.iter().any(|a| match a.source {
+                ArcSource::Local(ref s) => s.operator == src.operator && s.index == src.index,
+                _ => false,
+            });
+            if src.operator != op && !served {
+                graph.arcs.direct.push(dead_end(src));
+            }
+        }
+    }
+}
+
+fn is_runtime_fn(compiled_algo: &OhuaData, op_id: OpId, name: &str) -> bool {
+    compiled_algo
+        .graph
+        .operators
+        .iter()
+        .find(|op| op.operatorId == op_id)
+        .map(|op| {
+            op.operatorType.qbNamespace == OHUA_RUNTIME_NAMESPACE && op.operatorType.qbName == name
+        })
+        .unwrap_or(false)
+}
+
+/// Finds all `(collect, consumer)` operator pairs where `consumer` is the runtime function
+/// with the given name and the one and only consumer of the vector produced by `collect`.
+fn find_collect_pairs(compiled_algo: &OhuaData, consumer: &str) -> Vec<(OpId, OpId)> {
+    compiled_algo
+        .graph
+        .operators
+        .iter()
+        .filter(|op| is_runtime_fn(compiled_algo, op.operatorId, "collect"))
+        .filter_map(|collect| {
+            let consumers: Vec<&DirectArc> = compiled_algo
+                .graph
+                .arcs
+                .direct
+                .iter()
+                .filter(|arc| match arc.source {
+                    ArcSource::Local(ref src) => src.operator == collect.operatorId,
+                    _ => false,
+                })
+                .collect();
+
+            match consumers.as_slice() {
+                [arc] if arc.target.index == 0
+                    && is_runtime_fn(compiled_algo, arc.target.operator, consumer) =>
+                {
+                    Some((collect.operatorId, arc.target.operator))
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Merges a `collect` into its sole consumer, which becomes the operator `fused`. The
+/// fused operator takes over the inputs of `collect` (size and data) at positions 0 and 1,
+/// followed by the remaining inputs of the consumer.
+fn fuse_collect(compiled_algo: &mut OhuaData, collect: OpId, consumer: OpId, fused: &str) {
+    let arcs = &mut compiled_algo.graph.arcs.direct;
+    arcs.retain(|arc| arc.target.operator != consumer || arc.target.index != 0);
+    for arc in arcs.iter_mut() {
+        if arc.target.operator == consumer {
+            arc.target.index += 1;
+        } else if arc.target.operator == collect {
+            arc.target.operator = consumer;
+        }
+    }
+
+    compiled_algo
+        .graph
+        .operators
+        .retain(|op| op.operatorId != collect);
+    for op in compiled_algo.graph.operators.iter_mut() {
+        if op.operatorId == consumer {
+            op.operatorType.qbName = fused.into();
+            op.nodeType = NodeType::OperatorNode;
+        }
+    }
+}
+
+/// Whether input port `index` of operator `op` is fed from the environment, i.e., receives a
+/// single value per run.
+fn is_env_input(compiled_algo: &OhuaData, op: OpId, index: Index) -> bool {
+    compiled_algo.graph.arcs.direct.iter().any(|arc| {
+        arc.target.operator == op
+            && arc.target.index == index
+            && match arc.source {
+                ArcSource::Env(_) => true,
+                ArcSource::Local(_) => false,
+            }
+    })
+}
+
+/// Replaces a `collect` whose result is only ever handed to `reduce` by a single `fold`
+/// operator. This way, the items of a loop are combined as soon as they arrive and the
+/// intermediate vector is never built.
+///
+/// `fold` receives its initial value and function only once, so a `reduce` whose `init` or
+/// `f` is computed anew for every loop, e.g., inside an outer loop, is left alone.
+fn fuse_collect_reduce(compiled_algo: &mut OhuaData) {
+    for (collect, reduce) in find_collect_pairs(compiled_algo, "reduce") {
+        if is_env_input(compiled_algo, reduce, 1) && is_env_input(compiled_algo, reduce, 2) {
+            fuse_collect(compiled_algo, collect, reduce, "fold");
+        }
+    }
+}
+
+/// Replaces a `collect` whose result is only ever handed to `somes` by `collect_some`,
+/// which drops `None` results right away.
+fn fuse_collect_somes(compiled_algo: &mut OhuaData) {
+    for (collect, somes) in find_collect_pairs(compiled_algo, "somes") {
+        fuse_collect(compiled_algo, collect, somes, "collect_some");
+    }
+}
+
+/// Turns a loop whose result is only ever handed to `until_break` into a loop that can be
+/// stopped early: the `smapFun` becomes a `smapBreakFun` and the `collect` is merged
+/// into a `collect_break`, which gets an additional output port 0 that feeds back into
+/// input port 1 of `smapBreakFun`. The results of the loop move to output port 1.
+fn fuse_collect_until_break(compiled_algo: &mut OhuaData) {
+    for (collect, until) in find_collect_pairs(compiled_algo, "until_break") {
+        let smap = compiled_algo
+            .graph
+            .arcs
+            .direct
+            .iter()
+            .find(|arc| arc.target.operator == collect && arc.target.index == 0)
+            .and_then(|arc| match arc.source {
+                ArcSource::Local(ref src) => Some(src.operator),
+                _ => None,
+            })
+            .filter(|op| is_runtime_fn(compiled_algo, *op, "smapFun"));
+        let smap = match smap {
+            Some(op) => op,
+            None => continue,
+        };
+
+        fuse_collect(compiled_algo, collect, until, "collect_break");
+        for arc in compiled_algo.graph.arcs.direct.iter_mut() {
+            if let ArcSource::Local(ref mut src) = arc.source {
+                if src.operator == until {
+                    src.index = 1;
+                }
+            }
+        }
         compiled_algo.graph.arcs.direct.push(DirectArc {
             target: ArcIdentifier {
-                operator: 0,
-                index: 0,
+                operator: smap,
+                index: 1,
             },
-            source: ArcSource::Local(dead_arc.source),
+            source: ArcSource::Local(ArcIdentifier {
+                operator: until,
+                index: 0,
+            }),
+        });
+
+        for op in compiled_algo.graph.operators.iter_mut() {
+            if op.operatorId == smap {
+                op.operatorType.qbName = "smapBreakFun".into();
+            }
+        }
+    }
+}
+
+fn local_sources(compiled_algo: &OhuaData, op: OpId) -> Vec<&ArcIdentifier> {
+    compiled_algo
+        .graph
+        .arcs
+        .direct
+        .iter()
+        .filter(|arc| arc.target.operator == op)
+        .filter_map(|arc| match arc.source {
+            ArcSource::Local(ref src) => Some(src),
+            _ => None,
+        })
+        .collect()
+}
+
+fn source_of(compiled_algo: &OhuaData, op: OpId, index: Index) -> Option<&ArcIdentifier> {
+    compiled_algo
+        .graph
+        .arcs
+        .direct
+        .iter()
+        .find(|arc| arc.target.operator == op && arc.target.index == index)
+        .and_then(|arc| match arc.source {
+            ArcSource::Local(ref src) => Some(src),
+            _ => None,
+        })
+}
+
+fn is_function_node(compiled_algo: &OhuaData, op: OpId) -> bool {
+    compiled_algo
+        .graph
+        .operators
+        .iter()
+        .any(|o| o.operatorId == op && o.nodeType == NodeType::FunctionNode)
+}
+
+/// Collects all function nodes that feed input `port` of `op` without passing any operator.
+fn function_node_upstream(compiled_algo: &OhuaData, op: OpId, port: Index) -> BTreeSet<OpId> {
+    let mut seen = BTreeSet::new();
+    let mut todo: Vec<OpId> = source_of(compiled_algo, op, port)
+        .map(|src| vec![src.operator])
+        .unwrap_or_default();
+    while let Some(op) = todo.pop() {
+        if is_function_node(compiled_algo, op) && seen.insert(op) {
+            todo.extend(local_sources(compiled_algo, op).iter().map(|src| src.operator));
+        }
+    }
+    seen
+}
+
+/// Determines whether the output of `op` is only ever computed if the branch of a
+/// conditional controlled by the `ifFun` output port `branch` is taken, i.e., whether
+/// any of its inputs depends on that port.
+fn is_gated_by(
+    compiled_algo: &OhuaData,
+    op: OpId,
+    branch: &ArcIdentifier,
+    visited: &mut BTreeSet<OpId>,
+) -> bool {
+    if !visited.insert(op) {
+        return false;
+    }
+    local_sources(compiled_algo, op).iter().any(|src| {
+        (src.operator == branch.operator && src.index == branch.index)
+            || is_gated_by(compiled_algo, src.operator, branch, visited)
+    })
+}
+
+/// A function node is a gating candidate for a branch if it takes no inputs besides
+/// literals and its result is used exclusively inside that branch.
+fn is_gating_candidate(
+    compiled_algo: &OhuaData,
+    op: OpId,
+    select: OpId,
+    branch: &BTreeSet<OpId>,
+    other_branch: &BTreeSet<OpId>,
+) -> bool {
+    let literal_inputs_only = compiled_algo
+        .graph
+        .arcs
+        .direct
+        .iter()
+        .filter(|arc| arc.target.operator == op)
+        .all(|arc| {
+            !matches!(
+                arc.source,
+                ArcSource::Env(Envs::EnvRefLit { .. }) | ArcSource::Local(_)
+            )
+        });
+    let exclusive = compiled_algo
+        .graph
+        .arcs
+        .direct
+        .iter()
+        .filter(|arc| match arc.source {
+            ArcSource::Local(ref src) => src.operator == op,
+            _ => false,
+        })
+        .all(|arc| arc.target.operator == select || branch.contains(&arc.target.operator));
+
+    literal_inputs_only && exclusive && !other_branch.contains(&op)
+}
+
+/// Makes sure that values of the branch of a conditional that is not taken are neither
+/// needlessly computed nor left behind in the input queues of `select`.
+///
+/// Branches usually receive their inputs through a `ctrl` that is driven by the `ifFun`
+/// and hence only compute something when taken. Function nodes inside a branch that do not
+/// depend on any input (e.g., producers of constants) are run regardless, though. These get
+/// an additional control input (index -1) from the `ifFun` output port of their branch, so
+/// they are only executed as often as the branch is taken.
+///
+/// Branch results that are still computed irrespective of the decision are drained by turning
+/// the `select` into a `select_drain`, which receives a bit mask (1: true branch,
+/// 2: else branch) of the branches to drain at input port 3.
+///
+/// Only functions without inputs besides literals are gated. A function of a branch that takes
+/// an argument of the algorithm or a value computed outside the branch, without a `ctrl` in
+/// between, is still run when the branch is not taken. Gating it would have to drain its
+/// inputs and clone the arguments for every call. Its result is merely dropped by
+/// `select_drain`.
+fn gate_branches(compiled_algo: &mut OhuaData) {
+    let selects: Vec<OpId> = compiled_algo
+        .graph
+        .operators
+        .iter()
+        .filter(|op| is_runtime_fn(compiled_algo, op.operatorId, "select"))
+        .map(|op| op.operatorId)
+        .collect();
+
+    for select in selects {
+        let decision = match source_of(compiled_algo, select, 0) {
+            Some(src) => src.clone(),
+            None => continue,
+        };
+        let if_fun = compiled_algo
+            .graph
+            .operators
+            .iter()
+            .filter(|op| is_runtime_fn(compiled_algo, op.operatorId, "ifFun"))
+            .map(|op| op.operatorId)
+            .find(|op| {
+                source_of(compiled_algo, *op, 0)
+                    .map(|src| src.operator == decision.operator && src.index == decision.index)
+                    .unwrap_or(false)
+            });
+        let if_fun = match if_fun {
+            Some(op) => op,
+            None => continue,
+        };
+
+        let branches = [
+            function_node_upstream(compiled_algo, select, 1),
+            function_node_upstream(compiled_algo, select, 2),
+        ];
+        let mut gates = Vec::new();
+        for (idx, branch) in branches.iter().enumerate() {
+            let other_branch = &branches[1 - idx];
+            for op in branch
+                .iter()
+                .filter(|op| is_gating_candidate(compiled_algo, **op, select, branch, other_branch))
+            {
+                gates.push(DirectArc {
+                    target: ArcIdentifier {
+                        operator: *op,
+                        index: -1,
+                    },
+                    source: ArcSource::Local(ArcIdentifier {
+                        operator: if_fun,
+                        index: idx as Index,
+                    }),
+                });
+            }
+        }
+        compiled_algo.graph.arcs.direct.extend(gates);
+
+        let mut drain = 0;
+        for idx in 0..2 {
+            let branch = ArcIdentifier {
+                operator: if_fun,
+                index: idx,
+            };
+            if let Some(src) = source_of(compiled_algo, select, idx + 1) {
+                if !is_gated_by(compiled_algo, src.operator, &branch, &mut BTreeSet::new()) {
+                    drain |= 1 << idx;
+                }
+            }
+        }
+        if drain != 0 {
+            compiled_algo.graph.arcs.direct.push(DirectArc {
+                target: ArcIdentifier {
+                    operator: select,
+                    index: 3,
+                },
+                source: ArcSource::Env(Envs::NumericLit { content: drain }),
+            });
+            for op in compiled_algo.graph.operators.iter_mut() {
+                if op.operatorId == select {
+                    op.operatorType.qbName = "select_drain".into();
+                }
+            }
+        }
+    }
+}
+
+/// A value known at compile time.
+#[derive(Debug, Clone)]
+enum Constant {
+    Literal(Envs),
+    Tuple(Vec<Constant>),
+}
+
+/// Evaluates the runtime functions `id`, `tuple` and `nth` if all of their inputs are
+/// literals or constant themselves. Functions that result in a literal are removed and their
+/// consumers receive the literal instead.
+fn fold_constants(compiled_algo: &mut OhuaData) {
+    let return_op = compiled_algo.graph.return_arc.operator;
+    let mut constants: BTreeMap<OpId, Constant> = BTreeMap::new();
+    loop {
+        let mut folded = Vec::new();
+        for op in &compiled_algo.graph.operators {
+            let id = op.operatorId;
+            let name = match runtime_name(op) {
+                Some(name) if op.nodeType == NodeType::FunctionNode => name,
+                _ => continue,
+            };
+            if id == return_op || constants.contains_key(&id) {
+                continue;
+            }
+
+            let mut in_arcs: Vec<&DirectArc> = compiled_algo
+                .graph
+                .arcs
+                .direct
+                .iter()
+                .filter(|arc| arc.target.operator == id)
+                .collect();
+            in_arcs.sort_by_key(|arc| arc.target.index);
+            let inputs: Option<Vec<Constant>> = in_arcs
+                .iter()
+                .map(|arc| match arc.source {
+                    _ if arc.target.index < 0 => None,
+                    ArcSource::Env(Envs::EnvRefLit { .. }) => None,
+                    ArcSource::Env(ref lit) => Some(Constant::Literal(lit.clone())),
+                    ArcSource::Local(ref src) => constants.get(&src.operator).cloned(),
+                })
+                .collect();
+
+            let value = match (name, inputs.as_deref()) {
+                ("id", Some([value])) => Some(value.clone()),
+                ("tuple", Some(elements)) => Some(Constant::Tuple(elements.to_vec())),
+                (
+                    "nth",
+                    Some(
+                        [Constant::Literal(Envs::NumericLit { content: idx }), _, Constant::Tuple(elements)],
+                    ),
+                ) => elements.get(*idx as usize).cloned(),
+                _ => None,
+            };
+            if let Some(value) = value {
+                folded.push((id, value));
+            }
+        }
+
+        if folded.is_empty() {
+            break;
+        }
+        constants.extend(folded);
+    }
+
+    for (op, value) in constants {
+        if let Constant::Literal(lit) = value {
+            let consumers: Vec<ArcIdentifier> = compiled_algo
+                .graph
+                .arcs
+                .direct
+                .iter()
+                .filter(|arc| {
+                    is_from(&arc.source, op)
+                        && operator_exists(compiled_algo, arc.target.operator)
+                })
+                .map(|arc| arc.target.clone())
+                .collect();
+            remove_operator(compiled_algo, op);
+            for target in consumers {
+                compiled_algo.graph.arcs.direct.push(DirectArc {
+                    target,
+                    source: ArcSource::Env(lit.clone()),
+                });
+            }
+        }
+    }
+}
+
+/// Hands arguments of the algorithm directly to their consumer instead of passing them through
+/// the `id` operator introduced by `handle_environment_arcs`, if there is a single consumer
+/// that is called only once, i.e., a function without any other inputs. Such a function can
+/// take the argument out of the environment like `id` itself.
+fn remove_redundant_ids(compiled_algo: &mut OhuaData) {
+    let ids: Vec<(OpId, Envs)> = compiled_algo
+        .graph
+        .operators
+        .iter()
+        .filter(|op| runtime_name(op) == Some("id") && op.nodeType == NodeType::FunctionNode)
+        .filter(|op| op.operatorId != compiled_algo.graph.return_arc.operator)
+        .filter_map(|op| {
+            let inputs: Vec<&DirectArc> = compiled_algo
+                .graph
+                .arcs
+                .direct
+                .iter()
+                .filter(|arc| arc.target.operator == op.operatorId)
+                .collect();
+            match inputs.as_slice() {
+                [arc] => match arc.source {
+                    ArcSource::Env(ref lit @ Envs::EnvRefLit { .. }) => {
+                        Some((op.operatorId, lit.clone()))
+                    }
+                    _ => None,
+                },
+                _ => None,
+            }
+        })
+        .collect();
+
+    for (id, lit) in ids {
+        if compiled_algo.graph.arcs.state.iter().any(|arc| is_from(&arc.source, id)) {
+            continue;
+        }
+        let consumers: Vec<&DirectArc> = compiled_algo
+            .graph
+            .arcs
+            .direct
+            .iter()
+            .filter(|arc| {
+                is_from(&arc.source, id) && operator_exists(compiled_algo, arc.target.operator)
+            })
+            .collect();
+        let target = match consumers.as_slice() {
+            [arc] => arc.target.clone(),
+            _ => continue,
+        };
+        let called_once = is_function_node(compiled_algo, target.operator)
+            && compiled_algo
+                .graph
+                .arcs
+                .direct
+                .iter()
+                .filter(|arc| arc.target.operator == target.operator)
+                .all(|arc| {
+                    arc.target.index >= 0
+                        && !matches!(arc.source, ArcSource::Local(ref src) if src.operator != id)
+                });
+        if !called_once {
+            continue;
+        }
+
+        remove_operator(compiled_algo, id);
+        compiled_algo.graph.arcs.direct.push(DirectArc {
+            target,
+            source: ArcSource::Env(lit),
         });
     }
 }
 
-/// Run a set of backend-specific optimizations on the `OhuaData` structure
-pub fn run_backend_optimizations(compiled_algo: &mut OhuaData) {
+/// The index and tuple length of an `nth` and the port of the tuple it takes apart.
+fn nth_info(compiled_algo: &OhuaData, op: OpId) -> Option<(i32, i32, ArcIdentifier)> {
+    let literal = |index: Index| {
+        compiled_algo
+            .graph
+            .arcs
+            .direct
+            .iter()
+            .find(|arc| arc.target.operator == op && arc.target.index == index)
+            .and_then(|arc| match arc.source {
+                ArcSource::Env(Envs::NumericLit { content }) => Some(content),
+                _ => None,
+            })
+    };
+    Some((literal(0)?, literal(1)?, source_of(compiled_algo, op, 2)?.clone()))
+}
+
+/// An `nth` operator along with the index it extracts and the length of the tuple.
+type NthCall = (OpId, i32, i32);
+
+/// Replaces all `nth`s taking apart the same tuple by a single `destruct` operator, which
+/// hands out every element of the tuple via the output port of the same index. The tuple is
+/// thus not cloned for every `nth` anymore. Unused elements lead to a dead end.
+fn fuse_nths(compiled_algo: &mut OhuaData) {
+    let mut groups: BTreeMap<(OpId, Index), Vec<NthCall>> = BTreeMap::new();
+    for op in compiled_algo.graph.operators.iter() {
+        if runtime_name(op) != Some("nth")
+            || op.operatorId == compiled_algo.graph.return_arc.operator
+        {
+            continue;
+        }
+        if let Some((idx, len, tuple)) = nth_info(compiled_algo, op.operatorId) {
+            groups
+                .entry((tuple.operator, tuple.index))
+                .or_default()
+                .push((op.operatorId, idx, len));
+        }
+    }
+
+    for nths in groups.values() {
+        let (destruct, _, len) = nths[0];
+        if nths.len() < 2 || nths.iter().any(|&(_, _, l)| l != len) || len > 12 {
+            continue;
+        }
+
+        for &(nth, idx, _) in nths {
+            for arc in compiled_algo.graph.arcs.direct.iter_mut() {
+                match arc.source {
+                    ArcSource::Local(ref mut src) if src.operator == nth => {
+                        src.operator = destruct;
+                        src.index = idx;
+                    }
+                    _ => (),
+                }
+            }
+        }
+        for &(nth, _, _) in &nths[1..] {
+            remove_operator(compiled_algo, nth);
+        }
+
+        let arcs = &mut compiled_algo.graph.arcs.direct;
+        arcs.retain(|arc| arc.target.operator != destruct || arc.target.index == 2);
+        for arc in arcs.iter_mut().filter(|arc| arc.target.operator == destruct) {
+            arc.target.index = 0;
+        }
+        arcs.retain(|arc| !is_from(&arc.source, destruct) || arc.target.operator != 0);
+        for idx in 0..len {
+            let used = arcs.iter().any(|arc| match arc.source {
+                ArcSource::Local(ref src) => src.operator == destruct && src.index == idx,
+                _ => false,
+            });
+            if !used {
+                arcs.push(dead_end(ArcIdentifier {
+                    operator: destruct,
+                    index: idx,
+                }));
+            }
+        }
+
+        for op in compiled_algo.graph.operators.iter_mut() {
+            if op.operatorId == destruct {
+                op.operatorType.qbName = "destruct".into();
+                op.nodeType = NodeType::OperatorNode;
+            }
+        }
+    }
+}
+
+/// The functions and operators of the runtime that do nothing but compute their results.
+const PURE_OPERATORS: [&str; 10] = [
+    "id", "tuple", "nth", "destruct", "seqFun", "ctrl", "smapFun", "collect", "select", "ifFun",
+];
+
+/// Removes pure runtime operators whose results are never used, until there are none left.
+/// Stateful functions may have side effects and are never removed.
+fn remove_dead_operators(compiled_algo: &mut OhuaData) {
+    loop {
+        let dead: Vec<OpId> = compiled_algo
+            .graph
+            .operators
+            .iter()
+            .filter(|op| {
+                runtime_name(op).is_some_and(|name| PURE_OPERATORS.contains(&name))
+                    && op.operatorId != compiled_algo.graph.return_arc.operator
+                    && !has_consumers(compiled_algo, op.operatorId)
+            })
+            .map(|op| op.operatorId)
+            .collect();
+        if dead.is_empty() {
+            break;
+        }
+        for op in dead {
+            remove_operator(compiled_algo, op);
+        }
+    }
+}
+
+/// Run a set of backend-specific optimizations on the `OhuaData` structure. Expects the
+/// arguments of the algorithm to be passed via `id` operators already, see
+/// `handle_environment_arcs`.
+pub fn run_backend_optimizations(compiled_algo: &mut OhuaData, passes: &Passes) {
     process_dead_ends(compiled_algo);
+    if passes.runs(Pass::CollectFusion) {
+        fuse_collect_reduce(compiled_algo);
+        fuse_collect_somes(compiled_algo);
+        fuse_collect_until_break(compiled_algo);
+    }
+    if passes.runs(Pass::BranchGating) {
+        gate_branches(compiled_algo);
+    }
+    if passes.runs(Pass::ConstantFolding) {
+        fold_constants(compiled_algo);
+    }
+    if passes.runs(Pass::RedundantIds) {
+        remove_redundant_ids(compiled_algo);
+    }
+    if passes.runs(Pass::NthFusion) {
+        fuse_nths(compiled_algo);
+    }
+    if passes.runs(Pass::DeadOperators) {
+        remove_dead_operators(compiled_algo);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ohua_types::Envs::*;
+    use crate::ohua_types::*;
+
+    fn op(id: OpId, name: &str, node_type: NodeType) -> Operator {
+        Operator {
+            operatorId: id,
+            operatorType: OperatorType {
+                qbNamespace: vec!["ohua_runtime".into(), "lang".into()],
+                qbName: name.into(),
+            },
+            nodeType: node_type,
+        }
+    }
+
+    fn sf(id: OpId, name: &str) -> Operator {
+        let mut op = op(id, name, NodeType::FunctionNode);
+        op.operatorType.qbNamespace = vec!["crate".into()];
+        op
+    }
+
+    fn env(lit: Envs, tgt: OpId, tgt_idx: Index) -> DirectArc {
+        DirectArc {
+            target: ArcIdentifier {
+                operator: tgt,
+                index: tgt_idx,
+            },
+            source: ArcSource::Env(lit),
+        }
+    }
+
+    fn graph(operators: Vec<Operator>, direct: Vec<DirectArc>, result: OpId) -> OhuaData {
+        let mut compiled = smap_reduce(false);
+        compiled.graph.operators = operators;
+        compiled.graph.arcs.direct = direct;
+        compiled.graph.return_arc.operator = result;
+        compiled
+    }
+
+    fn op_ids(compiled: &OhuaData) -> Vec<OpId> {
+        compiled
+            .graph
+            .operators
+            .iter()
+            .map(|o| o.operatorId)
+            .collect()
+    }
+
+    fn sorted_arcs(compiled: &OhuaData) -> Vec<String> {
+        let mut arcs: Vec<String> = compiled
+            .graph
+            .arcs
+            .direct
+            .iter()
+            .map(|a| format!("{}", a))
+            .collect();
+        arcs.sort();
+        arcs
+    }
+
+    fn arc(src: OpId, src_idx: Index, tgt: OpId, tgt_idx: Index) -> DirectArc {
+        DirectArc {
+            target: ArcIdentifier {
+                operator: tgt,
+                index: tgt_idx,
+            },
+            source: ArcSource::Local(ArcIdentifier {
+                operator: src,
+                index: src_idx,
+            }),
+        }
+    }
+
+    fn smap_reduce(extra_consumer: bool) -> OhuaData {
+        let mut direct = vec![
+            arc(1, 2, 3, 0),
+            arc(2, 0, 3, 1),
+            arc(3, 0, 4, 0),
+            DirectArc {
+                target: ArcIdentifier {
+                    operator: 4,
+                    index: 1,
+                },
+                source: ArcSource::Env(NumericLit { content: 0 }),
+            },
+            DirectArc {
+                target: ArcIdentifier {
+                    operator: 4,
+                    index: 2,
+                },
+                source: ArcSource::Env(FunRefLit {
+                    contents: OperatorType {
+                        qbNamespace: vec!["crate".into()],
+                        qbName: "add".into(),
+                    },
+                }),
+            },
+        ];
+        if extra_consumer {
+            direct.push(arc(3, 0, 5, 0));
+        }
+
+        OhuaData {
+            graph: DFGraph {
+                operators: vec![
+                    op(1, "smapFun", NodeType::OperatorNode),
+                    op(2, "id", NodeType::FunctionNode),
+                    op(3, "collect", NodeType::OperatorNode),
+                    op(4, "reduce", NodeType::FunctionNode),
+                ],
+                arcs: Arcs {
+                    direct,
+                    state: vec![],
+                    dead: vec![],
+                },
+                return_arc: ArcIdentifier {
+                    operator: 4,
+                    index: -1,
+                },
+                input_targets: Vec::new(),
+            },
+            mainArity: 0,
+            sfDependencies: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn collect_reduce_is_fused() {
+        let mut compiled = smap_reduce(false);
+        fuse_collect_reduce(&mut compiled);
+
+        assert!(compiled.graph.operators.iter().all(|o| o.operatorId != 3));
+        let fold = compiled
+            .graph
+            .operators
+            .iter()
+            .find(|o| o.operatorId == 4)
+            .unwrap();
+        assert_eq!(fold.operatorType.qbName, "fold");
+        assert_eq!(fold.nodeType, NodeType::OperatorNode);
+
+        let inputs: Vec<&DirectArc> = compiled
+            .graph
+            .arcs
+            .direct
+            .iter()
+            .filter(|a| a.target.operator == 4)
+            .collect();
+        assert_eq!(inputs.len(), 4);
+        let source = |index: Index| {
+            &inputs
+                .iter()
+                .find(|a| a.target.index == index)
+                .unwrap()
+                .source
+        };
+        assert!(matches!(
+            source(0),
+            ArcSource::Local(ArcIdentifier {
+                operator: 1,
+                index: 2
+            })
+        ));
+        assert!(matches!(
+            source(1),
+            ArcSource::Local(ArcIdentifier {
+                operator: 2,
+                index: 0
+            })
+        ));
+        assert!(matches!(source(2), ArcSource::Env(NumericLit { content: 0 })));
+        assert!(matches!(source(3), ArcSource::Env(FunRefLit { .. })));
+    }
+
+    #[test]
+    fn reduce_with_computed_init_is_not_fused() {
+        // the initial value is computed for every run of the loop, e.g., in an outer loop
+        let mut compiled = smap_reduce(false);
+        compiled
+            .graph
+            .arcs
+            .direct
+            .retain(|a| a.target.operator != 4 || a.target.index != 1);
+        compiled.graph.arcs.direct.push(arc(5, 0, 4, 1));
+        fuse_collect_reduce(&mut compiled);
+
+        assert!(compiled.graph.operators.iter().any(|o| o.operatorId == 3));
+        let reduce = compiled
+            .graph
+            .operators
+            .iter()
+            .find(|o| o.operatorId == 4)
+            .unwrap();
+        assert_eq!(reduce.operatorType.qbName, "reduce");
+    }
+
+    #[test]
+    fn shared_collect_is_not_fused() {
+        let mut compiled = smap_reduce(true);
+        fuse_collect_reduce(&mut compiled);
+
+        assert!(compiled.graph.operators.iter().any(|o| o.operatorId == 3));
+        assert_eq!(compiled.graph.arcs.direct.len(), 6);
+    }
+
+    #[test]
+    fn collect_until_break_is_fused() {
+        let mut compiled = smap_reduce(false);
+        compiled.graph.operators[3].operatorType.qbName = "until_break".into();
+        compiled
+            .graph
+            .arcs
+            .direct
+            .retain(|a| a.target.operator != 4 || a.target.index == 0);
+        compiled.graph.arcs.direct.push(arc(4, 0, 5, 0));
+        fuse_collect_until_break(&mut compiled);
+
+        let names: Vec<&str> = compiled
+            .graph
+            .operators
+            .iter()
+            .map(|o| o.operatorType.qbName.as_str())
+            .collect();
+        assert_eq!(names, vec!["smapBreakFun", "id", "collect_break"]);
+
+        let mut arcs: Vec<String> = compiled
+            .graph
+            .arcs
+            .direct
+            .iter()
+            .map(|a| format!("{}", a))
+            .collect();
+        arcs.sort();
+        let mut expected: Vec<String> = [
+            arc(1, 2, 4, 0),
+            arc(2, 0, 4, 1),
+            arc(4, 1, 5, 0),
+            arc(4, 0, 1, 1),
+        ]
+        .iter()
+        .map(|a| format!("{}", a))
+        .collect();
+        expected.sort();
+        assert_eq!(arcs, expected);
+    }
+
+    fn conditional(branch_inputs: Vec<DirectArc>, operators: Vec<Operator>) -> OhuaData {
+        let mut direct = vec![arc(0, 0, 1, 0), arc(0, 0, 4, 0), arc(2, 0, 4, 1), arc(3, 0, 4, 2)];
+        direct.extend(branch_inputs);
+        let mut ops = vec![
+            op(0, "get_cond", NodeType::FunctionNode),
+            op(1, "ifFun", NodeType::OperatorNode),
+            op(2, "positive", NodeType::FunctionNode),
+            op(3, "negative", NodeType::FunctionNode),
+            op(4, "select", NodeType::OperatorNode),
+        ];
+        ops.extend(operators);
+
+        let mut compiled = smap_reduce(false);
+        compiled.graph.operators = ops;
+        compiled.graph.arcs.direct = direct;
+        compiled.graph.return_arc.operator = 4;
+        compiled
+    }
+
+    #[test]
+    fn branch_sources_are_gated() {
+        let mut compiled = conditional(vec![], vec![]);
+        gate_branches(&mut compiled);
+
+        let gates: Vec<String> = compiled
+            .graph
+            .arcs
+            .direct
+            .iter()
+            .filter(|a| a.target.index == -1)
+            .map(|a| format!("{}", a))
+            .collect();
+        assert_eq!(
+            gates,
+            vec![format!("{}", arc(1, 0, 2, -1)), format!("{}", arc(1, 1, 3, -1))]
+        );
+        assert_eq!(compiled.graph.operators[4].operatorType.qbName, "select");
+    }
+
+    #[test]
+    fn ungated_branches_are_drained() {
+        let mut compiled = conditional(
+            vec![arc(5, 0, 2, 0), arc(5, 0, 3, 0)],
+            vec![op(5, "get_input", NodeType::FunctionNode)],
+        );
+        gate_branches(&mut compiled);
+
+        assert!(compiled.graph.arcs.direct.iter().all(|a| a.target.index != -1));
+        assert_eq!(compiled.graph.operators[4].operatorType.qbName, "select_drain");
+        let drain = compiled
+            .graph
+            .arcs
+            .direct
+            .iter()
+            .find(|a| a.target.operator == 4 && a.target.index == 3)
+            .unwrap();
+        assert_eq!(
+            format!("{}", drain.source),
+            "ValueType::EnvironmentVal(NumericLit(3))"
+        );
+    }
+
+    #[test]
+    fn branches_on_arguments_are_drained() {
+        // the true branch takes an argument of the algorithm, the false branch a literal
+        let mut compiled = conditional(
+            vec![
+                env(EnvRefLit { content: 0 }, 2, 0),
+                env(NumericLit { content: 7 }, 3, 0),
+            ],
+            vec![],
+        );
+        gate_branches(&mut compiled);
+
+        let gated: Vec<OpId> = compiled
+            .graph
+            .arcs
+            .direct
+            .iter()
+            .filter(|a| a.target.index == -1)
+            .map(|a| a.target.operator)
+            .collect();
+        assert_eq!(gated, vec![3]);
+        assert_eq!(compiled.graph.operators[4].operatorType.qbName, "select_drain");
+        let drain = compiled
+            .graph
+            .arcs
+            .direct
+            .iter()
+            .find(|a| a.target.operator == 4 && a.target.index == 3)
+            .unwrap();
+        assert!(matches!(drain.source, ArcSource::Env(NumericLit { content: 1 })));
+    }
+
+    /// `consume(id(nth(1, 2, tuple(1, 2))))`
+    fn literal_tuple() -> OhuaData {
+        graph(
+            vec![
+                op(1, "tuple", NodeType::FunctionNode),
+                op(2, "nth", NodeType::FunctionNode),
+                op(3, "id", NodeType::FunctionNode),
+                sf(4, "consume"),
+            ],
+            vec![
+                env(NumericLit { content: 1 }, 1, 0),
+                env(NumericLit { content: 2 }, 1, 1),
+                env(NumericLit { content: 1 }, 2, 0),
+                env(NumericLit { content: 2 }, 2, 1),
+                arc(1, 0, 2, 2),
+                arc(2, 0, 3, 0),
+                arc(3, 0, 4, 0),
+            ],
+            4,
+        )
+    }
+
+    #[test]
+    fn literal_only_functions_are_folded() {
+        let mut compiled = literal_tuple();
+        fold_constants(&mut compiled);
+
+        // the tuple itself is not a literal and stays, its result is discarded
+        assert_eq!(op_ids(&compiled), vec![1, 4]);
+        let mut expected: Vec<String> = [
+            env(NumericLit { content: 1 }, 1, 0),
+            env(NumericLit { content: 2 }, 1, 1),
+            env(NumericLit { content: 2 }, 4, 0),
+            dead_end(ArcIdentifier {
+                operator: 1,
+                index: 0,
+            }),
+        ]
+        .iter()
+        .map(|a| format!("{}", a))
+        .collect();
+        expected.sort();
+        assert_eq!(sorted_arcs(&compiled), expected);
+    }
+
+    #[test]
+    fn unused_results_are_removed() {
+        let mut compiled = literal_tuple();
+        fold_constants(&mut compiled);
+        remove_dead_operators(&mut compiled);
+        assert_eq!(op_ids(&compiled), vec![4]);
+
+        // `log` may have side effects and stays, unlike the chain of `id` and `nth`
+        let mut compiled = graph(
+            vec![
+                sf(1, "gen"),
+                op(2, "id", NodeType::FunctionNode),
+                op(3, "nth", NodeType::FunctionNode),
+                sf(4, "log"),
+                sf(5, "consume"),
+            ],
+            vec![
+                arc(1, 0, 2, 0),
+                arc(2, 0, 3, 2),
+                arc(3, 0, 0, 0),
+                arc(1, 0, 4, 0),
+                arc(4, 0, 0, 0),
+                arc(1, 0, 5, 0),
+            ],
+            5,
+        );
+        remove_dead_operators(&mut compiled);
+        assert_eq!(op_ids(&compiled), vec![1, 4, 5]);
+        assert_eq!(compiled.graph.arcs.direct.len(), 3);
+    }
+
+    #[test]
+    fn arguments_used_once_skip_their_id() {
+        let env_arg = |i: i32, tgt: OpId| env(EnvRefLit { content: i }, tgt, 0);
+        let mut compiled = graph(
+            vec![
+                op(1, "id", NodeType::FunctionNode),
+                sf(2, "double"),
+                op(3, "id", NodeType::FunctionNode),
+                sf(4, "double"),
+                sf(5, "double"),
+                op(6, "id", NodeType::FunctionNode),
+                sf(7, "add"),
+            ],
+            vec![
+                env_arg(0, 1),
+                arc(1, 0, 2, 0),
+                env_arg(1, 3),
+                arc(3, 0, 4, 0),
+                arc(3, 0, 5, 0),
+                env_arg(2, 6),
+                arc(6, 0, 7, 0),
+                arc(2, 0, 7, 1),
+            ],
+            7,
+        );
+        remove_redundant_ids(&mut compiled);
+
+        // the second argument is used twice, the third by a function called in a loop
+        assert_eq!(op_ids(&compiled), vec![2, 3, 4, 5, 6, 7]);
+        assert!(sorted_arcs(&compiled).contains(&format!("{}", env_arg(0, 2))));
+    }
+
+    /// `(pair() |> nth(0, 3), nth(2, 3), nth(0, 3))`, each consumed by a function of its own
+    fn nths(len: i32) -> OhuaData {
+        let nth = |id: OpId, idx: i32, len: i32| {
+            vec![
+                env(NumericLit { content: idx }, id, 0),
+                env(NumericLit { content: len }, id, 1),
+                arc(1, 0, id, 2),
+            ]
+        };
+        let mut direct = vec![arc(2, 0, 5, 0), arc(3, 0, 6, 0), arc(4, 0, 7, 0)];
+        direct.extend(nth(2, 0, 3));
+        direct.extend(nth(3, 2, 3));
+        direct.extend(nth(4, 0, len));
+        graph(
+            vec![
+                sf(1, "triple"),
+                op(2, "nth", NodeType::FunctionNode),
+                op(3, "nth", NodeType::FunctionNode),
+                op(4, "nth", NodeType::FunctionNode),
+                sf(5, "first"),
+                sf(6, "third"),
+                sf(7, "first_again"),
+            ],
+            direct,
+            7,
+        )
+    }
+
+    #[test]
+    fn nths_are_fused_into_destruct() {
+        let mut compiled = nths(3);
+        fuse_nths(&mut compiled);
+
+        assert_eq!(op_ids(&compiled), vec![1, 2, 5, 6, 7]);
+        assert_eq!(compiled.graph.operators[1].operatorType.qbName, "destruct");
+        assert_eq!(compiled.graph.operators[1].nodeType, NodeType::OperatorNode);
+        let mut expected: Vec<String> = [
+            arc(1, 0, 2, 0),
+            arc(2, 0, 5, 0),
+            arc(2, 2, 6, 0),
+            arc(2, 0, 7, 0),
+            dead_end(ArcIdentifier {
+                operator: 2,
+                index: 1,
+            }),
+        ]
+        .iter()
+        .map(|a| format!("{}", a))
+        .collect();
+        expected.sort();
+        assert_eq!(sorted_arcs(&compiled), expected);
+
+        // tuples of different length cannot be the same tuple, something is off
+        let mut compiled = nths(4);
+        fuse_nths(&mut compiled);
+        assert_eq!(op_ids(&compiled), vec![1, 2, 3, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn passes_can_be_skipped() {
+        let mut passes = Passes::default();
+        passes.skip(Pass::NthFusion);
+        let mut compiled = nths(3);
+        run_backend_optimizations(&mut compiled, &passes);
+        assert!(compiled
+            .graph
+            .operators
+            .iter()
+            .all(|o| o.operatorType.qbName != "destruct"));
+
+        let mut compiled = nths(3);
+        run_backend_optimizations(&mut compiled, &Passes::default());
+        assert_eq!(compiled.graph.operators[1].operatorType.qbName, "destruct");
+
+        for pass in Pass::ALL.iter() {
+            assert_eq!(Pass::from_name(pass.name()), Some(*pass));
+        }
+    }
 }
@@ -24,13 +24,19 @@ mod lang;
 mod ohua_types;
 mod ohuac;
 mod parse;
+mod signatures;
+#[cfg(test)]
+mod simulator;
+#[cfg(test)]
+mod snapshots;
 mod typedgen;
 
 use crate::errors::*;
 use crate::ohua_types::OhuaData;
-use crate::parse::parse_ohua_call;
-use std::env::current_dir;
-use std::fs::File;
+use crate::signatures::Signatures;
+use crate::parse::{check_outputs, parse_ohua_call};
+use std::env::{current_dir, var_os};
+use std::fs::{self, File};
 use std::path::PathBuf;
 use tempdir::TempDir;
 use crate::typedgen::*;
@@ -43,7 +49,7 @@ use syn::export::ToTokens;
  */
 #[proc_macro_attribute]
 pub fn ohua(args: TokenStream, input: TokenStream) -> TokenStream {
-    let (algo_info, assignment) = parse_ohua_call(args, input);
+    let (algo_info, assignment, options) = parse_ohua_call(args, input);
     let (algo_name, algo_args) = algo_info;
 
     // after the initial parsing/verification, the compilation can begin
@@ -66,8 +72,16 @@ pub fn ohua(args: TokenStream, input: TokenStream) -> TokenStream {
     println!("[Phase 1] Starting `ohuac`");
     let ohuac_file = locate_ohuac_file(algo_name)
         .expect("The ohuac file could not be found at the requested place.");
+    let fixture = ohuac_file.with_extension("ohuao");
     let processed_algo = ohuac::generate_dfg(ohuac_file, tmp_dir.clone());
 
+    // keep the dataflow graph as a fixture for the snapshot tests, see `snapshots.rs`
+    if var_os("OHUA_WRITE_FIXTURES").is_some() {
+        if let Err(e) = fs::copy(&processed_algo.ohuao, &fixture) {
+            panic!("Unable to write the fixture {}. {}", fixture.display(), e);
+        }
+    }
+
     // Phase 2: Run the type extraction
     // println!("[Phase 2] Running type extraction");
     // let type_infos = match TypeKnowledgeBase::generate_from(&processed_algo) {
@@ -90,8 +104,13 @@ pub fn ohua(args: TokenStream, input: TokenStream) -> TokenStream {
     println!("[Phase 4] Starting code generation");
     alter_ohua_ns_imports(&mut ohua_data);
 
+    // the signatures of the stateful functions determine which arguments can be borrowed
+    let mut src_dir = current_dir().unwrap();
+    src_dir.push("src");
+    let signatures = Signatures::from_sources(&ohua_data.graph.operators, &src_dir);
+
     // all parsed code parts are unwrapped here, errors should not occur, as we've generated this
-    let final_code = generate_code(&mut ohua_data, &algo_args);
+    let final_code = generate_code(&mut ohua_data, &algo_args, &signatures, &options);
     #[cfg(feature = "debug")]
     println!(" Done!");
 
@@ -99,6 +118,9 @@ pub fn ohua(args: TokenStream, input: TokenStream) -> TokenStream {
     println!("{}", final_code);
     // Hand the output tokens back to the compil)er
     if let Some(mut local) = assignment {
+        if let Some(outputs) = count_outputs(&ohua_data.graph) {
+            check_outputs(&local, outputs);
+        }
         local.init = Some((syn::token::Eq::default(), syn::parse2(final_code).unwrap()));
         let x = local.into_token_stream().into();
         // println!("\n\n---\n{}", x);
@@ -1,15 +1,167 @@
+use crate::backend_optimizations::{Pass, Passes};
 use proc_macro::TokenStream;
+use syn::parse::{Parse, ParseStream, Parser};
 use syn::punctuated::Punctuated;
 use syn::token::Comma;
-use syn::{Expr, ExprCall, ExprPath, Local, Stmt};
+use syn::{Expr, ExprCall, ExprPath, Ident, Local, Pat, Stmt};
+
+/// Determines what the code generated for an algorithm evaluates to.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Mode {
+    /// Run the algorithm once and return its result (`#[ohua]`).
+    #[default]
+    Run,
+    /// Set up a reusable `AlgorithmInstance` that can be run many times (`#[ohua(instance)]`).
+    Instance,
+    /// Run the algorithm on every item of the arguments, which are streams (`#[ohua(stream)]`).
+    Stream,
+}
+
+/// How a run of the algorithm can be aborted (`#[ohua(cancel = token, timeout_ms = 100)]`).
+#[derive(Debug, Default)]
+pub struct Cancellation {
+    /// The `CancellationToken` handed in by the caller.
+    pub token: Option<Expr>,
+    /// The number of milliseconds after which the run times out.
+    pub timeout_ms: Option<Expr>,
+}
+
+impl Cancellation {
+    pub fn is_requested(&self) -> bool {
+        self.token.is_some() || self.timeout_ms.is_some()
+    }
+}
+
+/// All arguments given to the #[ohua] macro, along with the instrumentation selected via the
+/// features of this crate. `Options::default()` asks for no instrumentation at all, independent
+/// of the features.
+#[derive(Debug, Default)]
+pub struct Options {
+    pub mode: Mode,
+    pub cancellation: Cancellation,
+    /// The `Schedule` to run the algorithm with, one task at a time (`#[ohua(schedule = s)]`).
+    pub schedule: Option<Expr>,
+    /// The path of the file to record the traffic of all arcs to (`#[ohua(record = path)]`).
+    pub record: Option<Expr>,
+    /// The optimization passes to run (`#[ohua(skip_passes = [dead_operators, ...])]`).
+    pub passes: Passes,
+    /// Whether tests can substitute the stateful functions via `ohua_runtime::mock`
+    /// (`#[ohua(mock)]`).
+    pub mock: bool,
+    /// Whether the generated code reports operator execution to `ohua_runtime::trace`, set by the
+    /// `trace` feature.
+    pub trace: bool,
+    /// Whether the arcs of the generated code collect metrics on their traffic, set by the
+    /// `metrics` feature.
+    pub metrics: bool,
+    /// Whether the arcs of the generated code are watched for deadlocks, set by the `watchdog`
+    /// feature.
+    pub watchdog: bool,
+    /// The path of the invoked algorithm, e.g., `algos::sum`, which the `metrics_report` lists
+    /// its arcs under.
+    pub algorithm: String,
+}
+
+/// A single argument to the #[ohua] macro, either a flag or a `name = value` pair.
+enum MacroArg {
+    Flag(Ident),
+    Value(Ident, Expr),
+}
+
+impl Parse for MacroArg {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let name: Ident = input.parse()?;
+        if input.peek(Token![=]) {
+            input.parse::<Token![=]>()?;
+            Ok(MacroArg::Value(name, input.parse()?))
+        } else {
+            Ok(MacroArg::Flag(name))
+        }
+    }
+}
+
+fn parse_options(args: proc_macro2::TokenStream) -> Options {
+    let args = match Punctuated::<MacroArg, Token![,]>::parse_terminated.parse2(args) {
+        Ok(args) => args,
+        Err(e) => panic!("Malformed arguments to the #[ohua] macro. {}", e),
+    };
+
+    let mut options = Options {
+        trace: cfg!(feature = "trace"),
+        metrics: cfg!(feature = "metrics"),
+        watchdog: cfg!(feature = "watchdog"),
+        ..Options::default()
+    };
+    for arg in args {
+        match arg {
+            MacroArg::Flag(ref name) if name == "instance" => options.mode = Mode::Instance,
+            MacroArg::Flag(ref name) if name == "stream" => options.mode = Mode::Stream,
+            MacroArg::Flag(ref name) if name == "mock" => options.mock = true,
+            MacroArg::Value(ref name, token) if name == "cancel" => options.cancellation.token = Some(token),
+            MacroArg::Value(ref name, ms) if name == "timeout_ms" => options.cancellation.timeout_ms = Some(ms),
+            MacroArg::Value(ref name, schedule) if name == "schedule" => options.schedule = Some(schedule),
+            MacroArg::Value(ref name, path) if name == "record" => options.record = Some(path),
+            MacroArg::Value(ref name, passes) if name == "skip_passes" => {
+                for pass in parse_passes(&passes) {
+                    options.passes.skip(pass);
+                }
+            }
+            _ => panic!(
+                "Unsupported argument to the #[ohua] macro. Only `instance`, `stream`, `mock`, `cancel = <token>`, `timeout_ms = <ms>`, `schedule = <schedule>`, `record = <path>` and `skip_passes = [<pass>, ...]` are supported."
+            ),
+        }
+    }
+
+    if options.cancellation.is_requested() && options.mode != Mode::Run {
+        panic!("`cancel` and `timeout_ms` can only be used for single runs of an algorithm.");
+    }
+    if options.schedule.is_some()
+        && (options.mode != Mode::Run || options.cancellation.is_requested())
+    {
+        panic!(
+            "`schedule` can only be used for single runs of an algorithm that are not cancellable."
+        );
+    }
+
+    options
+}
+
+/// Parses the passes listed in `skip_passes = [...]`, a single pass may also be given without
+/// brackets.
+fn parse_passes(passes: &Expr) -> Vec<Pass> {
+    let names: Vec<&Expr> = match *passes {
+        Expr::Array(ref array) => array.elems.iter().collect(),
+        ref single => vec![single],
+    };
+    names
+        .into_iter()
+        .map(|name| {
+            let pass = match *name {
+                Expr::Path(ref path) if path.path.segments.len() == 1 => {
+                    Pass::from_name(&path.path.segments[0].ident.to_string())
+                }
+                _ => None,
+            };
+            pass.unwrap_or_else(|| {
+                let known: Vec<&str> = Pass::ALL.iter().map(|pass| pass.name()).collect();
+                panic!(
+                    "Unknown optimization pass `{}`, the passes are: {}.",
+                    quote! { #name },
+                    known.join(", ")
+                )
+            })
+        })
+        .collect()
+}
+
+/// The path of the invoked algorithm and the arguments it is invoked with.
+pub type AlgoCall = (ExprPath, Punctuated<Expr, Comma>);
 
 pub fn parse_ohua_call(
     args: TokenStream,
     input: TokenStream,
-) -> ((ExprPath, Punctuated<Expr, Comma>), Option<Local>) {
-    if !args.is_empty() {
-        panic!("The #[ohua] macro does currently not support macro arguments.");
-    }
+) -> (AlgoCall, Option<Local>, Options) {
+    let mut options = parse_options(args.into());
 
     // Parse the input tokens into a syntax tree, extract necessary information
     let ast: Stmt = match syn::parse(input) {
@@ -32,10 +184,42 @@ pub fn parse_ohua_call(
         _ => panic!("Encountered unsupported statement after #[ohua] macro"),
     };
 
-    (parse_expr(expression), assignment)
+    let algo_call = parse_expr(expression);
+    options.algorithm = algorithm_path(&algo_call.0);
+
+    (algo_call, assignment, options)
+}
+
+/// The path of an algorithm as written at the call site, e.g., `algos::sum`.
+fn algorithm_path(path: &ExprPath) -> String {
+    let segments: Vec<String> = path
+        .path
+        .segments
+        .iter()
+        .map(|segment| segment.ident.to_string())
+        .collect();
+    segments.join("::")
+}
+
+/// Checks the pattern the result of an algorithm returning `outputs` values is assigned to.
+///
+/// The outputs of an algorithm are positional, they get their names from the tuple pattern at the
+/// call site, e.g., `let (sum, count) = ...`. Such a pattern must bind every output.
+pub fn check_outputs(assignment: &Local, outputs: usize) {
+    for pat in assignment.pats.iter() {
+        if let Pat::Tuple(ref tuple) = *pat {
+            let bound = tuple.front.len() + tuple.back.len();
+            if bound > outputs || (tuple.dot2_token.is_none() && bound != outputs) {
+                panic!(
+                    "The algorithm returns {} outputs, but the pattern binds {}.",
+                    outputs, bound
+                );
+            }
+        }
+    }
 }
 
-fn parse_expr(expression: Expr) -> (ExprPath, Punctuated<Expr, Comma>) {
+fn parse_expr(expression: Expr) -> AlgoCall {
     let algo_call: ExprCall = if let Expr::Call(fn_call) = expression {
         fn_call
     } else {
@@ -61,4 +245,90 @@ pub mod tests {
         };
         parse_expr(expr)
     }
+
+    #[test]
+    fn algorithm_paths() {
+        let path: ExprPath = syn::parse_str("algos::sum").unwrap();
+        assert_eq!(algorithm_path(&path), "algos::sum");
+        let path: ExprPath = syn::parse_str("sum").unwrap();
+        assert_eq!(algorithm_path(&path), "sum");
+    }
+
+    fn assignment(statement: &str) -> Local {
+        match syn::parse_str::<Stmt>(statement) {
+            Ok(Stmt::Local(local)) => local,
+            _ => panic!("not an assignment: {}", statement),
+        }
+    }
+
+    #[test]
+    fn output_patterns() {
+        check_outputs(&assignment("let (sum, count) = stats(numbers);"), 2);
+        check_outputs(&assignment("let (sum, ..) = stats(numbers);"), 2);
+        check_outputs(&assignment("let (.., count) = stats(numbers);"), 2);
+        check_outputs(&assignment("let pair = stats(numbers);"), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "The algorithm returns 2 outputs, but the pattern binds 3.")]
+    fn output_patterns_bind_all_outputs() {
+        check_outputs(&assignment("let (sum, count, mean) = stats(numbers);"), 2);
+    }
+
+    #[test]
+    fn macro_options() {
+        let options = parse_options(quote! {});
+        assert_eq!(options.mode, Mode::Run);
+        assert!(!options.cancellation.is_requested());
+        assert!(options.schedule.is_none());
+        assert!(options.record.is_none());
+        assert!(!options.mock);
+
+        let options = parse_options(quote! { stream });
+        assert_eq!(options.mode, Mode::Stream);
+
+        let options = parse_options(quote! { mock, timeout_ms = 500 });
+        assert!(options.mock);
+        assert!(options.cancellation.is_requested());
+
+        let options = parse_options(quote! { cancel = token.clone(), timeout_ms = 500 });
+        assert_eq!(options.mode, Mode::Run);
+        assert!(options.cancellation.token.is_some());
+        assert!(options.cancellation.timeout_ms.is_some());
+
+        let options = parse_options(quote! { schedule = Schedule::Random(42) });
+        assert_eq!(options.mode, Mode::Run);
+        assert!(options.schedule.is_some());
+
+        let options = parse_options(quote! { instance, record = "run.trace" });
+        assert_eq!(options.mode, Mode::Instance);
+        assert!(options.record.is_some());
+        assert!(Pass::ALL.iter().all(|pass| options.passes.runs(*pass)));
+
+        let options = parse_options(quote! { skip_passes = [nth_fusion, dead_operators] });
+        assert!(!options.passes.runs(Pass::NthFusion));
+        assert!(!options.passes.runs(Pass::DeadOperators));
+        assert!(options.passes.runs(Pass::CollectFusion));
+
+        let options = parse_options(quote! { skip_passes = branch_gating });
+        assert!(!options.passes.runs(Pass::BranchGating));
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown optimization pass `inlining`")]
+    fn unknown_passes_are_rejected() {
+        parse_options(quote! { skip_passes = [inlining] });
+    }
+
+    #[test]
+    #[should_panic]
+    fn cancelling_instances_is_rejected() {
+        parse_options(quote! { instance, timeout_ms = 500 });
+    }
+
+    #[test]
+    #[should_panic]
+    fn scheduling_streams_is_rejected() {
+        parse_options(quote! { stream, schedule = Schedule::InOrder });
+    }
 }
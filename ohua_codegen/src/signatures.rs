@@ -0,0 +1,281 @@
+//! Knowledge about the Rust signatures of the stateful functions used in an algorithm.
+use crate::ohua_types::{NodeType, Operator, OperatorType};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use syn::{
+    ArgCaptured, FnArg, FnDecl, GenericArgument, Item, ItemFn, PathArguments, ReturnType, Type,
+    UseTree,
+};
+
+/// Records, per stateful function, which of its parameters are only borrowed and whether it
+/// can fail.
+///
+/// Functions without an entry are assumed to take all their arguments by value and to always
+/// succeed.
+#[derive(Debug, Default)]
+pub struct Signatures {
+    borrowed: BTreeMap<String, Vec<bool>>,
+    fallible: BTreeSet<String>,
+}
+
+fn qualified_name(op_type: &OperatorType) -> String {
+    let mut path = op_type.qbNamespace.clone();
+    path.push(op_type.qbName.clone());
+    path.join("::")
+}
+
+impl Signatures {
+    /// Collects the signatures of all stateful functions in `ops` that are defined in the local
+    /// crate whose sources live in `src_dir`. Functions that cannot be located are left out.
+    pub fn from_sources(ops: &[Operator], src_dir: &Path) -> Signatures {
+        let mut sigs = Signatures::default();
+
+        for op in ops.iter().filter(|o| o.nodeType == NodeType::FunctionNode) {
+            if let Some((fun, run_error_imported)) = find_fn(src_dir, &op.operatorType) {
+                sigs.insert(&op.operatorType, param_modes(&fun.decl));
+                if returns_run_error(&fun.decl, run_error_imported) {
+                    sigs.insert_fallible(&op.operatorType);
+                }
+            }
+        }
+
+        sigs
+    }
+
+    /// Registers the parameter modes of a function, `true` marking a parameter taken by reference.
+    pub fn insert(&mut self, op_type: &OperatorType, borrowed: Vec<bool>) {
+        self.borrowed.insert(qualified_name(op_type), borrowed);
+    }
+
+    /// Registers a function that returns a `Result<_, RunError>`.
+    pub fn insert_fallible(&mut self, op_type: &OperatorType) {
+        self.fallible.insert(qualified_name(op_type));
+    }
+
+    /// Whether the function reports failures as a `Result<_, RunError>`.
+    pub fn fails(&self, op_type: &OperatorType) -> bool {
+        self.fallible.contains(&qualified_name(op_type))
+    }
+
+    /// Whether the function only borrows the argument at position `idx`.
+    pub fn borrows(&self, op_type: &OperatorType, idx: i32) -> bool {
+        if idx < 0 {
+            return false;
+        }
+
+        self.borrowed
+            .get(&qualified_name(op_type))
+            .and_then(|params| params.get(idx as usize))
+            .cloned()
+            .unwrap_or(false)
+    }
+}
+
+/// Locates the source file of a module of the local crate, given by its full path.
+fn module_file(src_dir: &Path, namespace: &[String]) -> Option<PathBuf> {
+    let (root, path) = namespace.split_first()?;
+    if root != "crate" {
+        return None;
+    }
+
+    let candidates = if path.is_empty() {
+        vec![src_dir.join("lib.rs"), src_dir.join("main.rs")]
+    } else {
+        let mut module = src_dir.to_path_buf();
+        module.extend(path);
+        vec![module.with_extension("rs"), module.join("mod.rs")]
+    };
+
+    candidates.into_iter().find(|file| file.exists())
+}
+
+/// Finds the definition of a function, along with whether its module imports `RunError`.
+fn find_fn(src_dir: &Path, op_type: &OperatorType) -> Option<(ItemFn, bool)> {
+    let file = module_file(src_dir, &op_type.qbNamespace)?;
+    let content = fs::read_to_string(file).ok()?;
+    let ast = syn::parse_file(&content).ok()?;
+
+    let run_error_imported = imports_run_error(&ast.items);
+    let fun = ast.items.into_iter().find_map(|item| match item {
+        Item::Fn(fun) if fun.ident == op_type.qbName => Some(fun),
+        _ => None,
+    })?;
+    Some((fun, run_error_imported))
+}
+
+/// Whether one of the `use` declarations among `items` imports `ohua_runtime::RunError`.
+fn imports_run_error(items: &[Item]) -> bool {
+    // `tree` names items of `ohua_runtime`
+    fn from_runtime(tree: &UseTree) -> bool {
+        match tree {
+            UseTree::Name(name) => name.ident == "RunError",
+            UseTree::Glob(_) => true,
+            UseTree::Group(group) => group.items.iter().any(from_runtime),
+            _ => false,
+        }
+    }
+    fn imports(tree: &UseTree) -> bool {
+        match tree {
+            UseTree::Path(path) if path.ident == "ohua_runtime" => from_runtime(&path.tree),
+            UseTree::Group(group) => group.items.iter().any(imports),
+            _ => false,
+        }
+    }
+
+    items.iter().any(|item| match item {
+        Item::Use(item) => imports(&item.tree),
+        _ => false,
+    })
+}
+
+/// Marks every parameter that is taken as a shared reference. Mutable references are treated
+/// like owned values, as they cannot be served from a shared value.
+fn param_modes(decl: &FnDecl) -> Vec<bool> {
+    decl.inputs
+        .iter()
+        .map(|arg| match arg {
+            FnArg::Captured(ArgCaptured {
+                ty: Type::Reference(r),
+                ..
+            }) => r.mutability.is_none(),
+            _ => false,
+        })
+        .collect()
+}
+
+/// Detects a return type of the form `Result<_, RunError>`. The error type has to be given as
+/// `ohua_runtime::RunError`, or as `RunError` if `run_error_imported` from `ohua_runtime`.
+fn returns_run_error(decl: &FnDecl, run_error_imported: bool) -> bool {
+    let last_segment = |ty: &Type| match ty {
+        Type::Path(p) => p.path.segments.iter().last().cloned(),
+        _ => None,
+    };
+    let is_run_error = |ty: &Type| match ty {
+        Type::Path(p) if p.qself.is_none() => {
+            let names: Vec<String> = p
+                .path
+                .segments
+                .iter()
+                .map(|seg| seg.ident.to_string())
+                .collect();
+            match names.as_slice() {
+                [name] => run_error_imported && name == "RunError",
+                [krate, name] => krate == "ohua_runtime" && name == "RunError",
+                _ => false,
+            }
+        }
+        _ => false,
+    };
+
+    let result = match decl.output {
+        ReturnType::Type(_, ref ty) => last_segment(ty),
+        ReturnType::Default => None,
+    };
+    match result {
+        Some(ref seg) if seg.ident == "Result" => match seg.arguments {
+            PathArguments::AngleBracketed(ref args) => match args.args.iter().nth(1) {
+                Some(GenericArgument::Type(err)) => is_run_error(err),
+                _ => false,
+            },
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn borrowed_params_are_detected() {
+        let fun: ItemFn =
+            syn::parse_str("pub fn f(a: &Vec<i32>, b: i32, c: &mut String, d: &str) -> i32 { 0 }")
+                .unwrap();
+
+        assert_eq!(param_modes(&fun.decl), vec![true, false, false, true]);
+    }
+
+    #[test]
+    fn fallible_functions_are_detected() {
+        let fallible = |sig: &str, run_error_imported: bool| {
+            let fun: ItemFn = syn::parse_str(&format!("{} {{ unimplemented!() }}", sig)).unwrap();
+            returns_run_error(&fun.decl, run_error_imported)
+        };
+
+        assert!(fallible("fn f(a: i32) -> Result<i32, RunError>", true));
+        assert!(!fallible("fn f(a: i32) -> Result<i32, RunError>", false));
+        assert!(fallible(
+            "fn f() -> std::result::Result<(), ohua_runtime::RunError>",
+            false
+        ));
+        // some other `RunError`
+        assert!(!fallible("fn f() -> Result<(), db::RunError>", true));
+        assert!(!fallible("fn f() -> Result<i32, String>", true));
+        assert!(!fallible("fn f() -> i32", true));
+        assert!(!fallible("fn f()", true));
+    }
+
+    #[test]
+    fn run_error_imports_are_detected() {
+        let imported = |source: &str| imports_run_error(&syn::parse_file(source).unwrap().items);
+
+        assert!(imported("use ohua_runtime::RunError;"));
+        assert!(imported("use ohua_runtime::{arcs::ArcInput, RunError};"));
+        assert!(imported("use ohua_runtime::*;"));
+        assert!(imported("use {std::fmt, ohua_runtime::RunError};"));
+        assert!(!imported("use db::RunError;"));
+        assert!(!imported("use ohua_runtime::arcs::*;"));
+        assert!(!imported("fn f() {}"));
+    }
+
+    #[test]
+    fn signatures_are_read_from_sources() {
+        let src_dir = tempdir::TempDir::new("ohua-sigs").unwrap();
+        fs::create_dir(src_dir.path().join("fns")).unwrap();
+        fs::write(
+            src_dir.path().join("fns").join("mod.rs"),
+            "pub fn count(words: &Vec<String>, limit: usize) -> usize { 0 }",
+        )
+        .unwrap();
+
+        let op = |ns: &[&str], name: &str| Operator {
+            operatorId: 0,
+            operatorType: OperatorType {
+                qbNamespace: ns.iter().map(|s| s.to_string()).collect(),
+                qbName: name.to_string(),
+            },
+            nodeType: NodeType::FunctionNode,
+        };
+        let ops = [
+            op(&["crate", "fns"], "count"),
+            op(&["crate", "fns"], "missing"),
+            op(&["other", "fns"], "count"),
+        ];
+
+        let sigs = Signatures::from_sources(&ops, src_dir.path());
+
+        assert!(sigs.borrows(&ops[0].operatorType, 0));
+        assert!(!sigs.borrows(&ops[0].operatorType, 1));
+        assert!(!sigs.borrows(&ops[1].operatorType, 0));
+        assert!(!sigs.borrows(&ops[2].operatorType, 0));
+    }
+
+    #[test]
+    fn unknown_functions_take_values() {
+        let op_type = OperatorType {
+            qbNamespace: vec!["crate".to_string(), "fns".to_string()],
+            qbName: "f".to_string(),
+        };
+        let mut sigs = Signatures::default();
+        assert!(!sigs.borrows(&op_type, 0));
+
+        sigs.insert(&op_type, vec![false, true]);
+        assert!(!sigs.borrows(&op_type, 0));
+        assert!(sigs.borrows(&op_type, 1));
+        assert!(!sigs.borrows(&op_type, 2));
+    }
+}
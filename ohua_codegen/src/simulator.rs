@@ -0,0 +1,1505 @@
+//! An interpreter for dataflow graphs.
+//!
+//! `simulate` executes an `OhuaData` graph directly, as emitted by `ohuac` or as rewritten by the
+//! backend, without generating any code. The stateful functions of the graph are looked up in a
+//! table of `Functions`, the operators of the runtime (`smapFun`, `collect`, `ifFun`, `select`,
+//! `ctrl`, `nth`, `recurFun`, ... and the operators introduced by `run_backend_optimizations`)
+//! are interpreted with the same semantics as in `ohua_runtime::lang`. This allows checking the
+//! semantics of a graph and of the rewrites applied to it quickly and deterministically.
+//!
+//! Values are dynamically typed. Values the runtime operators have to look into follow a few
+//! conventions: lists (e.g., the input of `smapFun`, the output of `collect`) and tuples are
+//! `Vec<Value>`, the items of `collect_some` are `Option<Value>` and those of `collect_break`
+//! are simulator `Step`s. Literals arrive as `i32`, `()` and `FunRef` respectively.
+use crate::ohua_types::{ArcSource, Envs, Index, NodeType, OhuaData, OpId, OperatorType};
+use std::any::{type_name, Any};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::fmt;
+use std::rc::Rc;
+
+/// Gives up on graphs that keep running for longer than this many rounds.
+const MAX_ROUNDS: usize = 100_000;
+
+/// The port at which a function node receives its state.
+const STATE_PORT: Index = Index::MIN;
+
+/// A dynamically typed value travelling along the arcs of a simulated graph.
+#[derive(Clone)]
+pub struct Value(Rc<dyn Any>);
+
+impl Value {
+    pub fn new<T: Any>(t: T) -> Value {
+        Value(Rc::new(t))
+    }
+
+    /// A list holding `items`.
+    pub fn list<T: Any, I: IntoIterator<Item = T>>(items: I) -> Value {
+        Value::new(items.into_iter().map(Value::new).collect::<Vec<Value>>())
+    }
+
+    pub fn get<T: Any>(&self) -> Option<&T> {
+        self.0.downcast_ref()
+    }
+
+    /// The elements of a list or tuple, if all of them are of type `T`.
+    pub fn items<T: Any + Clone>(&self) -> Option<Vec<T>> {
+        self.get::<Vec<Value>>()?
+            .iter()
+            .map(|item| item.get::<T>().cloned())
+            .collect()
+    }
+
+    /// Extracts an argument of type `T`, which may also be the `Value` itself.
+    fn arg<T: Any + Clone>(&self) -> Option<T> {
+        (self as &dyn Any)
+            .downcast_ref::<T>()
+            .or_else(|| self.get::<T>())
+            .cloned()
+    }
+
+    /// Wraps a result, unless it is a `Value` already.
+    fn from_result<T: Any>(t: T) -> Value {
+        match (Box::new(t) as Box<dyn Any>).downcast::<Value>() {
+            Ok(value) => *value,
+            Err(other) => Value(Rc::from(other)),
+        }
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        macro_rules! try_debug {
+            ($($t:ty),*) => {
+                $(if let Some(v) = self.get::<$t>() {
+                    return write!(f, "{:?}", v);
+                })*
+            };
+        }
+        try_debug!(
+            i32,
+            i64,
+            u32,
+            u64,
+            usize,
+            bool,
+            String,
+            &'static str,
+            (),
+            Vec<Value>,
+            Option<Value>,
+            Step,
+            CtrlToken,
+            FunRef
+        );
+        write!(f, "<opaque>")
+    }
+}
+
+/// Items of loops that may be stopped early, see `ohua_runtime::lang::Step`.
+#[derive(Debug, Clone)]
+pub enum Step {
+    Emit(Value),
+    Skip,
+    Break,
+}
+
+/// A control token, see `ohua_runtime::lang::CtrlToken`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CtrlToken {
+    pub renew_next_time: bool,
+    pub count: usize,
+}
+
+impl CtrlToken {
+    fn renew(count: usize) -> Value {
+        Value::new(CtrlToken {
+            renew_next_time: true,
+            count,
+        })
+    }
+
+    fn keep(count: usize) -> Value {
+        Value::new(CtrlToken {
+            renew_next_time: false,
+            count,
+        })
+    }
+}
+
+/// A reference to a function, passed as literal to operators like `fold` or `reduce`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunRef(pub String);
+
+/// The path under which a function is registered, e.g., `crate::smap::smap_fns::splice`.
+fn path_of(op_type: &OperatorType) -> String {
+    op_type
+        .qbNamespace
+        .iter()
+        .chain(Some(&op_type.qbName))
+        .cloned()
+        .collect::<Vec<String>>()
+        .join("::")
+}
+
+fn is_runtime(op_type: &OperatorType) -> bool {
+    op_type.qbNamespace == ["ohua", "lang"] || op_type.qbNamespace == ["ohua_runtime", "lang"]
+}
+
+type Native = Box<dyn Fn(&[Value]) -> Result<Value, String>>;
+
+/// Rust functions that can be registered with the simulator. `Args` is the tuple of their
+/// argument types, which have to be `Clone` as arguments are cloned out of their `Value`s.
+pub trait Function<Args> {
+    fn call(&self, args: &[Value]) -> Result<Value, String>;
+}
+
+macro_rules! impl_function {
+    ($($arg:ident),*) => {
+        impl<F, R, $($arg),*> Function<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> R,
+            R: Any,
+            $($arg: Any + Clone),*
+        {
+            #[allow(unused_mut, unused_variables)]
+            fn call(&self, args: &[Value]) -> Result<Value, String> {
+                let arity = <[&str]>::len(&[$(stringify!($arg)),*]);
+                if args.len() != arity {
+                    return Err(format!("expected {} arguments, got {}", arity, args.len()));
+                }
+                let mut args = args.iter();
+                Ok(Value::from_result(self($(
+                    args.next().and_then(Value::arg::<$arg>).ok_or_else(|| {
+                        format!("expected an argument of type `{}`", type_name::<$arg>())
+                    })?
+                ),*)))
+            }
+        }
+    };
+}
+
+impl_function!();
+impl_function!(A0);
+impl_function!(A0, A1);
+impl_function!(A0, A1, A2);
+impl_function!(A0, A1, A2, A3);
+impl_function!(A0, A1, A2, A3, A4);
+
+/// The stateful functions available to a simulation, by path.
+///
+/// Methods of state objects are registered under their path as well and receive the state as
+/// first argument. As arguments are cloned, mutable state has to be shared, e.g., via
+/// `Rc<RefCell<_>>`.
+#[derive(Default)]
+pub struct Functions {
+    table: HashMap<String, Native>,
+}
+
+impl Functions {
+    pub fn new() -> Functions {
+        Functions::default()
+    }
+
+    pub fn register<A, F: Function<A> + 'static>(&mut self, path: &str, f: F) -> &mut Functions {
+        self.table
+            .insert(path.to_string(), Box::new(move |args| f.call(args)));
+        self
+    }
+
+    fn call(&self, op: OpId, path: &str, args: &[Value]) -> Result<Value, SimError> {
+        let f = self
+            .table
+            .get(path)
+            .ok_or_else(|| SimError::UnknownFunction {
+                op,
+                path: path.to_string(),
+            })?;
+        f(args).map_err(|message| SimError::Failed { op, message })
+    }
+
+    fn call_ref(&self, op: OpId, f: &Value, args: &[Value]) -> Result<Value, SimError> {
+        let FunRef(path) = expect::<FunRef>(op, f)?;
+        self.call(op, path, args)
+    }
+}
+
+/// Why a simulation did not produce a result.
+#[derive(Debug, PartialEq)]
+pub enum SimError {
+    /// No function is registered for a function node.
+    UnknownFunction { op: OpId, path: String },
+    /// The graph contains a runtime operator the simulator does not know.
+    UnsupportedOperator { op: OpId, name: String },
+    /// The graph refers to an argument of the algorithm that was not supplied.
+    MissingArgument(i32),
+    /// A function failed or an operator received a value of the wrong type.
+    Failed { op: OpId, message: String },
+    /// The graph came to a halt without a result. Lists the operators holding unprocessed
+    /// input.
+    Stuck { waiting: Vec<OpId> },
+    /// The graph did not come to a halt within `MAX_ROUNDS` rounds.
+    Diverged,
+}
+
+impl fmt::Display for SimError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SimError::UnknownFunction { op, ref path } => {
+                write!(f, "no function registered for `{}` (operator {})", path, op)
+            }
+            SimError::UnsupportedOperator { op, ref name } => {
+                write!(f, "operator {} is of unsupported type `{}`", op, name)
+            }
+            SimError::MissingArgument(i) => write!(f, "the algorithm has no argument {}", i),
+            SimError::Failed { op, ref message } => write!(f, "operator {}: {}", op, message),
+            SimError::Stuck { ref waiting } => write!(
+                f,
+                "the graph halted without a result, operators waiting with input: {:?}",
+                waiting
+            ),
+            SimError::Diverged => write!(f, "the graph did not halt"),
+        }
+    }
+}
+
+fn expect<T: Any>(op: OpId, value: &Value) -> Result<&T, SimError> {
+    value.get::<T>().ok_or_else(|| SimError::Failed {
+        op,
+        message: format!("expected a `{}`, got {:?}", type_name::<T>(), value),
+    })
+}
+
+/// The outcome of a simulation.
+#[derive(Debug)]
+pub struct Run {
+    pub result: Value,
+    /// How often the function of each function node was called.
+    pub calls: BTreeMap<OpId, usize>,
+    /// The input ports still holding values once the graph halted, with the number of values.
+    pub stale: Vec<((OpId, Index), usize)>,
+}
+
+enum Input {
+    Local(Index),
+    Env(Index, Value),
+}
+
+enum State {
+    Stateless,
+    /// The state arrives via a state arc.
+    Pending,
+    Ready(Value),
+}
+
+struct FunctionNode {
+    path: String,
+    runtime: bool,
+    inputs: Vec<Input>,
+    state: State,
+    gated: bool,
+    /// The calls left to make for the last control token at a gate.
+    pending: usize,
+    called: bool,
+}
+
+enum RecurPhase {
+    Start,
+    Loop,
+    Done,
+}
+
+enum Node {
+    Function(FunctionNode),
+    Smap,
+    SmapBreak {
+        items: Option<VecDeque<Value>>,
+        waiting: bool,
+    },
+    Destruct,
+    Collect,
+    CollectSome,
+    CollectBreak {
+        buffered: Vec<Value>,
+        stopped: bool,
+    },
+    Fold {
+        init: Option<Value>,
+        f: Option<Value>,
+    },
+    If,
+    Switch,
+    Select,
+    SelectN,
+    SelectDrain {
+        drain: Option<i32>,
+    },
+    Ctrl {
+        vars: Index,
+        values: Option<Vec<Value>>,
+        renew: bool,
+    },
+    Recur {
+        arity: Index,
+        phase: RecurPhase,
+    },
+}
+
+impl Node {
+    /// The output port that carries the result of the algorithm if this is the last operator.
+    fn result_port(&self) -> Index {
+        match *self {
+            Node::CollectBreak { .. } => 1,
+            Node::Recur { arity, .. } => arity + 1,
+            _ => 0,
+        }
+    }
+}
+
+/// The queues of all input ports and the routes from the output ports to them.
+struct Network {
+    queues: HashMap<(OpId, Index), VecDeque<Value>>,
+    routes: HashMap<(OpId, Index), Vec<(OpId, Index)>>,
+    result: (OpId, Index),
+    results: Vec<Value>,
+}
+
+/// The arcs as seen by a single operator.
+struct Io<'a> {
+    op: OpId,
+    arcs: &'a mut Network,
+}
+
+impl<'a> Io<'a> {
+    fn available(&self, port: Index) -> usize {
+        self.arcs
+            .queues
+            .get(&(self.op, port))
+            .map(VecDeque::len)
+            .unwrap_or(0)
+    }
+
+    fn ready<I: IntoIterator<Item = Index>>(&self, ports: I) -> bool {
+        ports.into_iter().all(|port| self.available(port) > 0)
+    }
+
+    fn peek(&self, port: Index) -> Option<&Value> {
+        self.arcs.queues.get(&(self.op, port))?.front()
+    }
+
+    /// Receives from `port`, which has to be checked to be non-empty.
+    fn take(&mut self, port: Index) -> Value {
+        self.arcs
+            .queues
+            .get_mut(&(self.op, port))
+            .and_then(VecDeque::pop_front)
+            .expect("received from an empty arc")
+    }
+
+    fn take_all<I: IntoIterator<Item = Index>>(&mut self, ports: I) -> Vec<Value> {
+        ports.into_iter().map(|port| self.take(port)).collect()
+    }
+
+    fn peek_as<T: Any + Clone>(&self, port: Index) -> Result<Option<T>, SimError> {
+        match self.peek(port) {
+            Some(value) => expect::<T>(self.op, value).map(|t| Some(t.clone())),
+            None => Ok(None),
+        }
+    }
+
+    fn emit(&mut self, port: Index, value: Value) {
+        if (self.op, port) == self.arcs.result {
+            self.arcs.results.push(value.clone());
+        }
+        if let Some(targets) = self.arcs.routes.get(&(self.op, port)) {
+            for target in targets {
+                self.arcs
+                    .queues
+                    .entry(*target)
+                    .or_default()
+                    .push_back(value.clone());
+            }
+        }
+    }
+}
+
+fn list(op: OpId, value: &Value) -> Result<Vec<Value>, SimError> {
+    expect::<Vec<Value>>(op, value).cloned()
+}
+
+/// The functions of the runtime that are function nodes in the graph.
+fn call_runtime(
+    op: OpId,
+    name: &str,
+    args: &[Value],
+    functions: &Functions,
+) -> Result<Value, SimError> {
+    let arg = |idx: usize| {
+        args.get(idx).ok_or_else(|| SimError::Failed {
+            op,
+            message: format!("`{}` expects at least {} arguments", name, idx + 1),
+        })
+    };
+    match name {
+        "id" => Ok(arg(0)?.clone()),
+        "tuple" => Ok(Value::new(args.to_vec())),
+        "seqFun" => Ok(CtrlToken::renew(1)),
+        "nth" => nth(op, *expect::<i32>(op, arg(0)?)? as usize, arg(2)?),
+        _ if name.starts_with("nth_") => match name["nth_".len()..].parse() {
+            Ok(idx) => nth(op, idx, arg(0)?),
+            Err(_) => Err(SimError::UnsupportedOperator {
+                op,
+                name: name.to_string(),
+            }),
+        },
+        "unitFn" => functions.call_ref(op, arg(0)?, &[]),
+        "reduce" => list(op, arg(0)?)?
+            .into_iter()
+            .try_fold(arg(1)?.clone(), |acc, item| {
+                functions.call_ref(op, arg(2)?, &[acc, item])
+            }),
+        "somes" => Ok(Value::new(
+            list(op, arg(0)?)?
+                .iter()
+                .map(|item| expect::<Option<Value>>(op, item).cloned())
+                .collect::<Result<Vec<Option<Value>>, SimError>>()?
+                .into_iter()
+                .flatten()
+                .collect::<Vec<Value>>(),
+        )),
+        "until_break" => {
+            let mut items = Vec::new();
+            for item in list(op, arg(0)?)? {
+                match expect::<Step>(op, &item)? {
+                    Step::Emit(d) => items.push(d.clone()),
+                    Step::Skip => (),
+                    Step::Break => break,
+                }
+            }
+            Ok(Value::new(items))
+        }
+        _ => Err(SimError::UnsupportedOperator {
+            op,
+            name: name.to_string(),
+        }),
+    }
+}
+
+fn nth(op: OpId, idx: usize, tuple: &Value) -> Result<Value, SimError> {
+    expect::<Vec<Value>>(op, tuple)?
+        .get(idx)
+        .cloned()
+        .ok_or_else(|| SimError::Failed {
+            op,
+            message: format!("tuple has no element {}", idx),
+        })
+}
+
+/// The index of the arm chosen by the condition of an `ifFun` or `switchFun`, see
+/// `ohua_runtime::lang::Discriminant`.
+fn arm(op: OpId, cond: &Value) -> Result<usize, SimError> {
+    if let Some(b) = cond.get::<bool>() {
+        return Ok(if *b { 0 } else { 1 });
+    }
+    macro_rules! try_int {
+        ($($t:ty),*) => {
+            $(if let Some(i) = cond.get::<$t>() {
+                return usize::try_from(*i).map_err(|_| SimError::Failed {
+                    op,
+                    message: format!("{} does not denote an arm", i),
+                });
+            })*
+        };
+    }
+    try_int!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+    Err(SimError::Failed {
+        op,
+        message: format!("{:?} is not a discriminant", cond),
+    })
+}
+
+fn token(op: OpId, value: &Value) -> Result<CtrlToken, SimError> {
+    expect::<CtrlToken>(op, value).copied()
+}
+
+/// Fires a function node once, if possible. Returns whether anything was done.
+fn fire_function(
+    node: &mut FunctionNode,
+    io: &mut Io,
+    functions: &Functions,
+    calls: &mut BTreeMap<OpId, usize>,
+) -> Result<bool, SimError> {
+    if let State::Pending = node.state {
+        if io.available(STATE_PORT) == 0 {
+            return Ok(false);
+        }
+        node.state = State::Ready(io.take(STATE_PORT));
+    }
+
+    let locals: Vec<Index> = node
+        .inputs
+        .iter()
+        .filter_map(|input| match *input {
+            Input::Local(port) => Some(port),
+            Input::Env(..) => None,
+        })
+        .collect();
+    if node.gated && node.pending == 0 {
+        if io.available(-1) == 0 {
+            return Ok(false);
+        }
+        node.pending = token(io.op, &io.take(-1))?.count;
+        return Ok(true);
+    }
+    // functions without any inputs are called exactly once
+    if (!node.gated && locals.is_empty() && node.called) || !io.ready(locals) {
+        return Ok(false);
+    }
+
+    let mut args = Vec::new();
+    if let State::Ready(ref state) = node.state {
+        args.push(state.clone());
+    }
+    for input in &node.inputs {
+        args.push(match *input {
+            Input::Local(port) => io.take(port),
+            Input::Env(_, ref value) => value.clone(),
+        });
+    }
+
+    let result = if node.runtime {
+        let name = &node.path[node.path.rfind("::").map(|i| i + 2).unwrap_or(0)..];
+        call_runtime(io.op, name, &args, functions)?
+    } else {
+        functions.call(io.op, &node.path, &args)?
+    };
+    *calls.entry(io.op).or_default() += 1;
+    node.called = true;
+    node.pending = node.pending.saturating_sub(1);
+    io.emit(0, result);
+    Ok(true)
+}
+
+/// Fires an operator once, if possible, following the implementation of the operator in
+/// `ohua_runtime::lang`. Returns whether anything was done.
+fn fire_operator(node: &mut Node, io: &mut Io, functions: &Functions) -> Result<bool, SimError> {
+    let op = io.op;
+    match *node {
+        Node::Function(_) => unreachable!(),
+        Node::Smap => {
+            if !io.ready(Some(0)) {
+                return Ok(false);
+            }
+            let items = list(op, &io.take(0))?;
+            io.emit(2, Value::new(items.len()));
+            io.emit(1, CtrlToken::renew(items.len()));
+            for item in items {
+                io.emit(0, item);
+            }
+        }
+        Node::SmapBreak {
+            ref mut items,
+            ref mut waiting,
+        } => {
+            if *waiting {
+                // wait for the collector, ignoring a stop request that came too late
+                if !io.ready(Some(1)) {
+                    return Ok(false);
+                }
+                *waiting = *expect::<bool>(op, &io.take(1))?;
+                return Ok(true);
+            }
+            if items.is_none() {
+                if !io.ready(Some(0)) {
+                    return Ok(false);
+                }
+                *items = Some(list(op, &io.take(0))?.into());
+                return Ok(true);
+            }
+            let pending = items.as_mut().unwrap();
+            if io.ready(Some(1)) && *expect::<bool>(op, &io.take(1))? {
+                pending.clear();
+            }
+            match pending.pop_front() {
+                Some(item) => {
+                    io.emit(2, Value::new(true));
+                    io.emit(0, item);
+                    io.emit(1, CtrlToken::keep(1));
+                }
+                None => {
+                    io.emit(2, Value::new(false));
+                    io.emit(1, CtrlToken::renew(0));
+                    *items = None;
+                    *waiting = true;
+                }
+            }
+        }
+        Node::Destruct => {
+            if !io.ready(Some(0)) {
+                return Ok(false);
+            }
+            for (port, element) in list(op, &io.take(0))?.into_iter().enumerate() {
+                io.emit(port as Index, element);
+            }
+        }
+        Node::Collect | Node::CollectSome => {
+            let size = match io.peek_as::<usize>(0)? {
+                Some(size) if io.available(1) >= size => size,
+                _ => return Ok(false),
+            };
+            io.take(0);
+            let mut items = io.take_all(vec![1; size]);
+            if let Node::CollectSome = *node {
+                items = items
+                    .iter()
+                    .map(|item| expect::<Option<Value>>(op, item).cloned())
+                    .collect::<Result<Vec<Option<Value>>, SimError>>()?
+                    .into_iter()
+                    .flatten()
+                    .collect();
+            }
+            io.emit(0, Value::new(items));
+        }
+        Node::CollectBreak {
+            ref mut buffered,
+            ref mut stopped,
+        } => match io.peek_as::<bool>(0)? {
+            Some(true) if io.ready(Some(1)) => {
+                io.take(0);
+                let item = io.take(1);
+                match *expect::<Step>(op, &item)? {
+                    _ if *stopped => (),
+                    Step::Emit(ref d) => buffered.push(d.clone()),
+                    Step::Skip => (),
+                    Step::Break => {
+                        *stopped = true;
+                        io.emit(0, Value::new(true));
+                    }
+                }
+            }
+            Some(false) => {
+                io.take(0);
+                io.emit(0, Value::new(false));
+                io.emit(1, Value::new(buffered.split_off(0)));
+                *stopped = false;
+            }
+            _ => return Ok(false),
+        },
+        Node::Fold {
+            ref mut init,
+            ref mut f,
+        } => {
+            // `init` and `f` are received once and reused for every loop
+            if init.is_none() || f.is_none() {
+                if !io.ready(vec![2, 3]) {
+                    return Ok(false);
+                }
+                *init = Some(io.take(2));
+                *f = Some(io.take(3));
+                return Ok(true);
+            }
+            let size = match io.peek_as::<usize>(0)? {
+                Some(size) if io.available(1) >= size => size,
+                _ => return Ok(false),
+            };
+            io.take(0);
+            let mut acc = init.clone().unwrap();
+            for item in io.take_all(vec![1; size]) {
+                acc = functions.call_ref(op, f.as_ref().unwrap(), &[acc, item])?;
+            }
+            io.emit(0, acc);
+        }
+        Node::If | Node::Switch => {
+            if !io.ready(Some(0)) {
+                return Ok(false);
+            }
+            let taken = arm(op, &io.take(0))?;
+            let arms = if let Node::If = *node {
+                2
+            } else {
+                io.arcs
+                    .routes
+                    .keys()
+                    .filter(|(src, _)| *src == op)
+                    .map(|(_, port)| port + 1)
+                    .max()
+                    .unwrap_or(0)
+            };
+            if taken >= arms as usize {
+                return Err(SimError::Failed {
+                    op,
+                    message: format!("there is no arm {} among {} arms", taken, arms),
+                });
+            }
+            for port in 0..arms {
+                let count = if port as usize == taken { 1 } else { 0 };
+                io.emit(port, CtrlToken::renew(count));
+            }
+        }
+        Node::Select | Node::SelectN => {
+            let branch = match io.peek(0) {
+                Some(cond) => arm(op, cond)? as Index + 1,
+                None => return Ok(false),
+            };
+            if !io.ready(Some(branch)) {
+                return Ok(false);
+            }
+            io.take(0);
+            let result = io.take(branch);
+            io.emit(0, result);
+        }
+        Node::SelectDrain { ref mut drain } => {
+            let mask = match *drain {
+                Some(mask) => mask,
+                None if io.ready(Some(3)) => {
+                    *drain = Some(*expect::<i32>(op, &io.take(3))?);
+                    return Ok(true);
+                }
+                None => return Ok(false),
+            };
+            let (taken, untaken, drain_untaken) = match io.peek_as::<bool>(0)? {
+                Some(true) => (1, 2, mask & 2 != 0),
+                Some(false) => (2, 1, mask & 1 != 0),
+                None => return Ok(false),
+            };
+            if !io.ready(Some(taken)) || (drain_untaken && !io.ready(Some(untaken))) {
+                return Ok(false);
+            }
+            io.take(0);
+            let result = io.take(taken);
+            if drain_untaken {
+                io.take(untaken);
+            }
+            io.emit(0, result);
+        }
+        Node::Ctrl {
+            vars,
+            ref mut values,
+            ref mut renew,
+        } => {
+            if values.is_none() {
+                if !io.ready(1..=vars) {
+                    return Ok(false);
+                }
+                *values = Some(io.take_all(1..=vars));
+                return Ok(true);
+            }
+            if !io.ready(Some(0)) || (*renew && !io.ready(1..=vars)) {
+                return Ok(false);
+            }
+            let token = token(op, &io.take(0))?;
+            if *renew {
+                *values = Some(io.take_all(1..=vars));
+            }
+            for _ in 0..token.count {
+                for (port, value) in values.as_ref().unwrap().iter().enumerate() {
+                    io.emit(port as Index, value.clone());
+                }
+            }
+            *renew = token.renew_next_time;
+        }
+        Node::Recur {
+            arity,
+            ref mut phase,
+        } => {
+            let inits = 2..2 + arity;
+            let loop_ins = 2 + arity..2 + 2 * arity;
+            match *phase {
+                RecurPhase::Start if io.ready(inits.clone()) => {
+                    io.emit(0, CtrlToken::renew(1));
+                    for (port, value) in io.take_all(inits).into_iter().enumerate() {
+                        io.emit(port as Index + 1, value);
+                    }
+                    *phase = RecurPhase::Loop;
+                }
+                RecurPhase::Loop => match io.peek_as::<bool>(0)? {
+                    Some(true) if io.ready(Some(1)) && io.ready(loop_ins.clone()) => {
+                        io.take_all(vec![0, 1]);
+                        io.emit(0, CtrlToken::renew(1));
+                        for (port, value) in io.take_all(loop_ins).into_iter().enumerate() {
+                            io.emit(port as Index + 1, value);
+                        }
+                    }
+                    Some(false) if io.ready(Some(1)) && io.ready(loop_ins.clone()) => {
+                        io.take(0);
+                        // the arguments computed by the last iteration are not needed anymore
+                        io.take_all(loop_ins);
+                        io.emit(0, CtrlToken::keep(0));
+                        let result = io.take(1);
+                        io.emit(arity + 1, result);
+                        *phase = RecurPhase::Done;
+                    }
+                    _ => return Ok(false),
+                },
+                _ => return Ok(false),
+            }
+        }
+    }
+    Ok(true)
+}
+
+/// Executes `graph` once with the arguments `args`, calling the stateful functions in
+/// `functions`, and returns the result of the algorithm.
+///
+/// The operators take turns in the order of the graph, each receiving at most once per turn,
+/// until none of them can make progress anymore.
+pub fn simulate(graph: &OhuaData, functions: &Functions, args: &[Value]) -> Result<Run, SimError> {
+    let env = |lit: &Envs| -> Result<Value, SimError> {
+        Ok(match *lit {
+            Envs::NumericLit { content } => Value::new(content),
+            Envs::EnvRefLit { content } => args
+                .get(content as usize)
+                .cloned()
+                .ok_or(SimError::MissingArgument(content))?,
+            Envs::FunRefLit { ref contents } => Value::new(FunRef(path_of(contents))),
+            Envs::UnitLit {} => Value::new(()),
+        })
+    };
+    let is_function = |op: OpId| {
+        graph
+            .graph
+            .operators
+            .iter()
+            .any(|o| o.operatorId == op && o.nodeType == NodeType::FunctionNode)
+    };
+
+    let mut arcs = Network {
+        queues: HashMap::new(),
+        routes: HashMap::new(),
+        result: (graph.graph.return_arc.operator, 0),
+        results: Vec::new(),
+    };
+    let mut add_route = |src: &crate::ohua_types::ArcIdentifier, target: (OpId, Index)| {
+        // function nodes send their result via all of their outputs
+        let port = if is_function(src.operator) {
+            0
+        } else {
+            src.index
+        };
+        arcs.routes
+            .entry((src.operator, port))
+            .or_default()
+            .push(target);
+    };
+    // values sent to dead ends are discarded
+    let exists = |op: OpId| graph.graph.operators.iter().any(|o| o.operatorId == op);
+    for arc in graph
+        .graph
+        .arcs
+        .direct
+        .iter()
+        .filter(|a| exists(a.target.operator))
+    {
+        if let ArcSource::Local(ref src) = arc.source {
+            add_route(src, (arc.target.operator, arc.target.index));
+        }
+    }
+    for arc in &graph.graph.arcs.state {
+        if let ArcSource::Local(ref src) = arc.source {
+            add_route(src, (arc.target, STATE_PORT));
+        }
+    }
+
+    let mut nodes = Vec::new();
+    for op in &graph.graph.operators {
+        let id = op.operatorId;
+        let mut inputs = Vec::new();
+        for arc in graph
+            .graph
+            .arcs
+            .direct
+            .iter()
+            .filter(|a| a.target.operator == id)
+        {
+            inputs.push(match arc.source {
+                ArcSource::Local(_) => Input::Local(arc.target.index),
+                ArcSource::Env(ref lit) => Input::Env(arc.target.index, env(lit)?),
+            });
+        }
+        inputs.sort_by_key(|input| match *input {
+            Input::Local(port) | Input::Env(port, _) => port,
+        });
+        let ports = inputs.len() as Index;
+
+        let name = op.operatorType.qbName.as_str();
+        let node = match op.nodeType {
+            NodeType::FunctionNode => {
+                let gated = inputs.iter().any(|i| matches!(*i, Input::Local(-1)));
+                let state = match graph.graph.arcs.state.iter().find(|a| a.target == id) {
+                    Some(arc) => match arc.source {
+                        ArcSource::Local(_) => State::Pending,
+                        ArcSource::Env(ref lit) => State::Ready(env(lit)?),
+                    },
+                    None => State::Stateless,
+                };
+                inputs.retain(|i| !matches!(*i, Input::Local(-1)));
+                Node::Function(FunctionNode {
+                    path: path_of(&op.operatorType),
+                    runtime: is_runtime(&op.operatorType),
+                    inputs,
+                    state,
+                    gated,
+                    pending: 0,
+                    called: false,
+                })
+            }
+            NodeType::OperatorNode => {
+                // literals are sent to operators only once
+                for input in inputs {
+                    if let Input::Env(port, value) = input {
+                        arcs.queues.entry((id, port)).or_default().push_back(value);
+                    }
+                }
+                if !is_runtime(&op.operatorType) {
+                    return Err(SimError::UnsupportedOperator {
+                        op: id,
+                        name: path_of(&op.operatorType),
+                    });
+                }
+                match name {
+                    "smapFun" => Node::Smap,
+                    "smapBreakFun" => Node::SmapBreak {
+                        items: None,
+                        waiting: false,
+                    },
+                    "destruct" => Node::Destruct,
+                    "collect" => Node::Collect,
+                    "collect_some" => Node::CollectSome,
+                    "collect_break" => Node::CollectBreak {
+                        buffered: Vec::new(),
+                        stopped: false,
+                    },
+                    "fold" => Node::Fold {
+                        init: None,
+                        f: None,
+                    },
+                    "ifFun" => Node::If,
+                    "switchFun" => Node::Switch,
+                    "select" => Node::Select,
+                    "select_n" => Node::SelectN,
+                    "select_drain" => Node::SelectDrain { drain: None },
+                    "ctrl" => Node::Ctrl {
+                        vars: ports - 1,
+                        values: None,
+                        renew: false,
+                    },
+                    "recurFun" => Node::Recur {
+                        arity: (ports - 2) / 2,
+                        phase: RecurPhase::Start,
+                    },
+                    _ => {
+                        return Err(SimError::UnsupportedOperator {
+                            op: id,
+                            name: name.to_string(),
+                        })
+                    }
+                }
+            }
+        };
+        if id == graph.graph.return_arc.operator {
+            arcs.result.1 = node.result_port();
+        }
+        nodes.push((id, node));
+    }
+
+    let mut calls = BTreeMap::new();
+    let mut rounds = 0;
+    loop {
+        let mut progress = false;
+        for (op, node) in nodes.iter_mut() {
+            let mut io = Io {
+                op: *op,
+                arcs: &mut arcs,
+            };
+            progress |= match *node {
+                Node::Function(ref mut f) => fire_function(f, &mut io, functions, &mut calls)?,
+                ref mut node => fire_operator(node, &mut io, functions)?,
+            };
+        }
+        if !progress {
+            break;
+        }
+        rounds += 1;
+        if rounds == MAX_ROUNDS {
+            return Err(SimError::Diverged);
+        }
+    }
+
+    let mut stale: Vec<((OpId, Index), usize)> = arcs
+        .queues
+        .iter()
+        .filter(|(_, queue)| !queue.is_empty())
+        .map(|(port, queue)| (*port, queue.len()))
+        .collect();
+    stale.sort();
+    match arcs.results.into_iter().next() {
+        Some(result) => Ok(Run {
+            result,
+            calls,
+            stale,
+        }),
+        None => {
+            let mut waiting: Vec<OpId> = stale.iter().map(|((op, _), _)| *op).collect();
+            waiting.dedup();
+            Err(SimError::Stuck { waiting })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alter_ohua_ns_imports;
+    use crate::backend_optimizations::{run_backend_optimizations, Passes};
+    use crate::ohua_types::*;
+    use std::fs;
+    use std::path::Path;
+
+    fn fixture(path: &str) -> OhuaData {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../testcases/src")
+            .join(path);
+        let mut data: OhuaData = serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+        alter_ohua_ns_imports(&mut data);
+        data
+    }
+
+    fn op(id: OpId, name: &str, node_type: NodeType) -> Operator {
+        let namespace = match node_type {
+            NodeType::OperatorNode => vec!["ohua_runtime".into(), "lang".into()],
+            NodeType::FunctionNode => vec!["crate".into()],
+        };
+        Operator {
+            operatorId: id,
+            operatorType: OperatorType {
+                qbNamespace: namespace,
+                qbName: name.into(),
+            },
+            nodeType: node_type,
+        }
+    }
+
+    fn runtime_fn(id: OpId, name: &str) -> Operator {
+        let mut op = op(id, name, NodeType::FunctionNode);
+        op.operatorType.qbNamespace = vec!["ohua_runtime".into(), "lang".into()];
+        op
+    }
+
+    fn arc(src: OpId, src_idx: Index, tgt: OpId, tgt_idx: Index) -> DirectArc {
+        DirectArc {
+            target: ArcIdentifier {
+                operator: tgt,
+                index: tgt_idx,
+            },
+            source: ArcSource::Local(ArcIdentifier {
+                operator: src,
+                index: src_idx,
+            }),
+        }
+    }
+
+    fn env(lit: Envs, tgt: OpId, tgt_idx: Index) -> DirectArc {
+        DirectArc {
+            target: ArcIdentifier {
+                operator: tgt,
+                index: tgt_idx,
+            },
+            source: ArcSource::Env(lit),
+        }
+    }
+
+    fn graph(operators: Vec<Operator>, direct: Vec<DirectArc>, result: OpId) -> OhuaData {
+        OhuaData {
+            graph: DFGraph {
+                operators,
+                arcs: Arcs {
+                    direct,
+                    state: vec![],
+                    dead: vec![],
+                },
+                return_arc: ArcIdentifier {
+                    operator: result,
+                    index: -1,
+                },
+                input_targets: Vec::new(),
+            },
+            mainArity: 1,
+            sfDependencies: Vec::new(),
+        }
+    }
+
+    /// Runs `data` before and after the backend optimizations, which must agree on the result.
+    fn simulate_optimized(mut data: OhuaData, functions: &Functions, args: &[Value]) -> (Run, Run) {
+        let plain = simulate(&data, functions, args).unwrap();
+        run_backend_optimizations(&mut data, &Passes::default());
+        let optimized = simulate(&data, functions, args).unwrap();
+        assert_eq!(
+            format!("{:?}", plain.result),
+            format!("{:?}", optimized.result)
+        );
+        (plain, optimized)
+    }
+
+    #[test]
+    fn smap_fixture() {
+        let mut functions = Functions::new();
+        functions
+            .register("crate::smap::smap_fns::gen_input", || {
+                Value::list(vec!["a".to_string(), "bb".to_string()])
+            })
+            .register("crate::smap::smap_fns::splice", |s: String| s + "!")
+            .register("crate::smap::smap_fns::printout", |_: String| ());
+
+        let data = fixture("smap/algorithms/smap_test.ohuao");
+        let (run, _) = simulate_optimized(data, &functions, &[]);
+        assert_eq!(
+            run.result.items::<String>(),
+            Some(vec!["a!".to_string(), "bb!".to_string()])
+        );
+        assert_eq!(run.calls[&3], 2);
+        assert!(run.stale.is_empty());
+    }
+
+    #[test]
+    fn if_fixture_takes_one_branch() {
+        for &cond in &[true, false] {
+            let mut functions = Functions::new();
+            functions
+                .register("crate::conditionals::iftest::get_cond_result", move || cond)
+                .register("crate::conditionals::iftest::get_input", || "x".to_string())
+                .register(
+                    "crate::conditionals::iftest::modify_string_positive",
+                    |s: String| s + "+",
+                )
+                .register(
+                    "crate::conditionals::iftest::modify_string_negative",
+                    |s: String| s + "-",
+                );
+
+            let data = fixture("conditionals/algorithms/if_test_base_0.ohuao");
+            let (run, _) = simulate_optimized(data, &functions, &[]);
+            let expected = if cond { "x+" } else { "x-" };
+            assert_eq!(run.result.get::<String>().unwrap(), expected);
+            assert_eq!(run.calls.contains_key(&6), cond);
+            assert_eq!(run.calls.contains_key(&7), !cond);
+            assert!(run.stale.is_empty());
+        }
+    }
+
+    #[test]
+    fn switch_fixture_takes_one_arm() {
+        let functions = |variant: i32| {
+            let mut functions = Functions::new();
+            functions
+                .register("crate::conditionals::iftest::get_variant", move || variant)
+                .register("crate::conditionals::iftest::get_input", || "x".to_string())
+                .register(
+                    "crate::conditionals::iftest::modify_string_positive",
+                    |s: String| s + "+",
+                )
+                .register(
+                    "crate::conditionals::iftest::modify_string_negative",
+                    |s: String| s + "-",
+                )
+                .register(
+                    "crate::conditionals::iftest::modify_string_unknown",
+                    |s: String| s + "?",
+                );
+            functions
+        };
+
+        for (variant, expected) in [(0, "x+"), (1, "x-"), (2, "x?")].iter() {
+            let data = fixture("conditionals/algorithms/switch_test.ohuao");
+            let (run, _) = simulate_optimized(data, &functions(*variant), &[]);
+            assert_eq!(run.result.get::<String>().unwrap(), expected);
+            assert_eq!(run.calls.len(), 3);
+            assert!(run.stale.is_empty());
+        }
+
+        for &variant in &[3, -1] {
+            let data = fixture("conditionals/algorithms/switch_test.ohuao");
+            match simulate(&data, &functions(variant), &[]) {
+                Err(SimError::Failed { op: 3, .. }) => (),
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn collect_reduce_and_fold_agree() {
+        let add = OperatorType {
+            qbNamespace: vec!["crate".into()],
+            qbName: "add".into(),
+        };
+        let data = graph(
+            vec![
+                op(1, "smapFun", NodeType::OperatorNode),
+                op(2, "double", NodeType::FunctionNode),
+                op(3, "collect", NodeType::OperatorNode),
+                runtime_fn(4, "reduce"),
+            ],
+            vec![
+                env(Envs::EnvRefLit { content: 0 }, 1, 0),
+                arc(1, 0, 2, 0),
+                arc(1, 2, 3, 0),
+                arc(2, 0, 3, 1),
+                arc(3, 0, 4, 0),
+                env(Envs::NumericLit { content: 0 }, 4, 1),
+                env(Envs::FunRefLit { contents: add }, 4, 2),
+            ],
+            4,
+        );
+        let mut functions = Functions::new();
+        functions
+            .register("crate::double", |x: i32| x * 2)
+            .register("crate::add", |a: i32, b: i32| a + b);
+
+        let (plain, optimized) =
+            simulate_optimized(data, &functions, &[Value::list(vec![1, 2, 3])]);
+        assert_eq!(plain.result.get::<i32>(), Some(&12));
+        assert_eq!(optimized.calls.get(&4), None);
+    }
+
+    #[test]
+    fn until_break_stops_the_loop() {
+        let data = graph(
+            vec![
+                op(1, "smapFun", NodeType::OperatorNode),
+                op(2, "check", NodeType::FunctionNode),
+                op(3, "collect", NodeType::OperatorNode),
+                runtime_fn(4, "until_break"),
+            ],
+            vec![
+                env(Envs::EnvRefLit { content: 0 }, 1, 0),
+                arc(1, 0, 2, 0),
+                arc(1, 2, 3, 0),
+                arc(2, 0, 3, 1),
+                arc(3, 0, 4, 0),
+            ],
+            4,
+        );
+        let mut functions = Functions::new();
+        functions.register("crate::check", |x: i32| match x {
+            2 => Step::Skip,
+            3 => Step::Break,
+            _ => Step::Emit(Value::new(x)),
+        });
+
+        let input = Value::list(vec![1, 2, 3, 4, 5]);
+        let (plain, optimized) = simulate_optimized(data, &functions, &[input]);
+        assert_eq!(plain.result.items::<i32>(), Some(vec![1]));
+        assert_eq!(plain.calls[&2], 5);
+        assert!(optimized.calls[&2] < 5);
+        assert!(optimized.stale.is_empty());
+    }
+
+    #[test]
+    fn gated_branches_run_only_when_taken() {
+        let data = graph(
+            vec![
+                op(1, "get_cond", NodeType::FunctionNode),
+                op(2, "ifFun", NodeType::OperatorNode),
+                op(3, "positive", NodeType::FunctionNode),
+                op(4, "negative", NodeType::FunctionNode),
+                op(5, "select", NodeType::OperatorNode),
+            ],
+            vec![
+                env(Envs::EnvRefLit { content: 0 }, 1, 0),
+                arc(1, 0, 2, 0),
+                arc(1, 0, 5, 0),
+                arc(3, 0, 5, 1),
+                arc(4, 0, 5, 2),
+            ],
+            5,
+        );
+        let mut functions = Functions::new();
+        functions
+            .register("crate::get_cond", |b: bool| b)
+            .register("crate::positive", || 1)
+            .register("crate::negative", || -1);
+
+        let (plain, optimized) = simulate_optimized(data, &functions, &[Value::new(true)]);
+        assert_eq!(plain.result.get::<i32>(), Some(&1));
+        // without gates, the result of the other branch is left behind
+        assert_eq!(plain.calls.get(&4), Some(&1));
+        assert_eq!(plain.stale, vec![((5, 2), 1)]);
+        assert_eq!(optimized.calls.get(&4), None);
+        assert!(optimized.stale.is_empty());
+    }
+
+    #[test]
+    fn unused_control_flow_is_removed() {
+        // a loop and a conditional whose results are dropped, next to `sum(xs)`
+        let mut data = graph(
+            vec![
+                op(1, "smapFun", NodeType::OperatorNode),
+                op(2, "ctrl", NodeType::OperatorNode),
+                op(3, "add", NodeType::FunctionNode),
+                op(4, "collect", NodeType::OperatorNode),
+                op(5, "offset", NodeType::FunctionNode),
+                op(6, "get_cond", NodeType::FunctionNode),
+                op(7, "ifFun", NodeType::OperatorNode),
+                op(8, "ctrl", NodeType::OperatorNode),
+                op(9, "ctrl", NodeType::OperatorNode),
+                op(10, "positive", NodeType::FunctionNode),
+                op(11, "negative", NodeType::FunctionNode),
+                op(12, "select", NodeType::OperatorNode),
+                op(13, "sum", NodeType::FunctionNode),
+            ],
+            vec![
+                env(Envs::EnvRefLit { content: 0 }, 1, 0),
+                arc(1, 0, 3, 0),
+                arc(1, 1, 2, 0),
+                arc(1, 2, 4, 0),
+                arc(5, 0, 2, 1),
+                arc(2, 0, 3, 1),
+                arc(3, 0, 4, 1),
+                arc(4, 0, 0, 0),
+                arc(6, 0, 7, 0),
+                arc(6, 0, 12, 0),
+                arc(7, 0, 8, 0),
+                arc(7, 1, 9, 0),
+                arc(5, 0, 8, 1),
+                arc(5, 0, 9, 1),
+                arc(8, 0, 10, 0),
+                arc(9, 0, 11, 0),
+                arc(10, 0, 12, 1),
+                arc(11, 0, 12, 2),
+                arc(12, 0, 0, 0),
+                env(Envs::EnvRefLit { content: 0 }, 13, 0),
+            ],
+            13,
+        );
+        let mut functions = Functions::new();
+        functions
+            .register("crate::offset", || 10)
+            .register("crate::add", |a: i32, b: i32| a + b)
+            .register("crate::get_cond", || true)
+            .register("crate::positive", |x: i32| x)
+            .register("crate::negative", |x: i32| -x)
+            .register("crate::sum", |xs: Vec<Value>| {
+                xs.iter().map(|x| x.get::<i32>().unwrap()).sum::<i32>()
+            });
+        let args = [Value::list(vec![1, 2, 3])];
+
+        let plain = simulate(&data, &functions, &args).unwrap();
+        run_backend_optimizations(&mut data, &Passes::default());
+        let ops: Vec<OpId> = data.graph.operators.iter().map(|o| o.operatorId).collect();
+        assert_eq!(ops, vec![1, 2, 3, 5, 6, 7, 8, 9, 10, 11, 13]);
+
+        // the functions inside the loop and the branch still run as before
+        let optimized = simulate(&data, &functions, &args).unwrap();
+        assert_eq!(optimized.result.get::<i32>(), Some(&6));
+        assert_eq!(plain.calls, optimized.calls);
+        assert_eq!(optimized.calls[&3], 3);
+        assert!(optimized.stale.is_empty());
+    }
+
+    #[test]
+    fn destructuring_and_literals() {
+        // `let (a, b) = pair(); add3(a, b, id(10))`
+        let data = graph(
+            vec![
+                op(1, "pair", NodeType::FunctionNode),
+                runtime_fn(2, "nth"),
+                runtime_fn(3, "nth"),
+                runtime_fn(4, "id"),
+                op(5, "add3", NodeType::FunctionNode),
+            ],
+            vec![
+                env(Envs::NumericLit { content: 0 }, 2, 0),
+                env(Envs::NumericLit { content: 2 }, 2, 1),
+                arc(1, 0, 2, 2),
+                env(Envs::NumericLit { content: 1 }, 3, 0),
+                env(Envs::NumericLit { content: 2 }, 3, 1),
+                arc(1, 0, 3, 2),
+                env(Envs::NumericLit { content: 10 }, 4, 0),
+                arc(2, 0, 5, 0),
+                arc(3, 0, 5, 1),
+                arc(4, 0, 5, 2),
+            ],
+            5,
+        );
+        let mut functions = Functions::new();
+        functions
+            .register("crate::pair", || vec![Value::new(1), Value::new(2)])
+            .register("crate::add3", |a: i32, b: i32, c: i32| a * 100 + b * 10 + c);
+
+        let (plain, optimized) = simulate_optimized(data, &functions, &[]);
+        assert_eq!(plain.result.get::<i32>(), Some(&130));
+        assert_eq!(plain.calls.len(), 5);
+        // the nths became a single operator and the id is gone
+        assert_eq!(optimized.calls.keys().collect::<Vec<_>>(), vec![&1, &5]);
+        assert!(optimized.stale.is_empty());
+    }
+
+    #[test]
+    fn recursion() {
+        // recurFun: condition, result, init, loop argument; ctrl, loop formal, finish
+        let data = graph(
+            vec![
+                op(1, "recurFun", NodeType::OperatorNode),
+                op(2, "ctrl", NodeType::OperatorNode),
+                op(3, "double", NodeType::FunctionNode),
+                op(4, "below_100", NodeType::FunctionNode),
+            ],
+            vec![
+                env(Envs::EnvRefLit { content: 0 }, 1, 2),
+                arc(1, 0, 2, 0),
+                arc(1, 1, 2, 1),
+                arc(2, 0, 3, 0),
+                arc(3, 0, 4, 0),
+                arc(4, 0, 1, 0),
+                arc(3, 0, 1, 1),
+                arc(3, 0, 1, 3),
+            ],
+            1,
+        );
+        let mut functions = Functions::new();
+        functions
+            .register("crate::double", |x: i32| x * 2)
+            .register("crate::below_100", |x: i32| x < 100);
+
+        let run = simulate(&data, &functions, &[Value::new(3)]).unwrap();
+        assert_eq!(run.result.get::<i32>(), Some(&192));
+        assert_eq!(run.calls[&3], 6);
+        // nothing is left for the next run, only the ctrl waits for the values of that run
+        assert_eq!(run.stale, vec![((2, 0), 1)]);
+    }
+
+    #[test]
+    fn errors() {
+        let data = fixture("smap/algorithms/smap_test.ohuao");
+        assert_eq!(
+            simulate(&data, &Functions::new(), &[]).unwrap_err(),
+            SimError::UnknownFunction {
+                op: 1,
+                path: "crate::smap::smap_fns::gen_input".into()
+            }
+        );
+
+        let mut functions = Functions::new();
+        functions
+            .register("crate::smap::smap_fns::gen_input", || 42)
+            .register("crate::smap::smap_fns::splice", |s: String| s)
+            .register("crate::smap::smap_fns::printout", |_: String| ());
+        match simulate(&data, &functions, &[]).unwrap_err() {
+            SimError::Failed { op: 2, .. } => (),
+            e => panic!("unexpected error: {}", e),
+        }
+
+        let mut data = data;
+        // `collect` never learns the size of the loop
+        data.graph
+            .arcs
+            .direct
+            .retain(|a| a.target.operator != 5 || a.target.index != 0);
+        functions.register("crate::smap::smap_fns::gen_input", || {
+            Value::list(vec!["a".to_string()])
+        });
+        assert_eq!(
+            simulate(&data, &functions, &[]).unwrap_err(),
+            SimError::Stuck { waiting: vec![5] }
+        );
+    }
+}
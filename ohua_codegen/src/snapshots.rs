@@ -0,0 +1,155 @@
+//! Snapshot tests of the code generated for the algorithms of the `testcases` crate.
+//!
+//! Every algorithm in `testcases/src` has its dataflow graph checked in next to it, as
+//! `<algo>.ohuao` in the format emitted by `ohuac`. The code generated for every such fixture
+//! is formatted and compared with `<algo>.snap`, so that changes to the generated code are
+//! caught without `ohuac` installed and show up in review. The snapshots are generated without
+//! any instrumentation, so they do not depend on the features the tests are run with.
+//!
+//! After an intended change to the code generation, rerun the tests with `UPDATE_SNAPSHOTS=1`
+//! to rewrite the snapshots. A fixture without a snapshot fails the test as well, so that a
+//! snapshot which went missing is not silently recreated; the snapshots of new fixtures are
+//! written by a run with `UPDATE_SNAPSHOTS=1`, too. Fixtures
+//! are (re)generated by building the `testcases` with `OHUA_WRITE_FIXTURES=1` set, which makes
+//! the `#[ohua]` macro keep the output of `ohuac` for every algorithm it compiles. The only
+//! fixtures without an algorithm are the graphs in `HAND_WRITTEN`.
+use crate::alter_ohua_ns_imports;
+use crate::ohua_types::OhuaData;
+use crate::parse::Options;
+use crate::signatures::Signatures;
+use crate::typedgen::generate_code;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn testcases_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("../testcases/src")
+}
+
+/// Graphs that `ohuac` does not emit, written by hand to cover parts of the code generation
+/// that no algorithm reaches.
+const HAND_WRITTEN: [&str; 2] = [
+    // a multi-way branch
+    "conditionals/algorithms/switch_test.ohuao",
+    // a state bound to an argument of the algorithm
+    "general/algorithms/caller_state.ohuao",
+];
+
+/// Collects all files below `dir` with the extension `ext`, sorted by path.
+fn find_files(dir: &Path, ext: &str) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            files.extend(find_files(&path, ext));
+        } else if path.extension().is_some_and(|e| e == ext) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    files
+}
+
+/// Generates the code for the fixture at `path` like `#[ohua]` would, with the arguments of
+/// the algorithm named `arg0`, `arg1`, ...
+fn generate(path: &Path) -> String {
+    let mut data: OhuaData = serde_json::from_str(&fs::read_to_string(path).unwrap())
+        .unwrap_or_else(|e| panic!("Malformed fixture {}: {}", path.display(), e));
+    alter_ohua_ns_imports(&mut data);
+    let sigs = Signatures::from_sources(&data.graph.operators, &testcases_dir());
+
+    let args: Vec<String> = (0..data.mainArity).map(|i| format!("arg{}", i)).collect();
+    let call: syn::ExprCall = syn::parse_str(&format!("algo({})", args.join(", "))).unwrap();
+    let code = generate_code(&mut data, &call.args, &sigs, &Options::default());
+
+    let file: syn2::File = syn2::parse_str(&format!("fn algorithm() {}", code))
+        .unwrap_or_else(|e| panic!("Invalid code generated for {}: {}", path.display(), e));
+    prettyplease::unparse(&file)
+}
+
+/// Describes the first line in which `actual` deviates from `expected`.
+fn first_difference(expected: &str, actual: &str) -> String {
+    let mut expected_lines = expected.lines();
+    let mut actual_lines = actual.lines();
+    let mut line = 1;
+    loop {
+        match (expected_lines.next(), actual_lines.next()) {
+            (Some(e), Some(a)) if e == a => line += 1,
+            (e, a) => {
+                return format!(
+                    "line {}:\n  expected: {}\n  actual:   {}",
+                    line,
+                    e.unwrap_or("<end of snapshot>"),
+                    a.unwrap_or("<end of generated code>")
+                )
+            }
+        }
+    }
+}
+
+#[test]
+fn generated_code_matches_snapshots() {
+    let update = env::var_os("UPDATE_SNAPSHOTS").is_some();
+
+    let fixtures = find_files(&testcases_dir(), "ohuao");
+    assert!(!fixtures.is_empty(), "No `.ohuao` fixtures found.");
+
+    let mut changed = Vec::new();
+    for fixture in fixtures {
+        let code = generate(&fixture);
+        let snapshot = fixture.with_extension("snap");
+        match fs::read_to_string(&snapshot) {
+            Ok(ref expected) if *expected == code => (),
+            _ if update => fs::write(&snapshot, code).unwrap(),
+            Ok(ref expected) => changed.push(format!(
+                "{}, {}",
+                snapshot.display(),
+                first_difference(expected, &code)
+            )),
+            Err(_) => changed.push(format!("{}, missing", snapshot.display())),
+        }
+    }
+
+    assert!(
+        changed.is_empty(),
+        "The generated code differs from the snapshots. Rerun with `UPDATE_SNAPSHOTS=1` if the change is intended.\n{}",
+        changed.join("\n")
+    );
+}
+
+#[test]
+fn every_algorithm_has_a_fixture() {
+    let dir = testcases_dir();
+    let algorithms = find_files(&dir, "ohuac");
+    let fixtures = find_files(&dir, "ohuao");
+
+    let missing: Vec<String> = algorithms
+        .iter()
+        .filter(|algo| !algo.with_extension("ohuao").exists())
+        .map(|algo| algo.display().to_string())
+        .collect();
+    assert!(
+        missing.is_empty(),
+        "No fixture for the algorithms:\n{}",
+        missing.join("\n")
+    );
+
+    let hand_written: Vec<PathBuf> = HAND_WRITTEN.iter().map(|f| dir.join(f)).collect();
+    let unknown: Vec<String> = fixtures
+        .iter()
+        .filter(|f| !f.with_extension("ohuac").exists() && !hand_written.contains(f))
+        .map(|f| f.display().to_string())
+        .collect();
+    assert!(
+        unknown.is_empty(),
+        "Fixtures without an algorithm, list them in `HAND_WRITTEN` if `ohuac` cannot emit them:\n{}",
+        unknown.join("\n")
+    );
+    for fixture in hand_written {
+        assert!(
+            fixtures.contains(&fixture),
+            "{} is missing",
+            fixture.display()
+        );
+    }
+}
@@ -1,9 +1,10 @@
 #![allow(unused_doc_comments)]
 use crate::backend_optimizations::run_backend_optimizations;
-use crate::lang::{generate_ctrl_operator, generate_nth};
 use crate::ohua_types::ArcSource::{Env, Local};
 use crate::ohua_types::Envs::*;
 use crate::ohua_types::*;
+use crate::parse::{Cancellation, Mode, Options};
+use crate::signatures::Signatures;
 
 use std::collections::BTreeSet;
 
@@ -18,6 +19,26 @@ fn is_runtime_op(op: &Operator) -> bool {
     op.operatorType.qbNamespace == OHUA_RUNTIME_NAMESPACE
 }
 
+fn is_tuple(op: &Operator) -> bool {
+    is_runtime_op(op) && op.operatorType.qbName == "tuple"
+}
+
+/// An algorithm returning `ohua::lang::tuple(a, b, ...)` gets one result arc per component
+/// instead of a function call that builds the tuple. Tuples used inside the algorithm are
+/// built by `ohua_runtime::lang::tuple`.
+fn is_output_tuple(op: &Operator, graph: &DFGraph) -> bool {
+    is_tuple(op) && op.operatorId == graph.return_arc.operator
+}
+
+/// The number of values returned by an algorithm that ends in `ohua::lang::tuple(a, b, ...)`.
+pub fn count_outputs(graph: &DFGraph) -> Option<usize> {
+    graph
+        .operators
+        .iter()
+        .find(|op| is_output_tuple(op, graph))
+        .map(|op| get_in_arcs(&op.operatorId, &graph.arcs.direct).len())
+}
+
 fn get_op_id(val: &ArcSource) -> &i32 {
     match val {
         ArcSource::Env(e) => match e {
@@ -28,12 +49,6 @@ fn get_op_id(val: &ArcSource) -> &i32 {
     }
 }
 
-fn get_num_inputs(op: &i32, arcs: &Vec<DirectArc>) -> usize {
-    arcs.iter()
-        .filter(|arc| &(arc.target.operator) == op)
-        .count()
-}
-
 // TODO: Is this still needed?
 #[allow(dead_code)]
 fn get_num_outputs(op: &i32, arcs: &Vec<DirectArc>) -> usize {
@@ -144,12 +159,12 @@ fn generate_out_arc_var(arc: &DirectArc, ops: &Vec<Operator>) -> Ident {
 
 fn generate_var_for_in_arc(op: &i32, idx: &i32) -> Ident {
     assert!(idx > &-2);
-    // let index = match idx {
-    //     -1 => "ctrl".to_string(),
-    //     _ => idx.to_string(),
-    // };
+    let index = match idx {
+        -1 => "ctrl".to_string(),
+        _ => idx.to_string(),
+    };
     Ident::new(
-        &format!("sf_{}_in_{}", op.to_string(), idx),
+        &format!("sf_{}_in_{}", op.to_string(), index),
         Span::call_site(),
     )
 }
@@ -357,10 +372,37 @@ fn generate_pair_arc_var<'a>(arc: &'a DirectArc) -> Ident {
     }
 }
 
-pub fn generate_arcs(compiled: &OhuaData) -> TokenStream {
+/// Determines whether the output port `port` can share its values among its arcs instead of
+/// cloning them. This is the case when the port belongs to a stateful function, serves more
+/// than one arc and every consumer is a stateful function that only borrows the value.
+fn is_shared_port(port: &ArcIdentifier, compiled: &OhuaData, sigs: &Signatures) -> bool {
+    let ops = &compiled.graph.operators;
+    let is_function = |id: &i32| {
+        ops.iter()
+            .any(|o| &o.operatorId == id && o.nodeType == NodeType::FunctionNode)
+    };
+
+    let consumers: Vec<&DirectArc> = get_out_arcs(&port.operator, &compiled.graph.arcs.direct)
+        .drain(..)
+        .filter(|arc| get_out_index_from_source(&arc.source) == &port.index)
+        .collect();
+
+    is_function(&port.operator)
+        && consumers.len() > 1
+        && consumers.iter().all(|arc| {
+            ops.iter().any(|o| {
+                o.operatorId == arc.target.operator
+                    && o.nodeType == NodeType::FunctionNode
+                    && sigs.borrows(&o.operatorType, arc.target.index)
+            })
+        })
+}
+
+pub fn generate_arcs(compiled: &OhuaData, sigs: &Signatures, options: &Options) -> TokenStream {
     let mut arcs: Vec<DirectArc> = compiled.graph.arcs.direct.clone();
 
     arcs.retain(filter_env_arc);
+    let cancellable = options.cancellation.is_requested();
 
     // separate normal arcs and dead arcs
     let (normal_arcs, dead_ends): (Vec<DirectArc>, Vec<DirectArc>) =
@@ -389,6 +431,15 @@ pub fn generate_arcs(compiled: &OhuaData) -> TokenStream {
         .iter()
         .map(|pair| generate_pair_arc_var(pair[0]))
         .collect();
+    let pair_queues: Vec<Ident> = index_pairs
+        .iter()
+        .map(|pair| match pair[0].source {
+            Local(ref src) if is_shared_port(src, compiled, sigs) => {
+                Ident::new("SharedDispatchQueue", Span::call_site())
+            }
+            _ => Ident::new("DispatchQueue", Span::call_site()),
+        })
+        .collect();
     let pair_args: Vec<Vec<Ident>> = index_pairs
         .iter()
         .map(|pair| {
@@ -398,25 +449,72 @@ pub fn generate_arcs(compiled: &OhuaData) -> TokenStream {
         })
         .collect();
 
-    let state_outs = compiled
+    // state from the environment is bound in `generate_sfns`
+    let local_states: Vec<&StateArc> = compiled
         .graph
         .arcs
         .state
+        .iter()
+        .filter(|arc| match arc.source {
+            Local(_) => true,
+            Env(_) => false,
+        })
+        .collect();
+    let state_outs = local_states
         .iter()
         .map(|arc| generate_send_var_for_state_arc(&arc, &(compiled.graph.operators)));
-    let state_ins = compiled
-        .graph
-        .arcs
-        .state
+    let state_ins = local_states
         .iter()
         .map(|arc| generate_recv_var_for_state_arc(&(arc.target)));
 
+    let outs: Vec<Ident> = outs.collect();
+    let state_outs: Vec<Ident> = state_outs.collect();
+    let channels: Vec<TokenStream> = outs
+        .iter()
+        .map(|out| generate_channel(options, out, cancellable, options.record.is_some()))
+        .collect();
+    // state is not passed between functions and hence not recorded
+    let state_channels: Vec<TokenStream> = state_outs
+        .iter()
+        .map(|out| generate_channel(options, out, cancellable, false))
+        .collect();
+
     quote! {
-        #(let (#outs, #ins) = std::sync::mpsc::channel();)*
+        #(let (#outs, #ins) = #channels;)*
         #(let #dead_outs = DeadEndArc::default();)*
-        #(let (#state_outs, #state_ins) = std::sync::mpsc::channel();)*
-        #(let #pair_ins = DispatchQueue::new(vec![#(#pair_args,)*]);)*
+        #(let (#state_outs, #state_ins) = #state_channels;)*
+        #(let #pair_ins = #pair_queues::new(vec![#(#pair_args,)*]);)*
+    }
+}
+
+/// Generates the channel for the arc whose sending end is `out`. Metered channels are named
+/// after `out`, which is what they are listed as among the arcs of the algorithm in the
+/// `metrics_report`, and watched like the channels of `options.watchdog`.
+/// Cancellable channels stop as soon as the `cancel_token` of the run is cancelled. Recorded
+/// channels write the values sent via them to the `recorder` of the run.
+fn generate_channel(
+    options: &Options,
+    out: &Ident,
+    cancellable: bool,
+    recorded: bool,
+) -> TokenStream {
+    let name = out.to_string();
+    let mut channel = if options.metrics {
+        let algorithm = &options.algorithm;
+        quote! { ohua_runtime::arcs::metered_channel(#algorithm, #name) }
+    } else if options.watchdog {
+        quote! { ohua_runtime::arcs::watched_channel(#name) }
+    } else {
+        quote! { ohua_runtime::arcs::named_channel(#name) }
+    };
+
+    if cancellable {
+        channel = quote! { ohua_runtime::cancel::cancellable(&cancel_token, #channel) };
     }
+    if recorded {
+        channel = quote! { ohua_runtime::record::recorded(&recorder, #name, #channel) };
+    }
+    channel
 }
 
 fn get_call_reference(op_type: &OperatorType) -> Ident {
@@ -431,20 +529,83 @@ fn get_call_reference(op_type: &OperatorType) -> Ident {
 // The main point is this: I intended to replace/extend the `loop` construct with something that
 // interfaces with a scheduler. Let's see if I can get rid of this and accomplish the same just
 // via the arcs.
-fn generate_operator_code(op_name: Ident, call_args: Vec<TokenStream>) -> TokenStream {
+/// Generates the code calling the runtime operator `op_name`, within one `Call` span per
+/// invocation if `trace` is given.
+fn generate_operator_code(
+    op_name: Ident,
+    call_args: Vec<TokenStream>,
+    trace: Option<TracePoint>,
+) -> TokenStream {
     let name_str = op_name.to_string();
-    if name_str.starts_with("ctrl_") || name_str.starts_with("recur_") {
-        quote! { #op_name(#(&#call_args),*)?; Ok(()) }
+    let call = quote! { #op_name(#(&#call_args),*)?; };
+    let call = match trace {
+        Some(point) => point.span(call),
+        None => call,
+    };
+    // `recur_N` handles a single run of the recursion, so it is called again for every run
+    if name_str == "ctrl"
+        || name_str == "fold"
+        || name_str == "select_drain"
+        || name_str == "feed_args"
+        || name_str == "destruct"
+    {
+        quote! { #call Ok(()) }
     } else {
         quote! {
             loop{
-                #op_name(#(&#call_args),*)?;
+                #call
             }
         }
     }
 }
 
-pub fn generate_ops(compiled: &OhuaData) -> TokenStream {
+/// `switchFun` and `select_n` serve an arbitrary number of arms, so the arcs of all arms
+/// are handed over as a single slice instead of one argument per arc. Likewise, the inputs
+/// and outputs of a `ctrl` are bundled into one tuple each, as are the outputs of `destruct`.
+///
+/// `ohuac` does not emit multi-way branches, they only appear in hand-written graphs.
+fn bundle_arm_args(op: &Operator, mut call_args: Vec<TokenStream>) -> Vec<TokenStream> {
+    if !is_runtime_op(op) {
+        return call_args;
+    }
+
+    match op.operatorType.qbName.as_str() {
+        "switchFun" => {
+            let arms = call_args.split_off(1);
+            call_args.push(quote! { [#(&#arms as &dyn ArcInput<CtrlToken>),*] });
+        }
+        "select_n" => {
+            let out = call_args.split_off(call_args.len() - 1);
+            let arms = call_args.split_off(1);
+            call_args.push(quote! { [#(&#arms as &dyn ArcOutput<_>),*] });
+            call_args.extend(out);
+        }
+        "ctrl" => {
+            let num_vars = (call_args.len() - 1) / 2;
+            assert!(
+                num_vars <= 12,
+                "Branches and loops passing more than 12 variables to their body are not supported."
+            );
+            let vars_out = call_args.split_off(1 + num_vars);
+            let vars_in = call_args.split_off(1);
+            call_args.push(quote! { (#(#vars_in,)*) });
+            call_args.push(quote! { (#(#vars_out,)*) });
+        }
+        "destruct" => {
+            let outs = call_args.split_off(1);
+            call_args.push(quote! { (#(#outs,)*) });
+        }
+        "feed_args" => {
+            let vars_out = call_args.split_off(1);
+            call_args.insert(0, quote! { instance_args });
+            call_args.push(quote! { (#(#vars_out,)*) });
+        }
+        _ => (),
+    }
+    call_args
+}
+
+pub fn generate_ops(compiled: &OhuaData, options: &Options) -> TokenStream {
     let ops = compiled.graph.operators.iter().filter(|o| {
         (match o.nodeType {
             NodeType::OperatorNode => true,
@@ -481,18 +642,29 @@ pub fn generate_ops(compiled: &OhuaData) -> TokenStream {
                 call_args.push(quote! { result_snd });
             }
 
+            let call_args = bundle_arm_args(op, call_args);
             let op_name = get_call_reference(&op.operatorType);
 
-            if call_args.len() > 0 {
-                generate_operator_code(op_name, call_args)
+            let trace = if options.trace {
+                Some(TracePoint::new(op))
             } else {
-                quote! { #op_name() }
-            }
+                None
+            };
+            let op_code = if call_args.len() > 0 {
+                generate_operator_code(op_name, call_args, trace)
+            } else {
+                let call = quote! { #op_name() };
+                match trace {
+                    Some(point) => point.span(call),
+                    None => call,
+                }
+            };
+            generate_task(op, op_code)
         })
         .collect();
 
     quote! {
-        #(tasks.push(Box::new(move || { #op_codes })); )*
+        #(tasks.push(#op_codes); )*
     }
 }
 
@@ -503,65 +675,189 @@ fn filter_env_arc(arc: &DirectArc) -> bool {
     }
 }
 
+/// Wraps the code for a single call into a loop that runs the call as often as
+/// requested by the control tokens arriving at `gate`.
+fn generate_gated_call(gate: &Ident, sfn_code: TokenStream) -> TokenStream {
+    quote! {
+        loop {
+            for _ in 0..CtrlToken::count(&#gate.recv()?) {
+                #sfn_code
+            }
+        }
+    }
+}
+
+/// How a stateful function gets hold of its state.
+enum StateBinding {
+    /// State arriving via a state arc lives for a single run of the algorithm. It is received
+    /// once by the statement bound here, which binds it to `state`.
+    Received(TokenStream),
+    /// State handed in by the caller as an `Arc<Mutex<_>>` outlives the run. It is locked for
+    /// every call separately, only once the arguments of the call have arrived, so algorithms
+    /// sharing the state only wait for each other while the function actually runs.
+    Locked(Ident),
+}
+
+fn generate_state_binding(arc: &StateArc) -> StateBinding {
+    let state_var = generate_recv_var_for_state_arc(&arc.target);
+    match arc.source {
+        Env(_) => StateBinding::Locked(state_var),
+        Local(_) => StateBinding::Received(quote! { let state = #state_var.recv()?; }),
+    }
+}
+
+/// Variables to bind the arguments of a call to, see `generate_sfn_call_code`.
+fn arg_vars(num_args: usize) -> Vec<Ident> {
+    (0..num_args)
+        .map(|i| Ident::new(&format!("a{}", i), Span::call_site()))
+        .collect()
+}
+
+/// Turns the code of `op` into a task, panics of the task are attributed to `op`.
+fn generate_task(op: &Operator, code: TokenStream) -> TokenStream {
+    let (id, name) = (op.operatorId, &op.operatorType.qbName);
+    quote! { ohua_runtime::task(#id, #name, move || { #code }) }
+}
+
+/// Shadows the function `op_type` with the function registered for it in `ohua_runtime::mock`,
+/// which is the function itself unless a test registered a mock. `params` are the parameter
+/// types of the function, `_` or `&_`, so that it can be coerced to a function pointer.
+fn generate_mock_binding(op_type: &OperatorType, params: Vec<TokenStream>) -> TokenStream {
+    let sf = get_call_reference(op_type);
+    let path = op_type
+        .qbNamespace
+        .iter()
+        .chain(Some(&op_type.qbName))
+        .cloned()
+        .collect::<Vec<String>>()
+        .join("::");
+    quote! {
+        let #sf: fn(#(#params),*) -> _ = #sf;
+        let #sf = ohua_runtime::mock::resolve(#path, #sf);
+    }
+}
+
+/// Identifies an operator in the events emitted by traced code.
+struct TracePoint {
+    op_id: i32,
+    op_name: String,
+}
+
+impl TracePoint {
+    fn new(op: &Operator) -> TracePoint {
+        TracePoint {
+            op_id: op.operatorId,
+            op_name: op.operatorType.qbName.clone(),
+        }
+    }
+
+    /// Reports the value produced by `recv` as arrived at input `port` from output `source`.
+    fn input(&self, port: i32, source: &ArcIdentifier, recv: TokenStream) -> TokenStream {
+        let (id, name) = (self.op_id, &self.op_name);
+        let (src_op, src_port) = (source.operator, source.index);
+        quote! { ohua_runtime::trace::input(#id, #name, #port, (#src_op, #src_port), #recv) }
+    }
+
+    /// Runs `code` within a `Call` span.
+    fn span(&self, code: TokenStream) -> TokenStream {
+        let (id, name) = (self.op_id, &self.op_name);
+        quote! {
+            let _call = ohua_runtime::trace::Call::start(#id, #name);
+            #code
+        }
+    }
+
+    /// Evaluates the arguments before the `Call` span starts, so that waiting for inputs
+    /// is not accounted to the invocation. The arguments are bound in a `match` to keep
+    /// borrowed temporaries alive for the call.
+    fn call(
+        &self,
+        r: &Ident,
+        callee: TokenStream,
+        call_args: Vec<TokenStream>,
+        send: TokenStream,
+        out_ports: &[i32],
+    ) -> TokenStream {
+        let (id, name) = (self.op_id, &self.op_name);
+        let vars = &arg_vars(call_args.len());
+        let span = self.span(quote! { #callee(#(#vars),*) });
+        let ids: Vec<i32> = out_ports.iter().map(|_| id).collect();
+        let names: Vec<&String> = out_ports.iter().map(|_| name).collect();
+        quote! {
+            let #r = match (#(#call_args,)*) {
+                (#(#vars,)*) => { #span }
+            };
+            #send;
+            #(ohua_runtime::trace::output(#ids, #names, #out_ports);)*
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn generate_sfn_call_code(
-    op: &i32,
     sf: Ident,
     call_args: Vec<TokenStream>,
     r: Ident,
     send: TokenStream,
     num_input_arcs: usize,
-    state_arcs: &Vec<StateArc>,
+    state_binding: Option<StateBinding>,
+    gate: Option<Ident>,
+    trace: Option<(TracePoint, Vec<i32>)>,
 ) -> TokenStream {
-    let is_sfn = match state_arcs.iter().find(|arc| &arc.target == op) {
-        Some(_) => true,
-        None => false,
+    let callee = match state_binding {
+        Some(StateBinding::Received(_)) => quote! { state.#sf },
+        Some(StateBinding::Locked(ref state_var)) => quote! { #state_var.lock()?.#sf },
+        None => quote! { #sf },
     };
-    let call_code = quote! {#sf( #(#call_args),* )};
-    if is_sfn {
-        let state_chan = generate_recv_var_for_state_arc(&op);
-        let sfn_code = quote! {
-            let #r = state.#call_code;
-            #send
-        };
-
-        if num_input_arcs > 0 {
-            // global state goes along the lines of:
+    let sfn_code = match trace {
+        Some((point, out_ports)) => point.call(&r, callee, call_args, send, &out_ports),
+        // the arguments are received before the lock is taken, which is held for the call only
+        None if matches!(state_binding, Some(StateBinding::Locked(_))) => {
+            let vars = &arg_vars(call_args.len());
             quote! {
-                let state = #state_chan.recv()?;
-                loop {
-                   #sfn_code
-                }
+                let #r = match (#(#call_args,)*) {
+                    (#(#vars,)*) => #callee(#(#vars),*),
+                };
+                #send
             }
-        } else {
-            quote! { #sfn_code; Ok(()) }
         }
-    } else {
-        let sfn_code = quote! {
-            let #r = #call_code;
+        None => quote! {
+            let #r = #callee( #(#call_args),* );
             #send
-        };
-        if num_input_arcs > 0 {
-            quote! {
-                loop {
-                    #sfn_code
-                }
+        },
+    };
+
+    let sfn_code = if let Some(gate) = gate {
+        generate_gated_call(&gate, sfn_code)
+    } else if num_input_arcs > 0 {
+        quote! {
+            loop {
+                #sfn_code
             }
-        } else {
-            quote! { #sfn_code; Ok(()) }
         }
+    } else {
+        quote! { #sfn_code; Ok(()) }
+    };
+
+    // global state goes along the lines of:
+    match state_binding {
+        Some(StateBinding::Received(state_binding)) => quote! { #state_binding #sfn_code },
+        _ => sfn_code,
     }
 }
 
 pub fn generate_sfns(
     compiled: &OhuaData,
     algo_call_args: &Punctuated<Expr, Token![,]>,
+    sigs: &Signatures,
+    options: &Options,
 ) -> TokenStream {
     let sfns = compiled
         .graph
         .operators
         .iter()
         .filter(|&o| match o.nodeType {
-            NodeType::FunctionNode => true,
+            NodeType::FunctionNode => !is_output_tuple(o, &compiled.graph),
             _ => false,
         });
     let sf_codes: Vec<TokenStream> = sfns
@@ -572,7 +868,14 @@ pub fn generate_sfns(
                 &(compiled.graph.arcs.direct),
                 algo_call_args,
             );
-            let orig_in_arcs = get_in_arcs(&(op.operatorId), &(compiled.graph.arcs.direct));
+            let (gate_arcs, mut orig_in_arcs): (Vec<&DirectArc>, Vec<&DirectArc>) =
+                get_in_arcs(&(op.operatorId), &(compiled.graph.arcs.direct))
+                    .drain(..)
+                    .partition(|arc| arc.target.index == -1);
+            orig_in_arcs.sort_by_key(|a| a.target.index);
+            let gate = gate_arcs
+                .first()
+                .map(|arc| generate_var_for_in_arc(&arc.target.operator, &arc.target.index));
             let zipped_in_arcs: Vec<(&&DirectArc, TokenStream)> =
                 orig_in_arcs.iter().zip(in_arcs.drain(..)).collect();
 
@@ -614,10 +917,21 @@ pub fn generate_sfns(
                 &(compiled.graph.arcs.direct),
                 &(compiled.graph.operators),
             );
-            let out_arcs = tmp_out_arcs_vec
+            let mut out_ports: Vec<i32> = tmp_out_arcs_vec
+                .iter()
+                .map(|(arc, _)| *get_out_index_from_source(&arc.source))
+                .collect();
+            if out_ports.is_empty() && op.operatorId == compiled.graph.return_arc.operator {
+                // the result is dispatched via the first output
+                out_ports.push(0);
+            }
+            let out_arcs: Vec<(Ident, bool)> = tmp_out_arcs_vec
                 .drain(..)
-                .unzip::<&DirectArc, Ident, Vec<&DirectArc>, Vec<Ident>>()
-                .1;
+                .map(|(arc, ident)| match arc.source {
+                    Local(ref src) => (ident, is_shared_port(src, compiled, sigs)),
+                    _ => (ident, false),
+                })
+                .collect();
 
             let sf = get_call_reference(&op.operatorType);
             // let arcs = in_arcs.clone(); // can't reuse var in quote!
@@ -637,33 +951,126 @@ pub fn generate_sfns(
             let num_input_arcs = drain_arcs.len();
             // let drain_inputs = quote!{ #(#drain_arcs.recv()?;)* };
 
-            let call_args: Vec<TokenStream> = zipped_in_arcs
+            let trace_point = TracePoint::new(op);
+            let mut call_args: Vec<TokenStream> = zipped_in_arcs
                 .iter()
-                .map(|(orig_arc, code)| match orig_arc.source {
-                    Env(_) => code.clone().clone(),
-                    Local(_) => quote! { #code.recv()? },
+                .map(|(orig_arc, code)| {
+                    let borrowed = sigs.borrows(&op.operatorType, orig_arc.target.index);
+                    let recv = match orig_arc.source {
+                        Local(ref src) if options.trace => {
+                            trace_point.input(orig_arc.target.index, src, quote! { #code.recv()? })
+                        }
+                        _ => quote! { #code.recv()? },
+                    };
+                    match orig_arc.source {
+                        Env(_) if borrowed => quote! { &#code },
+                        Env(_) => code.clone().clone(),
+                        Local(ref src) if is_shared_port(src, compiled, sigs) => {
+                            quote! { &*#recv }
+                        }
+                        Local(_) if borrowed => quote! { &#recv },
+                        Local(_) => recv,
+                    }
                 })
                 .collect();
 
-            generate_sfn_call_code(
-                &op.operatorId,
+            if is_tuple(op) {
+                // `tuple` takes all components as a single argument
+                call_args = vec![quote! { (#(#call_args,)*) }];
+            }
+
+            let state_binding = compiled
+                .graph
+                .arcs
+                .state
+                .iter()
+                .find(|arc| arc.target == op.operatorId)
+                .map(generate_state_binding);
+
+            // methods of state objects and functions of the runtime are never mocked
+            let mock_binding = if options.mock && state_binding.is_none() && !is_runtime_op(op) {
+                let params: Vec<TokenStream> = zipped_in_arcs
+                    .iter()
+                    .map(|(orig_arc, _)| {
+                        let shared = match orig_arc.source {
+                            Local(ref src) => is_shared_port(src, compiled, sigs),
+                            Env(_) => false,
+                        };
+                        if shared || sigs.borrows(&op.operatorType, orig_arc.target.index) {
+                            quote! { &_ }
+                        } else {
+                            quote! { _ }
+                        }
+                    })
+                    .collect();
+                Some(generate_mock_binding(&op.operatorType, params))
+            } else {
+                None
+            };
+
+            // a failing function stops its operator, after its call has been traced
+            let send = if sigs.fails(&op.operatorType) {
+                quote! { let #r = #r?; #send }
+            } else {
+                send
+            };
+
+            let trace = if options.trace {
+                Some((trace_point, out_ports))
+            } else {
+                None
+            };
+
+            let sf_code = generate_sfn_call_code(
                 sf,
                 call_args,
                 r,
                 send,
                 num_input_arcs,
-                &compiled.graph.arcs.state,
-            )
+                state_binding,
+                gate,
+                trace,
+            );
+            let task = generate_task(op, sf_code);
+            match mock_binding {
+                Some(mock_binding) => quote! { { #mock_binding #task } },
+                None => task,
+            }
         })
         .collect();
 
+    // state handed in by the caller is evaluated outside of the tasks, so that the caller
+    // can keep a handle to it, e.g., by passing `Arc::clone(&state)`
+    let (persistent_states, persistent_exprs): (Vec<Ident>, Vec<&Expr>) = compiled
+        .graph
+        .arcs
+        .state
+        .iter()
+        .filter_map(|arc| match arc.source {
+            Env(EnvRefLit { content: i }) => Some((
+                generate_recv_var_for_state_arc(&arc.target),
+                algo_call_args.iter().nth(i as usize).unwrap_or_else(|| {
+                    panic!(
+                        "Invariant broken! State arg idx: {}, Algo call args length: {}",
+                        i,
+                        algo_call_args.len()
+                    )
+                }),
+            )),
+            _ => None,
+        })
+        .unzip();
+
     quote! {
+        #(let #persistent_states = #persistent_exprs;)*
         let mut tasks: Vec<Box<dyn FnOnce() -> Result<(), RunError> + Send + 'static>> = Vec::new();
-        #(tasks.push(Box::new(move || { #sf_codes })); )*
+        #(tasks.push(#sf_codes); )*
     }
 }
 
-fn generate_send(r: &Ident, outputs: &Vec<Ident>, op: &i32, final_op: &i32) -> TokenStream {
+/// Each output is paired with a flag marking whether it is a `SharedDispatchQueue`, which
+/// expects the value wrapped in an `Arc`.
+fn generate_send(r: &Ident, outputs: &Vec<(Ident, bool)>, op: &i32, final_op: &i32) -> TokenStream {
     // option 1: we could borrow here and then it would fail if somebody tries to write to val. (pass-by-ref)
     // option 2: clone (pass-by-val)
     // this is something that our knowledge base could be useful for: check if any of the predecessor.
@@ -678,13 +1085,43 @@ fn generate_send(r: &Ident, outputs: &Vec<Ident>, op: &i32, final_op: &i32) -> T
             }
         }
         1 => {
-            let o = &outputs[0];
-            quote! { #o.dispatch(#r)? }
+            let (o, shared) = &outputs[0];
+            if *shared {
+                quote! { #o.dispatch(std::sync::Arc::new(#r))? }
+            } else {
+                quote! { #o.dispatch(#r)? }
+            }
         }
         _ => {
-            let results: Vec<Ident> = outputs.iter().map(|_| r.clone()).collect();
-            quote! {
-                #(#outputs.dispatch(#results.clone())?);*;
+            let ports = |shared: bool| -> Vec<&Ident> {
+                outputs
+                    .iter()
+                    .filter(|(_, s)| *s == shared)
+                    .map(|(o, _)| o)
+                    .collect()
+            };
+            let (shared, owned) = (ports(true), ports(false));
+            if owned.is_empty() {
+                let results: Vec<Ident> = shared.iter().map(|_| r.clone()).collect();
+                quote! {
+                    let #r = std::sync::Arc::new(#r);
+                    #(#shared.dispatch(std::sync::Arc::clone(&#results))?);*;
+                }
+            } else {
+                // the shared ports get a single copy wrapped in an `Arc`, the others a copy each
+                let shared_r = Ident::new(&format!("{}_shared", r), Span::call_site());
+                let shared_results: Vec<Ident> = shared.iter().map(|_| shared_r.clone()).collect();
+                let share = if shared.is_empty() {
+                    quote! {}
+                } else {
+                    quote! { let #shared_r = std::sync::Arc::new(#r.clone()); }
+                };
+                let results: Vec<Ident> = owned.iter().map(|_| r.clone()).collect();
+                quote! {
+                    #share
+                    #(#shared.dispatch(std::sync::Arc::clone(&#shared_results))?;)*
+                    #(#owned.dispatch(#results.clone())?);*;
+                }
             }
         }
     }
@@ -724,11 +1161,16 @@ fn generate_app_namespaces(operators: &Vec<OperatorType>) -> Vec<TokenStream> {
         .collect()
 }
 
-fn generate_imports(operators: &Vec<Operator>, arcs: &Vec<DirectArc>) -> TokenStream {
-    let op_types = operators.iter().map(|op| op.operatorType.clone()).collect();
+fn generate_imports(graph: &DFGraph) -> TokenStream {
+    let op_types = graph
+        .operators
+        .iter()
+        .filter(|op| !is_output_tuple(op, graph))
+        .map(|op| op.operatorType.clone())
+        .collect();
     let app_namespaces = generate_app_namespaces(&op_types);
 
-    let mut arcs1 = arcs.clone();
+    let mut arcs1 = graph.arcs.direct.clone();
 
     let fn_lit_types = arcs1
         .drain(..)
@@ -754,52 +1196,13 @@ fn generate_imports(operators: &Vec<Operator>, arcs: &Vec<DirectArc>) -> TokenSt
         use ohua_runtime::*;
         use ohua_runtime::arcs::*;
 
-        use ohua_runtime::lang::{send_once, Unit};
+        use ohua_runtime::lang::{send_once, CtrlToken, Unit};
 
         #(#app_namespaces)*
         #(#fn_lit_namespaces)*
     }
 }
 
-// TODO: generalize this
-fn generate_ctrls(compiled_algo: &mut OhuaData) -> TokenStream {
-    let mut num_args: Vec<usize> = compiled_algo
-        .graph
-        .operators
-        .iter()
-        .filter(|op| is_runtime_op(op) && op.operatorType.qbName.as_str() == "ctrl")
-        .map(|op| {
-            let num_args = get_num_inputs(&op.operatorId, &compiled_algo.graph.arcs.direct);
-            num_args - 1
-        })
-        .collect();
-    num_args.sort();
-    num_args.dedup();
-
-    let code: Vec<TokenStream> = num_args
-        .drain(..)
-        .map(|num| generate_ctrl_operator(num))
-        .collect();
-
-    let mut ops: Vec<Operator> = compiled_algo.graph.operators.drain(..).collect();
-
-    compiled_algo.graph.operators = ops
-        .drain(..)
-        .map(|mut op| {
-            if is_runtime_op(&op) && op.operatorType.qbName.as_str() == "ctrl" {
-                let num_args = get_num_inputs(&op.operatorId, &compiled_algo.graph.arcs.direct);
-                op.operatorType.qbNamespace = vec![];
-                op.operatorType.qbName = format!("ctrl_{}", num_args - 1);
-            }
-            op
-        })
-        .collect();
-
-    quote! {
-        #(#code)*
-    }
-}
-
 fn find_nth_info(op_id: &i32, direct_arcs: &Vec<DirectArc>) -> (i32, i32) {
     let mut in_arcs = get_in_arcs(op_id, direct_arcs);
     assert!(in_arcs.len() == 3);
@@ -831,7 +1234,9 @@ fn is_nth(op_id: &i32, ops: &Vec<Operator>) -> bool {
     }
 }
 
-fn generate_nths(compiled_algo: &mut OhuaData) -> TokenStream {
+/// Turns every `nth` into a call to the matching destructuring function of the runtime,
+/// dropping the literal index and length arguments.
+fn prepare_nths(compiled_algo: &mut OhuaData) {
     let mut nths: Vec<(i32, i32, i32)> = compiled_algo
         .graph
         .operators
@@ -845,11 +1250,6 @@ fn generate_nths(compiled_algo: &mut OhuaData) -> TokenStream {
     nths.sort_unstable();
     nths.dedup();
 
-    let code: Vec<TokenStream> = nths
-        .iter()
-        .map(|(op, num, len)| generate_nth(op, num, len))
-        .collect();
-
     let mut direct_arcs: Vec<DirectArc> = compiled_algo.graph.arcs.direct.drain(..).collect();
     compiled_algo.graph.arcs.direct = direct_arcs
         .drain(..)
@@ -884,19 +1284,18 @@ fn generate_nths(compiled_algo: &mut OhuaData) -> TokenStream {
         .drain(..)
         .map(|mut op| {
             match nths.iter().find(|(id, _, _)| id == &op.operatorId) {
-                Some((op_id, idx, total)) => {
-                    op.operatorType.qbNamespace = vec![];
-                    op.operatorType.qbName = format!("nth_op{}_{}_{}", op_id, idx, total);
+                Some((_, idx, total)) => {
+                    assert!(
+                        idx < total && *total <= 12,
+                        "Destructuring of tuples with more than 12 elements is not supported."
+                    );
+                    op.operatorType.qbName = format!("nth_{}", idx);
                 }
                 None => (),
             }
             op
         })
         .collect();
-
-    quote! {
-        #(#code)*
-    }
 }
 
 mod generate_recur {
@@ -1047,60 +1446,341 @@ fn handle_environment_arcs(compiled_algo: &mut OhuaData) {
     }
 }
 
-pub fn generate_code(
-    compiled_algo: &mut OhuaData,
-    algo_call_args: &Punctuated<Expr, Token![,]>,
-) -> TokenStream {
-    run_backend_optimizations(compiled_algo);
-
-    handle_environment_arcs(compiled_algo);
-    let ctrl_code = generate_ctrls(compiled_algo);
-    let nth_code = generate_nths(compiled_algo);
-    //print!("{:?}", compiled_algo.graph.operators);
-    let recur_code = generate_recur::generate(compiled_algo);
-    // handle_environment_arcs(compiled_algo);
-    let header_code = generate_imports(
-        &compiled_algo.graph.operators,
-        &compiled_algo.graph.arcs.direct,
-    );
-    let arc_code = generate_arcs(&compiled_algo);
-    let sf_code = generate_sfns(&compiled_algo, algo_call_args);
-    let op_code = generate_ops(&compiled_algo);
-
-    // Macro hygiene: I can create a variable here and use it throughout the whole call-site of this
-    // macro because quote! has Span:call_site() -> call site = call site of the macro!
-    // https://github.com/dtolnay/quote
-    // https://docs.rs/proc-macro2/0.4/proc_macro2/struct.Span.html#method.call_site
-    quote! {
-        {
-            #header_code
-
-            #ctrl_code
-            #nth_code
-            #recur_code
-
-            #arc_code
-            let (result_snd, result_rcv) = std::sync::mpsc::channel();
+/// The indices of all algorithm arguments that are passed anew for every run, i.e., all
+/// arguments but those holding persistent state, which are taken from the call site once.
+fn run_arg_indices(compiled_algo: &OhuaData) -> Vec<i32> {
+    let state_args: BTreeSet<i32> = compiled_algo
+        .graph
+        .arcs
+        .state
+        .iter()
+        .filter_map(|arc| match arc.source {
+            Env(EnvRefLit { content: i }) => Some(i),
+            _ => None,
+        })
+        .collect();
 
-            #sf_code
+    (0..compiled_algo.mainArity)
+        .filter(|i| !state_args.contains(i))
+        .collect()
+}
 
-            #op_code
+/// Rewires the graph for an `AlgorithmInstance` or a stream. The arguments of every run
+/// arrive at a `feed_args` operator, which also triggers all functions that have no other inputs.
+fn prepare_instance_inputs(compiled_algo: &mut OhuaData) {
+    let input_op = compiled_algo
+        .graph
+        .operators
+        .iter()
+        .map(|o| o.operatorId)
+        .max()
+        .unwrap_or(0)
+        + 1;
+    let run_args = run_arg_indices(compiled_algo);
 
-            run_tasks(tasks);
-            result_rcv.recv().unwrap()
+    // output 0 triggers the sources, the arguments of a run leave through outputs 1 to n
+    let arg_port = |i: i32| ArcIdentifier {
+        operator: input_op,
+        index: run_args.iter().position(|a| *a == i).unwrap() as i32 + 1,
+    };
+    for arc in &mut compiled_algo.graph.arcs.direct {
+        if let Env(EnvRefLit { content: i }) = arc.source {
+            arc.source = Local(arg_port(i));
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-
-    use super::*;
-    use crate::parse::tests::parse_call;
+    let sources: Vec<i32> = compiled_algo
+        .graph
+        .operators
+        .iter()
+        .filter(|op| {
+            op.nodeType == NodeType::FunctionNode
+                && get_in_arcs(&op.operatorId, &compiled_algo.graph.arcs.direct)
+                    .iter()
+                    .all(|arc| arc.target.index != -1 && !filter_env_arc(arc))
+        })
+        .map(|op| op.operatorId)
+        .collect();
+    for src in &sources {
+        compiled_algo.graph.arcs.direct.push(DirectArc {
+            target: ArcIdentifier {
+                operator: *src,
+                index: -1,
+            },
+            source: Local(ArcIdentifier {
+                operator: input_op,
+                index: 0,
+            }),
+        });
+    }
 
-    use crate::ohua_types::ArcIdentifier;
-    use crate::ohua_types::ArcSource;
-    use crate::ohua_types::DFGraph;
+    // unused outputs lead to an operator that does not exist, i.e., a dead end
+    let used: Vec<i32> = get_out_arcs(&input_op, &compiled_algo.graph.arcs.direct)
+        .iter()
+        .map(|arc| *get_out_index_from_source(&arc.source))
+        .collect();
+    for idx in (0..=run_args.len() as i32).filter(|i| !used.contains(i)) {
+        compiled_algo.graph.arcs.direct.push(DirectArc {
+            target: ArcIdentifier {
+                operator: input_op + 1,
+                index: idx,
+            },
+            source: Local(ArcIdentifier {
+                operator: input_op,
+                index: idx,
+            }),
+        });
+    }
+
+    compiled_algo.graph.operators.push(Operator {
+        operatorId: input_op,
+        operatorType: OperatorType {
+            qbNamespace: OHUA_RUNTIME_NAMESPACE.iter().map(|s| s.to_string()).collect(),
+            qbName: "feed_args".to_string(),
+        },
+        nodeType: NodeType::OperatorNode,
+    });
+}
+
+/// Zips the stream arguments of an algorithm into a single stream of argument tuples.
+fn generate_input_stream(
+    compiled_algo: &OhuaData,
+    algo_call_args: &Punctuated<Expr, Token![,]>,
+) -> TokenStream {
+    let streams: Vec<&Expr> = run_arg_indices(compiled_algo)
+        .iter()
+        .map(|i| {
+            algo_call_args.iter().nth(*i as usize).unwrap_or_else(|| {
+                panic!(
+                    "Invariant broken! Stream arg idx: {}, Algo call args length: {}",
+                    i,
+                    algo_call_args.len()
+                )
+            })
+        })
+        .collect();
+    assert!(
+        !streams.is_empty(),
+        "An algorithm run with #[ohua(stream)] needs at least one stream argument."
+    );
+
+    let vars: Vec<Ident> = (0..streams.len())
+        .map(|i| Ident::new(&format!("a{}", i), Span::call_site()))
+        .collect();
+    let first = streams[0];
+    let first_var = &vars[0];
+    let (zipped, pattern) = streams.iter().zip(vars.iter()).skip(1).fold(
+        (
+            quote! { IntoIterator::into_iter(#first) },
+            quote! { #first_var },
+        ),
+        |(zipped, pattern), (stream, var)| {
+            (
+                quote! { #zipped.zip(#stream) },
+                quote! { (#pattern, #var) },
+            )
+        },
+    );
+
+    quote! { #zipped.map(|#pattern| (#(#vars,)*)) }
+}
+
+/// Binds the `cancel_token` that all arcs of a cancellable run share. The token handed in by
+/// the caller is cloned, so the caller can keep using it.
+fn generate_cancel_token(cancellation: &Cancellation) -> TokenStream {
+    if !cancellation.is_requested() {
+        return quote! {};
+    }
+
+    let token = match cancellation.token {
+        Some(ref token) => quote! { ohua_runtime::cancel::CancellationToken::clone(&#token) },
+        None => quote! { ohua_runtime::cancel::CancellationToken::new() },
+    };
+    match cancellation.timeout_ms {
+        Some(ref ms) => quote! {
+            let cancel_token = #token.with_timeout(std::time::Duration::from_millis(#ms));
+        },
+        None => quote! { let cancel_token = #token; },
+    }
+}
+
+/// Binds the `recorder` that all arcs of a recorded run write their traffic to.
+fn generate_recorder(record: &Option<Expr>) -> TokenStream {
+    match record {
+        Some(path) => quote! {
+            let recorder = ohua_runtime::record::Recorder::create(#path)
+                .expect("Unable to create the trace file for recording the run");
+        },
+        None => quote! {},
+    }
+}
+
+pub fn generate_code(
+    compiled_algo: &mut OhuaData,
+    algo_call_args: &Punctuated<Expr, Token![,]>,
+    sigs: &Signatures,
+    options: &Options,
+) -> TokenStream {
+    let (mode, cancellation) = (options.mode, &options.cancellation);
+    handle_environment_arcs(compiled_algo);
+    run_backend_optimizations(compiled_algo, &options.passes);
+
+    if mode != Mode::Run {
+        prepare_instance_inputs(compiled_algo);
+    }
+    prepare_nths(compiled_algo);
+    //print!("{:?}", compiled_algo.graph.operators);
+    let recur_code = generate_recur::generate(compiled_algo);
+    // handle_environment_arcs(compiled_algo);
+    let header_code = generate_imports(&compiled_algo.graph);
+    let cancel_code = generate_cancel_token(cancellation);
+    let record_code = generate_recorder(&options.record);
+    let arc_code = generate_arcs(&compiled_algo, sigs, options);
+    let sf_code = generate_sfns(&compiled_algo, algo_call_args, sigs, options);
+    let op_code = generate_ops(&compiled_algo, options);
+
+    // the receivers of the components of a tuple returned by the algorithm
+    let outputs: Vec<Ident> = match compiled_algo
+        .graph
+        .operators
+        .iter()
+        .find(|op| op.operatorId == compiled_algo.graph.return_arc.operator)
+    {
+        Some(op) if is_output_tuple(op, &compiled_algo.graph) => {
+            let mut in_arcs = get_in_arcs(&op.operatorId, &compiled_algo.graph.arcs.direct);
+            in_arcs.sort_by_key(|arc| arc.target.index);
+            in_arcs
+                .iter()
+                .map(|arc| generate_var_for_in_arc(&arc.target.operator, &arc.target.index))
+                .collect()
+        }
+        _ => Vec::new(),
+    };
+    let outputs = &outputs;
+    assert!(
+        outputs.len() <= 12,
+        "Algorithms returning more than 12 values are not supported."
+    );
+    // streams hand out one stream per component, the other modes receive the components from
+    // their arcs one after another
+    let stream_outputs = mode == Mode::Stream && !outputs.is_empty();
+    let result_code = if stream_outputs {
+        quote! {}
+    } else if outputs.is_empty() {
+        let channel = match options.record {
+            Some(_) => {
+                let return_arc = &compiled_algo.graph.return_arc;
+                let name = format!(
+                    "sf_{}_out_{}__result",
+                    return_arc.operator,
+                    return_arc.index.max(0)
+                );
+                quote! { ohua_runtime::record::recorded(&recorder, #name, std::sync::mpsc::channel()) }
+            }
+            None => quote! { std::sync::mpsc::channel() },
+        };
+        quote! { let (result_snd, result_rcv) = #channel; }
+    } else {
+        quote! { let result_rcv = (#(#outputs,)*); }
+    };
+
+    let receive_result = if outputs.is_empty() {
+        quote! { result_rcv.recv().unwrap() }
+    } else {
+        quote! { result_rcv.recv_all().unwrap() }
+    };
+
+    let (input_code, run_code) = match mode {
+        Mode::Run if cancellation.is_requested() => (
+            quote! {},
+            quote! {
+                run_tasks(tasks).and_then(|()| ohua_runtime::cancel::finish(&cancel_token, &result_rcv))
+            },
+        ),
+        Mode::Run => {
+            let run = match options.schedule {
+                Some(ref schedule) => quote! { ohua_runtime::sched::run_tasks(tasks, #schedule) },
+                None => quote! { run_tasks(tasks) },
+            };
+            (
+                quote! {},
+                quote! {
+                    if let Err(e) = #run {
+                        ohua_runtime::raise(e);
+                    }
+                    #receive_result
+                },
+            )
+        }
+        Mode::Instance => (
+            quote! { let (instance_args_snd, instance_args) = std::sync::mpsc::channel(); },
+            quote! {
+                ohua_runtime::instance::AlgorithmInstance::new(instance_args_snd, result_rcv, tasks)
+            },
+        ),
+        Mode::Stream => {
+            // the streams are evaluated outside of the tasks, like persistent state
+            let input_stream = generate_input_stream(compiled_algo, algo_call_args);
+            let results = if stream_outputs {
+                quote! {
+                    let stream_tasks = start_tasks(tasks);
+                    (#(ResultStream::new(#outputs, &stream_tasks),)*)
+                }
+            } else {
+                quote! { run_stream(result_rcv, tasks) }
+            };
+            (
+                quote! {
+                    let (instance_args_snd, instance_args) = std::sync::mpsc::channel();
+                    let instance_stream = #input_stream;
+                },
+                quote! {
+                    tasks.push(Box::new(move || {
+                        for run_args in instance_stream {
+                            instance_args_snd.send(run_args)?;
+                        }
+                        Ok(())
+                    }));
+                    #results
+                },
+            )
+        }
+    };
+
+    // Macro hygiene: I can create a variable here and use it throughout the whole call-site of this
+    // macro because quote! has Span:call_site() -> call site = call site of the macro!
+    // https://github.com/dtolnay/quote
+    // https://docs.rs/proc-macro2/0.4/proc_macro2/struct.Span.html#method.call_site
+    quote! {
+        {
+            #header_code
+
+            #recur_code
+
+            #cancel_code
+            #record_code
+            #arc_code
+            #result_code
+            #input_code
+
+            #sf_code
+
+            #op_code
+
+            #run_code
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::parse::tests::parse_call;
+
+    use crate::ohua_types::ArcIdentifier;
+    use crate::ohua_types::ArcSource;
+    use crate::ohua_types::DFGraph;
     use crate::ohua_types::OhuaData;
     use crate::ohua_types::Operator;
     use crate::ohua_types::OperatorType;
@@ -1168,26 +1848,210 @@ mod tests {
         );
 
         let generated_imports =
-            generate_imports(&compiled.graph.operators, &compiled.graph.arcs.direct).to_string();
+            generate_imports(&compiled.graph).to_string();
         // println!(
         //     "\nGenerated code for imports:\n{}\n",
         //     &(generated_imports.replace(";", ";\n"))
         // );
-        assert!("use std :: sync :: mpsc :: Receiver ; use ohua_runtime :: * ; use ohua_runtime :: arcs :: * ; use ohua_runtime :: lang :: { send_once , Unit } ; use ns1 :: some_sfn ; use ns2 :: some_other_sfn ;" == generated_imports);
+        assert!("use std :: sync :: mpsc :: Receiver ; use ohua_runtime :: * ; use ohua_runtime :: arcs :: * ; use ohua_runtime :: lang :: { send_once , CtrlToken , Unit } ; use ns1 :: some_sfn ; use ns2 :: some_other_sfn ;" == generated_imports);
 
-        let generated_arcs = generate_arcs(&compiled).to_string();
+        let generated_arcs = generate_arcs(&compiled, &Signatures::default(), &Options::default()).to_string();
         // println!("\nGenerated code for arcs:\n{}\n", &generated_arcs);
         assert!(
-            "let ( sf_0_out_0__sf_1_in_0 , sf_1_in_0 ) = std :: sync :: mpsc :: channel ( ) ;"
+            "let ( sf_0_out_0__sf_1_in_0 , sf_1_in_0 ) = ohua_runtime :: arcs :: named_channel ( \"sf_0_out_0__sf_1_in_0\" ) ;"
                 == generated_arcs
         );
 
-        let generated_sfns = generate_sfns(&compiled, &Punctuated::new()).to_string();
+        let generated_sfns = generate_sfns(&compiled, &Punctuated::new(), &Signatures::default(), &Options::default()).to_string();
         // println!(
         //     "Generated code for sfns:\n{}\n",
         //     &(generated_sfns.replace(";", ";\n"))
         // );
-        assert!("let mut tasks : Vec < Box < dyn FnOnce ( ) -> Result < ( ) , RunError > + Send + 'static >> = Vec :: new ( ) ; tasks . push ( Box :: new ( move || { let r = some_sfn ( ) ; sf_0_out_0__sf_1_in_0 . dispatch ( r ) ? ; Ok ( ( ) ) } ) ) ; tasks . push ( Box :: new ( move || { loop { let r = some_other_sfn ( sf_1_in_0 . recv ( ) ? ) ; result_snd . dispatch ( r ) ? ; } } ) ) ;" == generated_sfns);
+        assert!("let mut tasks : Vec < Box < dyn FnOnce ( ) -> Result < ( ) , RunError > + Send + 'static >> = Vec :: new ( ) ; tasks . push ( ohua_runtime :: task ( 0i32 , \"some_sfn\" , move || { let r = some_sfn ( ) ; sf_0_out_0__sf_1_in_0 . dispatch ( r ) ? ; Ok ( ( ) ) } ) ) ; tasks . push ( ohua_runtime :: task ( 1i32 , \"some_other_sfn\" , move || { loop { let r = some_other_sfn ( sf_1_in_0 . recv ( ) ? ) ; result_snd . dispatch ( r ) ? ; } } ) ) ;" == generated_sfns);
+    }
+
+    #[test]
+    fn borrowed_arg_code_gen() {
+        let con = OperatorType {
+            qbNamespace: vec!["ns2".to_string()],
+            qbName: "some_other_sfn".to_string(),
+        };
+        let compiled = producer_consumer(
+            OperatorType {
+                qbNamespace: vec!["ns1".to_string()],
+                qbName: "some_sfn".to_string(),
+            },
+            NodeType::FunctionNode,
+            con.clone(),
+            NodeType::FunctionNode,
+            0,
+        );
+        let mut sigs = Signatures::default();
+        sigs.insert(&con, vec![true]);
+
+        let generated_sfns = generate_sfns(&compiled, &Punctuated::new(), &sigs, &Options::default()).to_string();
+        assert!(generated_sfns.contains("let r = some_other_sfn ( & sf_1_in_0 . recv ( ) ? ) ;"));
+    }
+
+    #[test]
+    fn traced_operator_code_gen() {
+        let traced = |name: &str| {
+            let point = TracePoint::new(&Operator {
+                operatorId: 1,
+                operatorType: OperatorType {
+                    qbNamespace: OHUA_RUNTIME_NAMESPACE.iter().map(|s| s.to_string()).collect(),
+                    qbName: name.to_string(),
+                },
+                nodeType: NodeType::OperatorNode,
+            });
+            let op_name = Ident::new(name, Span::call_site());
+            generate_operator_code(op_name, vec![quote! { sf_1_in_0 }], Some(point)).to_string()
+        };
+
+        // one span per invocation
+        assert!("loop { let _call = ohua_runtime :: trace :: Call :: start ( 1i32 , \"smapFun\" ) ; smapFun ( & sf_1_in_0 ) ? ; }" == traced("smapFun"));
+        assert!("let _call = ohua_runtime :: trace :: Call :: start ( 1i32 , \"ctrl\" ) ; ctrl ( & sf_1_in_0 ) ? ; Ok ( ( ) )" == traced("ctrl"));
+    }
+
+    #[test]
+    fn fallible_sfn_code_gen() {
+        let con = OperatorType {
+            qbNamespace: vec!["ns2".to_string()],
+            qbName: "some_other_sfn".to_string(),
+        };
+        let compiled = producer_consumer(
+            OperatorType {
+                qbNamespace: vec!["ns1".to_string()],
+                qbName: "some_sfn".to_string(),
+            },
+            NodeType::FunctionNode,
+            con.clone(),
+            NodeType::FunctionNode,
+            0,
+        );
+        let mut sigs = Signatures::default();
+        sigs.insert_fallible(&con);
+
+        let generated_sfns = generate_sfns(&compiled, &Punctuated::new(), &sigs, &Options::default()).to_string();
+        assert!(generated_sfns.contains("let r = some_sfn ( ) ; sf_0_out_0__sf_1_in_0 . dispatch ( r ) ? ;"));
+        assert!(generated_sfns.contains("let r = some_other_sfn ( sf_1_in_0 . recv ( ) ? ) ; let r = r ? ; result_snd . dispatch ( r ) ? ;"));
+    }
+
+    #[test]
+    fn traced_sfn_code_gen() {
+        let compiled = producer_consumer(
+            OperatorType {
+                qbNamespace: vec!["ns1".to_string()],
+                qbName: "some_sfn".to_string(),
+            },
+            NodeType::FunctionNode,
+            OperatorType {
+                qbNamespace: vec!["ns2".to_string()],
+                qbName: "some_other_sfn".to_string(),
+            },
+            NodeType::FunctionNode,
+            0,
+        );
+        let point = TracePoint::new(&compiled.graph.operators[1]);
+        let source = ArcIdentifier {
+            operator: 0,
+            index: 0,
+        };
+        let arg = point.input(0, &source, quote! { sf_1_in_0.recv()? });
+        assert!(
+            "ohua_runtime :: trace :: input ( 1i32 , \"some_other_sfn\" , 0i32 , ( 0i32 , 0i32 ) , sf_1_in_0 . recv ( ) ? )"
+                == arg.to_string()
+        );
+
+        let r = Ident::new("r", Span::call_site());
+        let send = quote! { result_snd.dispatch(r)? };
+        let generated = generate_sfn_call_code(
+            Ident::new("some_other_sfn", Span::call_site()),
+            vec![quote! { &#arg }],
+            r,
+            send,
+            1,
+            None,
+            None,
+            Some((point, vec![0])),
+        )
+        .to_string();
+
+        assert!("loop { let r = match ( & ohua_runtime :: trace :: input ( 1i32 , \"some_other_sfn\" , 0i32 , ( 0i32 , 0i32 ) , sf_1_in_0 . recv ( ) ? ) , ) { ( a0 , ) => { let _call = ohua_runtime :: trace :: Call :: start ( 1i32 , \"some_other_sfn\" ) ; some_other_sfn ( a0 ) } } ; result_snd . dispatch ( r ) ? ; ohua_runtime :: trace :: output ( 1i32 , \"some_other_sfn\" , 0i32 ) ; }" == generated);
+    }
+
+    #[test]
+    fn shared_fan_out_code_gen() {
+        let sfn = |name: &str| OperatorType {
+            qbNamespace: vec!["ns1".to_string()],
+            qbName: name.to_string(),
+        };
+        let to = |op: i32| DirectArc {
+            target: ArcIdentifier {
+                operator: op,
+                index: 0,
+            },
+            source: ArcSource::Local(ArcIdentifier {
+                operator: 0,
+                index: 0,
+            }),
+        };
+        let compiled = OhuaData {
+            graph: DFGraph {
+                operators: (0..3)
+                    .map(|id| Operator {
+                        operatorId: id,
+                        operatorType: sfn(&format!("sfn_{}", id)),
+                        nodeType: NodeType::FunctionNode,
+                    })
+                    .collect(),
+                arcs: Arcs {
+                    direct: vec![to(1), to(2)],
+                    state: vec![],
+                    dead: vec![],
+                },
+                return_arc: ArcIdentifier {
+                    operator: 1,
+                    index: -1,
+                },
+                input_targets: Vec::new(),
+            },
+            mainArity: 1,
+            sfDependencies: Vec::new(),
+        };
+
+        let mut sigs = Signatures::default();
+        sigs.insert(&sfn("sfn_1"), vec![true]);
+
+        // a single consumer taking the value by value forces the clone
+        let generated_arcs = generate_arcs(&compiled, &sigs, &Options::default()).to_string();
+        assert!(generated_arcs.contains("let sf_0_out_0 = DispatchQueue :: new"));
+
+        sigs.insert(&sfn("sfn_2"), vec![true]);
+
+        let generated_arcs = generate_arcs(&compiled, &sigs, &Options::default()).to_string();
+        assert!(generated_arcs.contains("let sf_0_out_0 = SharedDispatchQueue :: new ( vec ! [ sf_0_out_0__sf_1_in_0 , sf_0_out_0__sf_2_in_0 , ] ) ;"));
+
+        let generated_sfns = generate_sfns(&compiled, &Punctuated::new(), &sigs, &Options::default()).to_string();
+        assert!(generated_sfns.contains("let r = sfn_0 ( ) ; sf_0_out_0 . dispatch ( std :: sync :: Arc :: new ( r ) ) ? ;"));
+        assert!(generated_sfns.contains("let r = sfn_2 ( & * sf_2_in_0 . recv ( ) ? ) ;"));
+    }
+
+    #[test]
+    fn mixed_shared_outputs_code_gen() {
+        let r = Ident::new("r", Span::call_site());
+        let out = |name: &str, shared: bool| (Ident::new(name, Span::call_site()), shared);
+
+        let send = generate_send(&r, &vec![out("a", true), out("b", true)], &1, &0).to_string();
+        assert_eq!(send, "let r = std :: sync :: Arc :: new ( r ) ; a . dispatch ( std :: sync :: Arc :: clone ( & r ) ) ? ; b . dispatch ( std :: sync :: Arc :: clone ( & r ) ) ? ;");
+
+        // only the shared ports get the value wrapped in an `Arc`
+        let send = generate_send(&r, &vec![out("a", true), out("b", false), out("c", false)], &1, &0)
+            .to_string();
+        assert_eq!(send, "let r_shared = std :: sync :: Arc :: new ( r . clone ( ) ) ; a . dispatch ( std :: sync :: Arc :: clone ( & r_shared ) ) ? ; b . dispatch ( r . clone ( ) ) ? ; c . dispatch ( r . clone ( ) ) ? ;");
+
+        let send = generate_send(&r, &vec![out("a", false), out("b", false)], &1, &0).to_string();
+        assert_eq!(send, "a . dispatch ( r . clone ( ) ) ? ; b . dispatch ( r . clone ( ) ) ? ;");
     }
 
     #[test]
@@ -1206,19 +2070,625 @@ mod tests {
             0,
         );
 
-        let generated_arcs = generate_arcs(&compiled).to_string();
+        let generated_arcs = generate_arcs(&compiled, &Signatures::default(), &Options::default()).to_string();
         // println!("\nGenerated code for arcs:\n{}\n", &generated_arcs);
         assert!(
-            "let ( sf_0_out_0__sf_1_in_0 , sf_1_in_0 ) = std :: sync :: mpsc :: channel ( ) ;"
+            "let ( sf_0_out_0__sf_1_in_0 , sf_1_in_0 ) = ohua_runtime :: arcs :: named_channel ( \"sf_0_out_0__sf_1_in_0\" ) ;"
                 == generated_arcs
         );
 
-        let generated_ops = generate_ops(&compiled).to_string();
+        let generated_ops = generate_ops(&compiled, &Options::default()).to_string();
         // println!(
         //     "Generated code for ops:\n{}\n",
         //     &(generated_ops.replace(";", ";\n"))
         // );
-        assert!("tasks . push ( Box :: new ( move || { loop { some_op ( & sf_0_out_0__sf_1_in_0 ) ? ; } } ) ) ; tasks . push ( Box :: new ( move || { loop { some_other_op ( & sf_1_in_0 , & result_snd ) ? ; } } ) ) ;" == generated_ops);
+        assert!("tasks . push ( ohua_runtime :: task ( 0i32 , \"some_op\" , move || { loop { some_op ( & sf_0_out_0__sf_1_in_0 ) ? ; } } ) ) ; tasks . push ( ohua_runtime :: task ( 1i32 , \"some_other_op\" , move || { loop { some_other_op ( & sf_1_in_0 , & result_snd ) ? ; } } ) ) ;" == generated_ops);
+    }
+
+    #[test]
+    fn switch_code_gen() {
+        let runtime_op = |id: i32, name: &str| Operator {
+            operatorId: id,
+            operatorType: OperatorType {
+                qbNamespace: vec!["ohua_runtime".to_string(), "lang".to_string()],
+                qbName: name.to_string(),
+            },
+            nodeType: NodeType::OperatorNode,
+        };
+        let arc = |src: i32, src_idx: i32, tgt: i32, tgt_idx: i32| DirectArc {
+            target: ArcIdentifier {
+                operator: tgt,
+                index: tgt_idx,
+            },
+            source: ArcSource::Local(ArcIdentifier {
+                operator: src,
+                index: src_idx,
+            }),
+        };
+        let compiled = OhuaData {
+            graph: DFGraph {
+                operators: vec![runtime_op(1, "switchFun"), runtime_op(2, "select_n")],
+                arcs: Arcs {
+                    direct: vec![
+                        arc(0, 0, 1, 0),
+                        arc(1, 0, 3, 0),
+                        arc(1, 1, 4, 0),
+                        arc(0, 0, 2, 0),
+                        arc(3, 0, 2, 1),
+                        arc(4, 0, 2, 2),
+                    ],
+                    state: vec![],
+                    dead: vec![],
+                },
+                return_arc: ArcIdentifier {
+                    operator: 2,
+                    index: -1,
+                },
+                input_targets: Vec::new(),
+            },
+            mainArity: 0,
+            sfDependencies: Vec::new(),
+        };
+
+        let generated_ops = generate_ops(&compiled, &Options::default()).to_string();
+        assert!("tasks . push ( ohua_runtime :: task ( 1i32 , \"switchFun\" , move || { loop { switchFun ( & sf_1_in_0 , & [ & sf_1_out_0__sf_3_in_0 as & dyn ArcInput < CtrlToken > , & sf_1_out_1__sf_4_in_0 as & dyn ArcInput < CtrlToken > ] ) ? ; } } ) ) ; tasks . push ( ohua_runtime :: task ( 2i32 , \"select_n\" , move || { loop { select_n ( & sf_2_in_0 , & [ & sf_2_in_1 as & dyn ArcOutput < _ > , & sf_2_in_2 as & dyn ArcOutput < _ > ] , & result_snd ) ? ; } } ) ) ;" == generated_ops);
+    }
+
+    #[test]
+    fn ctrl_code_gen() {
+        let arc = |src: i32, src_idx: i32, tgt: i32, tgt_idx: i32| DirectArc {
+            target: ArcIdentifier {
+                operator: tgt,
+                index: tgt_idx,
+            },
+            source: ArcSource::Local(ArcIdentifier {
+                operator: src,
+                index: src_idx,
+            }),
+        };
+        let compiled = OhuaData {
+            graph: DFGraph {
+                operators: vec![Operator {
+                    operatorId: 1,
+                    operatorType: OperatorType {
+                        qbNamespace: vec!["ohua_runtime".to_string(), "lang".to_string()],
+                        qbName: "ctrl".to_string(),
+                    },
+                    nodeType: NodeType::OperatorNode,
+                }],
+                arcs: Arcs {
+                    direct: vec![
+                        arc(0, 1, 1, 0),
+                        arc(2, 0, 1, 1),
+                        arc(3, 0, 1, 2),
+                        arc(1, 0, 4, 0),
+                        arc(1, 1, 5, 0),
+                    ],
+                    state: vec![],
+                    dead: vec![],
+                },
+                return_arc: ArcIdentifier {
+                    operator: 6,
+                    index: -1,
+                },
+                input_targets: Vec::new(),
+            },
+            mainArity: 0,
+            sfDependencies: Vec::new(),
+        };
+
+        let generated_ops = generate_ops(&compiled, &Options::default()).to_string();
+        assert!("tasks . push ( ohua_runtime :: task ( 1i32 , \"ctrl\" , move || { ctrl ( & sf_1_in_0 , & ( sf_1_in_1 , sf_1_in_2 , ) , & ( sf_1_out_0__sf_4_in_0 , sf_1_out_1__sf_5_in_0 , ) ) ? ; Ok ( ( ) ) } ) ) ;" == generated_ops);
+    }
+
+    #[test]
+    #[should_panic(expected = "passing more than 12 variables to their body are not supported")]
+    fn oversized_ctrl_code_gen() {
+        let arc = |src: i32, src_idx: i32, tgt: i32, tgt_idx: i32| DirectArc {
+            target: ArcIdentifier {
+                operator: tgt,
+                index: tgt_idx,
+            },
+            source: ArcSource::Local(ArcIdentifier {
+                operator: src,
+                index: src_idx,
+            }),
+        };
+        // the control input followed by 13 variables, each forwarded to an operator of its own
+        let mut direct = vec![arc(0, 1, 1, 0)];
+        for var in 0..13 {
+            direct.push(arc(100 + var, 0, 1, var + 1));
+            direct.push(arc(1, var, 200 + var, 0));
+        }
+        let compiled = OhuaData {
+            graph: DFGraph {
+                operators: vec![Operator {
+                    operatorId: 1,
+                    operatorType: OperatorType {
+                        qbNamespace: vec!["ohua_runtime".to_string(), "lang".to_string()],
+                        qbName: "ctrl".to_string(),
+                    },
+                    nodeType: NodeType::OperatorNode,
+                }],
+                arcs: Arcs {
+                    direct,
+                    state: vec![],
+                    dead: vec![],
+                },
+                return_arc: ArcIdentifier {
+                    operator: 300,
+                    index: -1,
+                },
+                input_targets: Vec::new(),
+            },
+            mainArity: 0,
+            sfDependencies: Vec::new(),
+        };
+
+        generate_ops(&compiled, &Options::default());
+    }
+
+    #[test]
+    fn persistent_state_code_gen() {
+        let mut compiled = producer_consumer(
+            OperatorType {
+                qbNamespace: vec!["ns1".to_string()],
+                qbName: "some_sfn".to_string(),
+            },
+            NodeType::FunctionNode,
+            OperatorType {
+                qbNamespace: vec![],
+                qbName: "count".to_string(),
+            },
+            NodeType::FunctionNode,
+            0,
+        );
+        compiled.graph.arcs.state.push(StateArc {
+            target: 1,
+            source: ArcSource::Env(EnvRefLit { content: 0 }),
+        });
+        let (_, call_args) = parse_call("some_algo(Arc::clone(&counter))");
+
+        let generated_arcs = generate_arcs(&compiled, &Signatures::default(), &Options::default()).to_string();
+        assert!(
+            "let ( sf_0_out_0__sf_1_in_0 , sf_1_in_0 ) = ohua_runtime :: arcs :: named_channel ( \"sf_0_out_0__sf_1_in_0\" ) ;"
+                == generated_arcs
+        );
+
+        let generated_sfns = generate_sfns(&compiled, &call_args, &Signatures::default(), &Options::default()).to_string();
+        assert!(generated_sfns.starts_with("let sf_1_state = Arc :: clone ( & counter ) ;"));
+        assert!(generated_sfns.contains("loop { let r = match ( sf_1_in_0 . recv ( ) ? , ) { ( a0 , ) => sf_1_state . lock ( ) ? . count ( a0 ) , } ;"));
+    }
+
+    /// `combine(gen(), arg0)` with an unused second argument
+    fn gen_combine() -> OhuaData {
+        let mut compiled = producer_consumer(
+            OperatorType {
+                qbNamespace: vec!["crate".to_string(), "fns".to_string()],
+                qbName: "gen".to_string(),
+            },
+            NodeType::FunctionNode,
+            OperatorType {
+                qbNamespace: vec!["crate".to_string(), "fns".to_string()],
+                qbName: "combine".to_string(),
+            },
+            NodeType::FunctionNode,
+            0,
+        );
+        compiled.graph.arcs.direct.push(DirectArc {
+            target: ArcIdentifier {
+                operator: 1,
+                index: 1,
+            },
+            source: ArcSource::Env(EnvRefLit { content: 0 }),
+        });
+        compiled.mainArity = 2;
+        compiled
+    }
+
+    #[test]
+    fn instance_code_gen() {
+        let mut compiled = gen_combine();
+        let (_, call_args) = parse_call("some_algo()");
+
+        let code = generate_code(
+            &mut compiled,
+            &call_args,
+            &Signatures::default(),
+            &Options {
+                mode: Mode::Instance,
+                ..Options::default()
+            },
+        )
+        .to_string();
+
+        // the producer without inputs is triggered once per run
+        assert!(code.contains("loop { for _ in 0 .. CtrlToken :: count ( & sf_0_in_ctrl . recv ( ) ? ) { let r = gen ( ) ;"));
+        // the unused second argument is dropped
+        assert!(code.contains("let sf_3_out_2__sf_4_in_2 = DeadEndArc :: default ( ) ;"));
+        assert!(code.contains("feed_args ( & instance_args , & sf_3_out_0__sf_0_in_ctrl , & ( sf_3_out_1__sf_2_in_0 , sf_3_out_2__sf_4_in_2 , ) ) ? ; Ok ( ( ) )"));
+        assert!(code.ends_with("ohua_runtime :: instance :: AlgorithmInstance :: new ( instance_args_snd , result_rcv , tasks ) }"));
+    }
+
+    #[test]
+    fn recursion_instance_code_gen() {
+        let fixture = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../testcases/src/recursion/algorithms/count_up.ohuao");
+        let mut compiled: OhuaData =
+            serde_json::from_str(&std::fs::read_to_string(fixture).unwrap()).unwrap();
+        crate::alter_ohua_ns_imports(&mut compiled);
+        let (_, call_args) = parse_call("some_algo()");
+
+        let code = generate_code(
+            &mut compiled,
+            &call_args,
+            &Signatures::default(),
+            &Options {
+                mode: Mode::Instance,
+                ..Options::default()
+            },
+        )
+        .to_string();
+
+        // every run passes through the recursion once, so it is set up anew for every run
+        assert!(code.contains("move || { loop { recur_1 ( & sf_1_in_0 ,"));
+    }
+
+    #[test]
+    fn cancellable_code_gen() {
+        let mut compiled = gen_combine();
+        let (_, call_args) = parse_call("some_algo(a)");
+        let cancellation = Cancellation {
+            token: None,
+            timeout_ms: Some(syn::parse_str("500").unwrap()),
+        };
+
+        let code = generate_code(
+            &mut compiled,
+            &call_args,
+            &Signatures::default(),
+            &Options {
+                cancellation,
+                ..Options::default()
+            },
+        )
+        .to_string();
+
+        assert!(code.contains("let cancel_token = ohua_runtime :: cancel :: CancellationToken :: new ( ) . with_timeout ( std :: time :: Duration :: from_millis ( 500 ) ) ;"));
+        assert!(code.contains("let ( sf_0_out_0__sf_1_in_0 , sf_1_in_0 ) = ohua_runtime :: cancel :: cancellable ( & cancel_token , ohua_runtime :: arcs :: named_channel ( \"sf_0_out_0__sf_1_in_0\" ) ) ;"));
+        assert!(code.ends_with("run_tasks ( tasks ) . and_then ( | ( ) | ohua_runtime :: cancel :: finish ( & cancel_token , & result_rcv ) ) }"));
+    }
+
+    #[test]
+    fn stream_code_gen() {
+        let mut compiled = gen_combine();
+        let (_, call_args) = parse_call("some_algo(numbers, rx)");
+
+        let code = generate_code(
+            &mut compiled,
+            &call_args,
+            &Signatures::default(),
+            &Options {
+                mode: Mode::Stream,
+                ..Options::default()
+            },
+        )
+        .to_string();
+
+        assert!(code.contains("let instance_stream = IntoIterator :: into_iter ( numbers ) . zip ( rx ) . map ( | ( a0 , a1 ) | ( a0 , a1 , ) ) ;"));
+        assert!(code.ends_with("tasks . push ( Box :: new ( move || { for run_args in instance_stream { instance_args_snd . send ( run_args ) ? ; } Ok ( ( ) ) } ) ) ; run_stream ( result_rcv , tasks ) }"));
+    }
+
+    /// `tuple(gen(), double(gen()))`
+    fn tuple_outputs() -> OhuaData {
+        let op = |id: i32, ns: &[&str], name: &str| Operator {
+            operatorId: id,
+            operatorType: OperatorType {
+                qbNamespace: ns.iter().map(|s| s.to_string()).collect(),
+                qbName: name.to_string(),
+            },
+            nodeType: NodeType::FunctionNode,
+        };
+        let arc = |src: i32, tgt: i32, tgt_idx: i32| DirectArc {
+            target: ArcIdentifier {
+                operator: tgt,
+                index: tgt_idx,
+            },
+            source: ArcSource::Local(ArcIdentifier {
+                operator: src,
+                index: 0,
+            }),
+        };
+
+        OhuaData {
+            graph: DFGraph {
+                operators: vec![
+                    op(1, &["crate", "fns"], "gen"),
+                    op(2, &["crate", "fns"], "double"),
+                    op(3, &OHUA_RUNTIME_NAMESPACE, "tuple"),
+                ],
+                arcs: Arcs {
+                    direct: vec![arc(1, 2, 0), arc(1, 3, 0), arc(2, 3, 1)],
+                    state: vec![],
+                    dead: vec![],
+                },
+                return_arc: ArcIdentifier {
+                    operator: 3,
+                    index: -1,
+                },
+                input_targets: Vec::new(),
+            },
+            mainArity: 0,
+            sfDependencies: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn tuple_outputs_code_gen() {
+        let mut compiled = tuple_outputs();
+        let code = generate_code(
+            &mut compiled,
+            &Punctuated::new(),
+            &Signatures::default(),
+            &Options::default(),
+        )
+        .to_string();
+
+        // no call to `tuple`, every component is received from its own arc
+        assert!(!code.contains("tuple"));
+        assert!(!code.contains("result_snd"));
+        assert!(code.contains("let result_rcv = ( sf_3_in_0 , sf_3_in_1 , ) ;"));
+        assert!(code.ends_with("if let Err ( e ) = run_tasks ( tasks ) { ohua_runtime :: raise ( e ) ; } result_rcv . recv_all ( ) . unwrap ( ) }"));
+
+        let mut compiled = tuple_outputs();
+        let code = generate_code(
+            &mut compiled,
+            &Punctuated::new(),
+            &Signatures::default(),
+            &Options {
+                mode: Mode::Instance,
+                ..Options::default()
+            },
+        )
+        .to_string();
+
+        assert!(!code.contains("result_snd"));
+        assert!(code.ends_with("ohua_runtime :: instance :: AlgorithmInstance :: new ( instance_args_snd , result_rcv , tasks ) }"));
+
+        let mut compiled = tuple_outputs();
+        compiled.mainArity = 1;
+        let (_, call_args) = parse_call("some_algo(numbers)");
+        let code = generate_code(
+            &mut compiled,
+            &call_args,
+            &Signatures::default(),
+            &Options {
+                mode: Mode::Stream,
+                ..Options::default()
+            },
+        )
+        .to_string();
+
+        assert!(!code.contains("result_snd"));
+        assert!(code.ends_with("let stream_tasks = start_tasks ( tasks ) ; ( ResultStream :: new ( sf_3_in_0 , & stream_tasks ) , ResultStream :: new ( sf_3_in_1 , & stream_tasks ) , ) }"));
+    }
+
+    #[test]
+    fn inner_tuple_code_gen() {
+        // the tuple is handed to another function instead of being returned
+        let mut compiled = tuple_outputs();
+        compiled.graph.operators.push(Operator {
+            operatorId: 4,
+            operatorType: OperatorType {
+                qbNamespace: vec!["crate".to_string(), "fns".to_string()],
+                qbName: "sum_pair".to_string(),
+            },
+            nodeType: NodeType::FunctionNode,
+        });
+        compiled.graph.arcs.direct.push(DirectArc {
+            target: ArcIdentifier {
+                operator: 4,
+                index: 0,
+            },
+            source: ArcSource::Local(ArcIdentifier {
+                operator: 3,
+                index: 0,
+            }),
+        });
+        compiled.graph.return_arc.operator = 4;
+        let code = generate_code(
+            &mut compiled,
+            &Punctuated::new(),
+            &Signatures::default(),
+            &Options::default(),
+        )
+        .to_string();
+
+        assert!(code.contains("use ohua_runtime :: lang :: tuple ;"));
+        assert!(code.contains("tasks . push ( ohua_runtime :: task ( 3i32 , \"tuple\" , move || { loop { let r = tuple ( ( sf_3_in_0 . recv ( ) ? , sf_3_in_1 . recv ( ) ? , ) ) ; sf_3_out_0__sf_4_in_0 . dispatch ( r ) ? } } ) ) ;"));
+        assert!(code.ends_with("result_rcv . recv ( ) . unwrap ( ) }"));
+    }
+
+    #[test]
+    fn destruct_code_gen() {
+        let op = |id: i32, ns: &[&str], name: &str| Operator {
+            operatorId: id,
+            operatorType: OperatorType {
+                qbNamespace: ns.iter().map(|s| s.to_string()).collect(),
+                qbName: name.to_string(),
+            },
+            nodeType: NodeType::FunctionNode,
+        };
+        let arc = |source: ArcSource, tgt: i32, tgt_idx: i32| DirectArc {
+            target: ArcIdentifier {
+                operator: tgt,
+                index: tgt_idx,
+            },
+            source,
+        };
+        let local = |src: i32| {
+            ArcSource::Local(ArcIdentifier {
+                operator: src,
+                index: 0,
+            })
+        };
+        let lit = |content: i32| ArcSource::Env(Envs::NumericLit { content });
+
+        // `let (a, b) = pair(); combine(a, b)`
+        let mut compiled = OhuaData {
+            graph: DFGraph {
+                operators: vec![
+                    op(1, &["crate", "fns"], "pair"),
+                    op(2, &OHUA_RUNTIME_NAMESPACE, "nth"),
+                    op(3, &OHUA_RUNTIME_NAMESPACE, "nth"),
+                    op(4, &["crate", "fns"], "combine"),
+                ],
+                arcs: Arcs {
+                    direct: vec![
+                        arc(lit(0), 2, 0),
+                        arc(lit(2), 2, 1),
+                        arc(local(1), 2, 2),
+                        arc(lit(1), 3, 0),
+                        arc(lit(2), 3, 1),
+                        arc(local(1), 3, 2),
+                        arc(local(2), 4, 0),
+                        arc(local(3), 4, 1),
+                    ],
+                    state: vec![],
+                    dead: vec![],
+                },
+                return_arc: ArcIdentifier {
+                    operator: 4,
+                    index: -1,
+                },
+                input_targets: Vec::new(),
+            },
+            mainArity: 0,
+            sfDependencies: Vec::new(),
+        };
+        let code = generate_code(
+            &mut compiled,
+            &Punctuated::new(),
+            &Signatures::default(),
+            &Options::default(),
+        )
+        .to_string();
+
+        assert!(!code.contains("nth"));
+        assert!(code.contains("destruct ( & sf_2_in_0 , & ( sf_2_out_0__sf_4_in_0 , sf_2_out_1__sf_4_in_1 , ) ) ? ; Ok ( ( ) )"), "{}", code);
+    }
+
+    #[test]
+    fn scheduled_code_gen() {
+        let mut compiled = tuple_outputs();
+        let code = generate_code(
+            &mut compiled,
+            &Punctuated::new(),
+            &Signatures::default(),
+            &Options {
+                schedule: Some(syn::parse_str("Schedule::Random(7)").unwrap()),
+                ..Options::default()
+            },
+        )
+        .to_string();
+
+        assert!(code.ends_with("if let Err ( e ) = ohua_runtime :: sched :: run_tasks ( tasks , Schedule :: Random ( 7 ) ) { ohua_runtime :: raise ( e ) ; } result_rcv . recv_all ( ) . unwrap ( ) }"));
+    }
+
+    #[test]
+    fn mock_binding_code_gen() {
+        let op_type = OperatorType {
+            qbNamespace: vec!["crate".to_string(), "foo".to_string()],
+            qbName: "fetch".to_string(),
+        };
+        let binding =
+            generate_mock_binding(&op_type, vec![quote! { _ }, quote! { &_ }]).to_string();
+
+        assert!("let fetch : fn ( _ , & _ ) -> _ = fetch ; let fetch = ohua_runtime :: mock :: resolve ( \"crate::foo::fetch\" , fetch ) ;" == binding);
+
+        // only algorithms invoked with `#[ohua(mock)]` look up mocks
+        let compiled = producer_consumer(
+            OperatorType {
+                qbNamespace: vec!["ns1".to_string()],
+                qbName: "some_sfn".to_string(),
+            },
+            NodeType::FunctionNode,
+            OperatorType {
+                qbNamespace: vec!["ns2".to_string()],
+                qbName: "some_other_sfn".to_string(),
+            },
+            NodeType::FunctionNode,
+            0,
+        );
+        let mocked = Options {
+            mock: true,
+            ..Options::default()
+        };
+        let generated_sfns =
+            generate_sfns(&compiled, &Punctuated::new(), &Signatures::default(), &mocked)
+                .to_string();
+        assert!(generated_sfns.contains("let some_other_sfn : fn ( _ ) -> _ = some_other_sfn ; let some_other_sfn = ohua_runtime :: mock :: resolve ( \"ns2::some_other_sfn\" , some_other_sfn ) ;"));
+        let generated_sfns = generate_sfns(
+            &compiled,
+            &Punctuated::new(),
+            &Signatures::default(),
+            &Options::default(),
+        )
+        .to_string();
+        assert!(!generated_sfns.contains("mock"));
+    }
+
+    #[test]
+    fn recorded_code_gen() {
+        let mut compiled = gen_combine();
+        let (_, call_args) = parse_call("some_algo(a)");
+        let code = generate_code(
+            &mut compiled,
+            &call_args,
+            &Signatures::default(),
+            &Options {
+                record: Some(syn::parse_str("\"run.trace\"").unwrap()),
+                ..Options::default()
+            },
+        )
+        .to_string();
+        assert!(code.contains("let recorder = ohua_runtime :: record :: Recorder :: create ( \"run.trace\" )"));
+        assert!(code.contains("let ( sf_0_out_0__sf_1_in_0 , sf_1_in_0 ) = ohua_runtime :: record :: recorded ( & recorder , \"sf_0_out_0__sf_1_in_0\" , ohua_runtime :: arcs :: named_channel ( \"sf_0_out_0__sf_1_in_0\" ) ) ;"));
+        assert!(code.contains("let ( result_snd , result_rcv ) = ohua_runtime :: record :: recorded ( & recorder , \"sf_1_out_0__result\" , std :: sync :: mpsc :: channel ( ) ) ;"));
+    }
+
+    #[test]
+    fn instrumented_code_gen() {
+        let mut compiled = gen_combine();
+        let (_, call_args) = parse_call("some_algo(a)");
+        let code = generate_code(
+            &mut compiled,
+            &call_args,
+            &Signatures::default(),
+            &Options {
+                trace: true,
+                metrics: true,
+                algorithm: "algos::some_algo".to_string(),
+                ..Options::default()
+            },
+        )
+        .to_string();
+        assert!(code.contains("let ( sf_0_out_0__sf_1_in_0 , sf_1_in_0 ) = ohua_runtime :: arcs :: metered_channel ( \"algos::some_algo\" , \"sf_0_out_0__sf_1_in_0\" ) ;"));
+        assert!(code.contains("ohua_runtime :: trace :: Call :: start ( 0i32 , \"gen\" )"));
+
+        let mut compiled = gen_combine();
+        let code = generate_code(
+            &mut compiled,
+            &call_args,
+            &Signatures::default(),
+            &Options {
+                watchdog: true,
+                ..Options::default()
+            },
+        )
+        .to_string();
+        assert!(code.contains("let ( sf_0_out_0__sf_1_in_0 , sf_1_in_0 ) = ohua_runtime :: arcs :: watched_channel ( \"sf_0_out_0__sf_1_in_0\" ) ;"));
+        assert!(!code.contains("ohua_runtime :: trace"));
     }
 
     #[test]
@@ -1262,15 +2732,15 @@ mod tests {
 
         let (_, call_args) = parse_call("some_algo(arg1)");
 
-        let generated_arcs = generate_arcs(&compiled).to_string();
+        let generated_arcs = generate_arcs(&compiled, &Signatures::default(), &Options::default()).to_string();
         // println!("\nGenerated code for arcs:\n{}\n", &generated_arcs);
         assert!("" == generated_arcs);
 
-        let generated_sfns = generate_sfns(&compiled, &call_args).to_string();
+        let generated_sfns = generate_sfns(&compiled, &call_args, &Signatures::default(), &Options::default()).to_string();
         // println!(
         //     "Generated code for sfns:\n{}\n",
         //     &(generated_sfns.replace(";", ";\n"))
         // );
-        assert!("let mut tasks : Vec < Box < dyn FnOnce ( ) -> Result < ( ) , RunError > + Send + 'static >> = Vec :: new ( ) ; tasks . push ( Box :: new ( move || { let r = some_sfn ( arg1 ) ; ; Ok ( ( ) ) } ) ) ;" == generated_sfns);
+        assert!("let mut tasks : Vec < Box < dyn FnOnce ( ) -> Result < ( ) , RunError > + Send + 'static >> = Vec :: new ( ) ; tasks . push ( ohua_runtime :: task ( 0i32 , \"some_sfn\" , move || { let r = some_sfn ( arg1 ) ; ; Ok ( ( ) ) } ) ) ;" == generated_sfns);
     }
 }
@@ -1,6 +1,13 @@
 //! Structures and methods for moving and exchanging data between operators.
-use std::marker::Send;
-use std::sync::mpsc::{SendError, Sender};
+use crate::{sched, RunError};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::marker::{PhantomData, Send};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 /// An arc that does not have a receiving side. Any data sent into this arc is dropped.
 #[derive(Default)]
@@ -9,39 +16,766 @@ pub struct DeadEndArc {}
 /// Central abstraction layer for all arcs. Every type of arc needs to implement this
 /// as it is used to move data between operators.
 pub trait ArcInput<T> {
-    fn dispatch(&self, t: T) -> Result<(), SendError<T>>;
+    fn dispatch(&self, t: T) -> Result<(), RunError>;
+}
+
+/// The receiving end of an arc, the counterpart to `ArcInput`.
+pub trait ArcOutput<T> {
+    fn recv(&self) -> Result<T, RunError>;
+
+    fn try_recv(&self) -> Result<T, TryRecvError>;
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError>;
 }
 
 impl<T> ArcInput<T> for Sender<T> {
-    fn dispatch(&self, t: T) -> Result<(), SendError<T>> {
-        self.send(t)
+    fn dispatch(&self, t: T) -> Result<(), RunError> {
+        self.send(t).map_err(|_| RunError::SendFailed { arc: None })
+    }
+}
+
+impl<T> ArcOutput<T> for Receiver<T> {
+    fn recv(&self) -> Result<T, RunError> {
+        if let Some(res) = sched::recv(self) {
+            return res;
+        }
+        Ok(Receiver::recv(self)?)
+    }
+
+    fn try_recv(&self) -> Result<T, TryRecvError> {
+        Receiver::try_recv(self)
+    }
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        Receiver::recv_timeout(self, timeout)
     }
 }
 
 impl<T: Send> ArcInput<T> for DeadEndArc {
-    fn dispatch(&self, _t: T) -> Result<(), SendError<T>> {
+    fn dispatch(&self, _t: T) -> Result<(), RunError> {
         // drop
         Ok(())
     }
 }
 
+/// An arc that names itself in the errors of failed sends and receives.
+///
+/// Arcs created via `watched_channel` also keep track of the messages queued in them and of
+/// whether their receiver is waiting, so that `run_tasks` can tell when a run got stuck, see
+/// `watchdog`.
+pub struct NamedArc<A> {
+    arc: A,
+    name: &'static str,
+    state: Option<Arc<ArcState>>,
+}
+
+impl<A> NamedArc<A> {
+    /// Dispatches `t` and returns the number of messages queued in the arc right after, which
+    /// is only known for watched arcs.
+    fn dispatch_queued<T>(&self, t: T) -> Result<Option<usize>, RunError>
+    where
+        A: ArcInput<T>,
+    {
+        let state = match self.state {
+            Some(ref state) => state,
+            None => {
+                return self
+                    .arc
+                    .dispatch(t)
+                    .map(|()| None)
+                    .map_err(|e| e.on_arc(self.name))
+            }
+        };
+        // count the message before sending it, so the receiver never sees a negative depth
+        let depth = state.depth.fetch_add(1, Ordering::SeqCst) + 1;
+        match self.arc.dispatch(t) {
+            Ok(()) => {
+                state.messages.fetch_add(1, Ordering::SeqCst);
+                Ok(Some(depth))
+            }
+            Err(e) => {
+                state.depth.fetch_sub(1, Ordering::SeqCst);
+                Err(e.on_arc(self.name))
+            }
+        }
+    }
+
+    /// Marks the arc as waiting while `recv` blocks, a received message leaves the queue.
+    fn wait<T, E>(&self, recv: impl FnOnce(&A, Option<&ArcState>) -> Result<T, E>) -> Result<T, E> {
+        let state = match self.state {
+            Some(ref state) => state,
+            None => return recv(&self.arc, None),
+        };
+        state.waiting.store(true, Ordering::SeqCst);
+        let res = recv(&self.arc, Some(state));
+        if res.is_ok() {
+            state.depth.fetch_sub(1, Ordering::SeqCst);
+        }
+        state.waiting.store(false, Ordering::SeqCst);
+        res
+    }
+}
+
+impl<T, A: ArcInput<T>> ArcInput<T> for NamedArc<A> {
+    fn dispatch(&self, t: T) -> Result<(), RunError> {
+        self.dispatch_queued(t).map(|_| ())
+    }
+}
+
+impl<T, A: ArcOutput<T>> ArcOutput<T> for NamedArc<A> {
+    fn recv(&self) -> Result<T, RunError> {
+        self.wait(|arc, state| match state {
+            // scheduled runs detect deadlocks themselves
+            Some(state) if !sched::is_running() => loop {
+                match arc.recv_timeout(DISCONNECT_INTERVAL) {
+                    Ok(t) => return Ok(t),
+                    Err(RecvTimeoutError::Timeout) if !state.is_disconnected() => {}
+                    Err(_) => return Err(RunError::RecvFailed { arc: None }),
+                }
+            },
+            _ => arc.recv(),
+        })
+        .map_err(|e| e.on_arc(self.name))
+    }
+
+    fn try_recv(&self) -> Result<T, TryRecvError> {
+        let res = self.arc.try_recv();
+        if let (Ok(_), Some(state)) = (&res, &self.state) {
+            state.depth.fetch_sub(1, Ordering::SeqCst);
+        }
+        res
+    }
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.wait(|arc, state| match arc.recv_timeout(timeout) {
+            Err(RecvTimeoutError::Timeout) if state.is_some_and(ArcState::is_disconnected) => {
+                Err(RecvTimeoutError::Disconnected)
+            }
+            res => res,
+        })
+    }
+}
+
+/// How often a watched arc checks whether its run got stuck and disconnected the arc.
+const DISCONNECT_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Creates a channel whose ends report `name` as the arc that failed.
+///
+/// The code generator names every arc after its sending variable.
+pub fn named_channel<T>(name: &'static str) -> (NamedArc<Sender<T>>, NamedArc<Receiver<T>>) {
+    let (snd, rcv) = channel();
+    (
+        NamedArc {
+            arc: snd,
+            name,
+            state: None,
+        },
+        NamedArc {
+            arc: rcv,
+            name,
+            state: None,
+        },
+    )
+}
+
+/// Creates a named channel that is watched for deadlocks by the next `run_tasks` on the current
+/// thread.
+///
+/// The code generator uses this instead of `named_channel` when `ohua_codegen` is built with the
+/// `watchdog` feature. Watching an arc costs a few atomic operations per message.
+pub fn watched_channel<T>(name: &'static str) -> (NamedArc<Sender<T>>, NamedArc<Receiver<T>>) {
+    let state = ArcState::watched(name);
+    let (snd, rcv) = channel();
+    (
+        NamedArc {
+            arc: snd,
+            name,
+            state: Some(Arc::clone(&state)),
+        },
+        NamedArc {
+            arc: rcv,
+            name,
+            state: Some(state),
+        },
+    )
+}
+
 /// A cloning dispatch queue as abstraction for output ports that serve more than one arc.
-pub struct DispatchQueue<T: Send> {
-    senders: Vec<Sender<T>>,
+pub struct DispatchQueue<T: Send, A = Sender<T>> {
+    senders: Vec<A>,
+    values: PhantomData<fn(T)>,
 }
 
-impl<T: Send> DispatchQueue<T> {
-    pub fn new(senders: Vec<Sender<T>>) -> DispatchQueue<T> {
-        DispatchQueue { senders: senders }
+impl<T: Send, A> DispatchQueue<T, A> {
+    pub fn new(senders: Vec<A>) -> DispatchQueue<T, A> {
+        DispatchQueue {
+            senders,
+            values: PhantomData,
+        }
     }
 }
 
-impl<T: Send + Clone> ArcInput<T> for DispatchQueue<T> {
-    fn dispatch(&self, t: T) -> Result<(), SendError<T>> {
+impl<T: Send + Clone, A: ArcInput<T>> ArcInput<T> for DispatchQueue<T, A> {
+    fn dispatch(&self, t: T) -> Result<(), RunError> {
         for sx in &self.senders {
-            sx.send(t.clone())?;
+            sx.dispatch(t.clone())?;
         }
 
         Ok(())
     }
 }
+
+/// A dispatch queue that shares a single value between all of its arcs instead of copying it.
+///
+/// Every receiver gets an `Arc` pointing to the same value, so duplicating the value only bumps
+/// a reference count. Consumers are handed read-only references to the shared value.
+pub type SharedDispatchQueue<T, A = Sender<Arc<T>>> = DispatchQueue<Arc<T>, A>;
+
+/// A bundle of receivers, one per value in the tuple `V`.
+///
+/// This is implemented for tuples of `ArcOutput`s and allows operators like `ctrl` to
+/// serve an arbitrary number of arcs without having to generate a function for every arity.
+pub trait RecvAll<V> {
+    fn recv_all(&self) -> Result<V, RunError>;
+}
+
+/// A bundle of arcs that can be served from a tuple of values, one value per arc.
+pub trait DispatchAll<V> {
+    fn dispatch_all(&self, values: V) -> Result<(), RunError>;
+}
+
+/// The results of an algorithm arrive on a single channel, unless the algorithm returns a tuple,
+/// whose components arrive on a channel each.
+impl<T> RecvAll<T> for Receiver<T> {
+    fn recv_all(&self) -> Result<T, RunError> {
+        ArcOutput::recv(self)
+    }
+}
+
+impl RecvAll<()> for () {
+    fn recv_all(&self) -> Result<(), RunError> {
+        Ok(())
+    }
+}
+
+impl DispatchAll<()> for () {
+    fn dispatch_all(&self, _values: ()) -> Result<(), RunError> {
+        Ok(())
+    }
+}
+
+macro_rules! impl_bundles {
+    ($(($t:ident, $a:ident, $idx:tt)),+) => {
+        impl<$($t),+, $($a: ArcOutput<$t>),+> RecvAll<($($t,)+)> for ($($a,)+) {
+            fn recv_all(&self) -> Result<($($t,)+), RunError> {
+                Ok(($(self.$idx.recv()?,)+))
+            }
+        }
+
+        impl<$($t: Send),+, $($a: ArcInput<$t>),+> DispatchAll<($($t,)+)> for ($($a,)+) {
+            fn dispatch_all(&self, values: ($($t,)+)) -> Result<(), RunError> {
+                $(self.$idx.dispatch(values.$idx)?;)+
+                Ok(())
+            }
+        }
+    };
+}
+
+impl_bundles!((T0, A0, 0));
+impl_bundles!((T0, A0, 0), (T1, A1, 1));
+impl_bundles!((T0, A0, 0), (T1, A1, 1), (T2, A2, 2));
+impl_bundles!((T0, A0, 0), (T1, A1, 1), (T2, A2, 2), (T3, A3, 3));
+impl_bundles!(
+    (T0, A0, 0),
+    (T1, A1, 1),
+    (T2, A2, 2),
+    (T3, A3, 3),
+    (T4, A4, 4)
+);
+impl_bundles!(
+    (T0, A0, 0),
+    (T1, A1, 1),
+    (T2, A2, 2),
+    (T3, A3, 3),
+    (T4, A4, 4),
+    (T5, A5, 5)
+);
+impl_bundles!(
+    (T0, A0, 0),
+    (T1, A1, 1),
+    (T2, A2, 2),
+    (T3, A3, 3),
+    (T4, A4, 4),
+    (T5, A5, 5),
+    (T6, A6, 6)
+);
+impl_bundles!(
+    (T0, A0, 0),
+    (T1, A1, 1),
+    (T2, A2, 2),
+    (T3, A3, 3),
+    (T4, A4, 4),
+    (T5, A5, 5),
+    (T6, A6, 6),
+    (T7, A7, 7)
+);
+impl_bundles!(
+    (T0, A0, 0),
+    (T1, A1, 1),
+    (T2, A2, 2),
+    (T3, A3, 3),
+    (T4, A4, 4),
+    (T5, A5, 5),
+    (T6, A6, 6),
+    (T7, A7, 7),
+    (T8, A8, 8)
+);
+impl_bundles!(
+    (T0, A0, 0),
+    (T1, A1, 1),
+    (T2, A2, 2),
+    (T3, A3, 3),
+    (T4, A4, 4),
+    (T5, A5, 5),
+    (T6, A6, 6),
+    (T7, A7, 7),
+    (T8, A8, 8),
+    (T9, A9, 9)
+);
+impl_bundles!(
+    (T0, A0, 0),
+    (T1, A1, 1),
+    (T2, A2, 2),
+    (T3, A3, 3),
+    (T4, A4, 4),
+    (T5, A5, 5),
+    (T6, A6, 6),
+    (T7, A7, 7),
+    (T8, A8, 8),
+    (T9, A9, 9),
+    (T10, A10, 10)
+);
+impl_bundles!(
+    (T0, A0, 0),
+    (T1, A1, 1),
+    (T2, A2, 2),
+    (T3, A3, 3),
+    (T4, A4, 4),
+    (T5, A5, 5),
+    (T6, A6, 6),
+    (T7, A7, 7),
+    (T8, A8, 8),
+    (T9, A9, 9),
+    (T10, A10, 10),
+    (T11, A11, 11)
+);
+
+/// The traffic of all metered arcs of the same algorithm and name, see `metrics_report`.
+#[derive(Default)]
+struct Traffic {
+    messages: AtomicUsize,
+    max_depth: AtomicUsize,
+    dispatch_nanos: AtomicU64,
+    recv_nanos: AtomicU64,
+}
+
+/// The state of a single watched arc, shared between its sending and its receiving end.
+pub(crate) struct ArcState {
+    name: &'static str,
+    messages: AtomicUsize,
+    depth: AtomicUsize,
+    waiting: AtomicBool,
+    disconnected: AtomicBool,
+}
+
+impl ArcState {
+    /// Creates the state of a new arc, which is watched by the next run on this thread.
+    fn watched(name: &'static str) -> Arc<ArcState> {
+        let state = Arc::new(ArcState {
+            name,
+            messages: AtomicUsize::new(0),
+            depth: AtomicUsize::new(0),
+            waiting: AtomicBool::new(false),
+            disconnected: AtomicBool::new(false),
+        });
+        RUN_ARCS.with(|arcs| arcs.borrow_mut().push(Arc::clone(&state)));
+        state
+    }
+
+    /// The state of an arc whose receiver waits while `depth` messages are queued.
+    #[cfg(test)]
+    pub(crate) fn waiting_with(name: &'static str, depth: usize) -> Arc<ArcState> {
+        Arc::new(ArcState {
+            name,
+            messages: AtomicUsize::new(depth),
+            depth: AtomicUsize::new(depth),
+            waiting: AtomicBool::new(true),
+            disconnected: AtomicBool::new(false),
+        })
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub(crate) fn messages(&self) -> usize {
+        self.messages.load(Ordering::SeqCst)
+    }
+
+    /// The number of messages dispatched but not received yet.
+    pub(crate) fn depth(&self) -> usize {
+        self.depth.load(Ordering::SeqCst)
+    }
+
+    /// Whether the receiving end is currently blocked in `recv`.
+    pub(crate) fn is_waiting(&self) -> bool {
+        self.waiting.load(Ordering::SeqCst)
+    }
+
+    /// Makes the receiving end fail instead of waiting any longer, like a sender that hung up.
+    pub(crate) fn disconnect(&self) {
+        self.disconnected.store(true, Ordering::SeqCst);
+    }
+
+    fn is_disconnected(&self) -> bool {
+        self.disconnected.load(Ordering::SeqCst)
+    }
+}
+
+fn elapsed_nanos(start: Instant) -> u64 {
+    start.elapsed().as_nanos() as u64
+}
+
+/// The traffic of the metered arcs created since the last `reset_metrics`, per algorithm and
+/// name. Arcs are not kept alive by the report, so algorithms that run over and over do not pile
+/// up metrics.
+static METRICS: Mutex<BTreeMap<(&'static str, &'static str), Arc<Traffic>>> =
+    Mutex::new(BTreeMap::new());
+
+thread_local! {
+    /// The watched arcs created on this thread that have not been handed to a run yet.
+    static RUN_ARCS: RefCell<Vec<Arc<ArcState>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Takes the watched arcs created on the current thread since the last call. The generated
+/// code sets up the arcs of an algorithm on the thread that runs it, right before the tasks.
+pub(crate) fn take_run_arcs() -> Vec<Arc<ArcState>> {
+    RUN_ARCS.with(|arcs| arcs.borrow_mut().split_off(0))
+}
+
+/// An arc that keeps track of the messages sent through it.
+///
+/// A message is counted as queued from the moment it is dispatched until it is received
+/// from the matching `MeteredReceiver`.
+pub struct MeteredArc<A> {
+    arc: NamedArc<A>,
+    traffic: Arc<Traffic>,
+}
+
+impl<T, A: ArcInput<T>> ArcInput<T> for MeteredArc<A> {
+    fn dispatch(&self, t: T) -> Result<(), RunError> {
+        let start = Instant::now();
+        let res = self.arc.dispatch_queued(t);
+        self.traffic
+            .dispatch_nanos
+            .fetch_add(elapsed_nanos(start), Ordering::Relaxed);
+
+        let depth = res?.unwrap_or_default();
+        self.traffic.messages.fetch_add(1, Ordering::Relaxed);
+        self.traffic.max_depth.fetch_max(depth, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// The receiving end of a metered arc. Tracks the time spent waiting for messages.
+pub struct MeteredReceiver<T> {
+    rcv: NamedArc<Receiver<T>>,
+    traffic: Arc<Traffic>,
+}
+
+impl<T> MeteredReceiver<T> {
+    fn timed<R>(&self, recv: impl FnOnce(&NamedArc<Receiver<T>>) -> R) -> R {
+        let start = Instant::now();
+        let res = recv(&self.rcv);
+        self.traffic
+            .recv_nanos
+            .fetch_add(elapsed_nanos(start), Ordering::Relaxed);
+        res
+    }
+}
+
+impl<T> ArcOutput<T> for MeteredReceiver<T> {
+    fn recv(&self) -> Result<T, RunError> {
+        self.timed(|rcv| rcv.recv())
+    }
+
+    fn try_recv(&self) -> Result<T, TryRecvError> {
+        self.rcv.try_recv()
+    }
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.timed(|rcv| rcv.recv_timeout(timeout))
+    }
+}
+
+/// Creates a channel whose traffic shows up in the `metrics_report` under `name`, among the arcs
+/// of `algorithm`.
+///
+/// The code generator uses this instead of `named_channel` when `ohua_codegen` is built with the
+/// `metrics` feature, passing the path of the invoked algorithm. Metered arcs are watched arcs,
+/// too.
+pub fn metered_channel<T>(
+    algorithm: &'static str,
+    name: &'static str,
+) -> (MeteredArc<Sender<T>>, MeteredReceiver<T>) {
+    let traffic = Arc::clone(
+        METRICS
+            .lock()
+            .unwrap()
+            .entry((algorithm, name))
+            .or_default(),
+    );
+    let (snd, rcv) = watched_channel(name);
+    (
+        MeteredArc {
+            arc: snd,
+            traffic: Arc::clone(&traffic),
+        },
+        MeteredReceiver { rcv, traffic },
+    )
+}
+
+/// The traffic of a single arc.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ArcReport {
+    /// The number of messages sent through the arc.
+    pub messages: usize,
+    /// The largest number of messages that were queued in the arc at the same time.
+    pub max_depth: usize,
+    /// The time spent in `dispatch`.
+    pub dispatch_blocked: Duration,
+    /// The time spent waiting in `recv`.
+    pub recv_blocked: Duration,
+}
+
+/// The traffic of all metered arcs, keyed by the paths of their algorithms and the names of the
+/// arcs.
+///
+/// Arcs of the same algorithm and name, i.e., from repeated runs of an algorithm, are summed up.
+#[derive(Debug, Clone, Default)]
+pub struct MetricsReport {
+    pub algorithms: BTreeMap<&'static str, BTreeMap<&'static str, ArcReport>>,
+}
+
+impl fmt::Display for MetricsReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<40} {:>10} {:>10} {:>14} {:>14}",
+            "arc", "messages", "max depth", "dispatch (ms)", "recv (ms)"
+        )?;
+        for (algorithm, arcs) in &self.algorithms {
+            writeln!(f, "{}", algorithm)?;
+            for (name, arc) in arcs {
+                writeln!(
+                    f,
+                    "  {:<38} {:>10} {:>10} {:>14.3} {:>14.3}",
+                    name,
+                    arc.messages,
+                    arc.max_depth,
+                    arc.dispatch_blocked.as_secs_f64() * 1000.0,
+                    arc.recv_blocked.as_secs_f64() * 1000.0
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Summarizes the traffic of all metered arcs created since the last `reset_metrics`.
+pub fn metrics_report() -> MetricsReport {
+    let mut report = MetricsReport::default();
+    for (&(algorithm, name), traffic) in METRICS.lock().unwrap().iter() {
+        let arc = ArcReport {
+            messages: traffic.messages.load(Ordering::Relaxed),
+            max_depth: traffic.max_depth.load(Ordering::Relaxed),
+            dispatch_blocked: Duration::from_nanos(traffic.dispatch_nanos.load(Ordering::Relaxed)),
+            recv_blocked: Duration::from_nanos(traffic.recv_nanos.load(Ordering::Relaxed)),
+        };
+        report
+            .algorithms
+            .entry(algorithm)
+            .or_default()
+            .insert(name, arc);
+    }
+    report
+}
+
+/// Forgets about all metered arcs created so far.
+pub fn reset_metrics() {
+    METRICS.lock().unwrap().clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // the metrics are global, so every test uses an algorithm of its own
+
+    fn state<A>(arc: &NamedArc<A>) -> &ArcState {
+        arc.state.as_ref().expect("a watched arc")
+    }
+
+    #[test]
+    fn metered_depth() {
+        let (snd, rcv) = metered_channel("depth", "sf_1_out_0__sf_2_in_0");
+        for i in 0..3 {
+            snd.dispatch(i).unwrap();
+        }
+        assert_eq!(state(&snd.arc).depth(), 3);
+        assert_eq!(rcv.recv().unwrap(), 0);
+        assert_eq!(rcv.try_recv().unwrap(), 1);
+        snd.dispatch(3).unwrap();
+        assert_eq!(state(&snd.arc).depth(), 2);
+        assert_eq!(rcv.recv_timeout(Duration::from_secs(1)).unwrap(), 2);
+        assert_eq!(rcv.recv().unwrap(), 3);
+        assert_eq!(state(&snd.arc).depth(), 0);
+        assert!(!state(&rcv.rcv).is_waiting());
+        assert!(rcv.try_recv().is_err());
+        assert_eq!(state(&snd.arc).depth(), 0);
+
+        let report = &metrics_report().algorithms["depth"]["sf_1_out_0__sf_2_in_0"];
+        assert_eq!(report.messages, 4);
+        assert_eq!(report.max_depth, 3);
+    }
+
+    #[test]
+    fn metered_failures() {
+        let (snd, rcv) = metered_channel::<i32>("failures", "metered_failures");
+        drop(rcv);
+        match snd.dispatch(1) {
+            Err(RunError::SendFailed { arc }) => assert_eq!(arc, Some("metered_failures")),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(state(&snd.arc).depth(), 0);
+        assert_eq!(
+            metrics_report().algorithms["failures"]["metered_failures"].messages,
+            0
+        );
+
+        let (snd, rcv) = metered_channel::<i32>("failures", "metered_failures");
+        drop(snd);
+        match rcv.recv() {
+            Err(RunError::RecvFailed { arc }) => assert_eq!(arc, Some("metered_failures")),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(!state(&rcv.rcv).is_waiting());
+    }
+
+    #[test]
+    fn metered_arcs_of_a_name_are_summed_up() {
+        for messages in 1..4 {
+            let (snd, rcv) = metered_channel("sum", "metered_sum");
+            for i in 0..messages {
+                snd.dispatch(i).unwrap();
+            }
+            while rcv.try_recv().is_ok() {}
+        }
+
+        let report = &metrics_report().algorithms["sum"]["metered_sum"];
+        assert_eq!(report.messages, 6);
+        assert_eq!(report.max_depth, 3);
+        // one entry per name rather than per arc
+        assert_eq!(
+            METRICS.lock().unwrap()[&("sum", "metered_sum")]
+                .messages
+                .load(Ordering::SeqCst),
+            6
+        );
+    }
+
+    #[test]
+    fn algorithms_are_reported_apart() {
+        for (algorithm, messages) in [("apart::fst", 1), ("apart::snd", 2)] {
+            let (snd, rcv) = metered_channel(algorithm, "sf_1_out_0__sf_2_in_0");
+            for i in 0..messages {
+                snd.dispatch(i).unwrap();
+            }
+            while rcv.try_recv().is_ok() {}
+        }
+
+        let report = metrics_report();
+        assert_eq!(
+            report.algorithms["apart::fst"]["sf_1_out_0__sf_2_in_0"].messages,
+            1
+        );
+        assert_eq!(
+            report.algorithms["apart::snd"]["sf_1_out_0__sf_2_in_0"].messages,
+            2
+        );
+    }
+
+    #[test]
+    fn named_arcs_are_not_watched() {
+        let (snd, rcv) = named_channel::<i32>("unwatched");
+        snd.dispatch(1).unwrap();
+        assert!(snd.state.is_none() && rcv.state.is_none());
+        assert_eq!(rcv.recv().unwrap(), 1);
+        drop(snd);
+        match rcv.recv() {
+            Err(RunError::RecvFailed { arc }) => assert_eq!(arc, Some("unwatched")),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(take_run_arcs().is_empty());
+    }
+
+    #[test]
+    fn disconnected_arcs_stop_waiting() {
+        let (_snd, rcv) = watched_channel::<i32>("disconnected");
+        let state = take_run_arcs().pop().unwrap();
+        let waiting = thread::spawn(move || rcv.recv());
+        while !state.is_waiting() {
+            thread::yield_now();
+        }
+        state.disconnect();
+        match waiting.join().unwrap() {
+            Err(RunError::RecvFailed { arc }) => assert_eq!(arc, Some("disconnected")),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(!state.is_waiting());
+    }
+
+    #[test]
+    fn run_arcs() {
+        let _fst = metered_channel::<i32>("run_arcs", "run_arcs_fst");
+        let _snd = metered_channel::<i32>("run_arcs", "run_arcs_snd");
+        let names: Vec<&str> = take_run_arcs().iter().map(|a| a.name()).collect();
+        assert_eq!(names, vec!["run_arcs_fst", "run_arcs_snd"]);
+        assert!(take_run_arcs().is_empty());
+    }
+
+    #[test]
+    fn report_format() {
+        let mut report = MetricsReport::default();
+        report.algorithms.entry("algos::sum").or_default().insert(
+            "sf_1_out_0__sf_2_in_0",
+            ArcReport {
+                messages: 12,
+                max_depth: 3,
+                dispatch_blocked: Duration::from_micros(1500),
+                recv_blocked: Duration::from_millis(20),
+            },
+        );
+
+        let lines: Vec<String> = report.to_string().lines().map(str::to_string).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "arc                                        messages  max depth  dispatch (ms)      recv (ms)",
+                "algos::sum",
+                "  sf_1_out_0__sf_2_in_0                          12          3          1.500         20.000",
+            ]
+        );
+    }
+}
@@ -0,0 +1,252 @@
+//! Aborting algorithms that are running.
+//!
+//! An algorithm invoked via `#[ohua(cancel = token)]` or `#[ohua(timeout_ms = ...)]` uses
+//! `Cancellable` arcs only. Once the run is cancelled or timed out, every operator stops at its
+//! next `recv` or `dispatch` and the algorithm evaluates to the respective `RunError`.
+use crate::arcs::{ArcInput, ArcOutput, RecvAll};
+use crate::RunError;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{RecvTimeoutError, TryRecvError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How often a waiting operator checks whether its run has been cancelled.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A handle to abort running algorithms.
+///
+/// Clones share their state, so a clone kept by the caller cancels all runs that use the token.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// Aborts all runs using this token or one of its clones.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// A clone of this token that additionally times out after `timeout` has passed.
+    pub fn with_timeout(&self, timeout: Duration) -> CancellationToken {
+        CancellationToken {
+            cancelled: Arc::clone(&self.cancelled),
+            deadline: Some(Instant::now() + timeout),
+        }
+    }
+
+    /// The error a run using this token has to stop with, if any.
+    pub fn error(&self) -> Option<RunError> {
+        if self.cancelled.load(Ordering::SeqCst) {
+            Some(RunError::Cancelled)
+        } else if self.deadline.is_some_and(|d| Instant::now() >= d) {
+            Some(RunError::TimedOut)
+        } else {
+            None
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.error().is_some()
+    }
+}
+
+/// An arc that refuses to deliver anything once its token is cancelled.
+///
+/// Both ends of an arc are wrapped. A receiver blocked in `recv` wakes up regularly to check the
+/// token. Once the run is cancelled, sending and receiving fail with `RunError::Cancelled` or
+/// `RunError::TimedOut`.
+pub struct Cancellable<A> {
+    arc: A,
+    token: CancellationToken,
+}
+
+impl<T, A: ArcInput<T>> ArcInput<T> for Cancellable<A> {
+    fn dispatch(&self, t: T) -> Result<(), RunError> {
+        if let Some(error) = self.token.error() {
+            return Err(error);
+        }
+        self.arc.dispatch(t)
+    }
+}
+
+impl<T, A: ArcOutput<T>> ArcOutput<T> for Cancellable<A> {
+    fn recv(&self) -> Result<T, RunError> {
+        loop {
+            match self.recv_timeout(POLL_INTERVAL) {
+                Ok(t) => return Ok(t),
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => {
+                    return match self.token.error() {
+                        Some(error) => Err(error),
+                        // the arc is closed, so this fails right away with the arc's own error
+                        None => self.arc.recv(),
+                    };
+                }
+            }
+        }
+    }
+
+    fn try_recv(&self) -> Result<T, TryRecvError> {
+        if self.token.is_cancelled() {
+            return Err(TryRecvError::Disconnected);
+        }
+        self.arc.try_recv()
+    }
+
+    fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.token.is_cancelled() {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            match self.arc.recv_timeout(POLL_INTERVAL.min(deadline - now)) {
+                Err(RecvTimeoutError::Timeout) => (),
+                res => return res,
+            }
+        }
+    }
+}
+
+/// Makes both ends of `channel` stop delivering once `token` is cancelled.
+pub fn cancellable<S, R>(
+    token: &CancellationToken,
+    channel: (S, R),
+) -> (Cancellable<S>, Cancellable<R>) {
+    let (snd, rcv) = channel;
+    (
+        Cancellable {
+            arc: snd,
+            token: token.clone(),
+        },
+        Cancellable {
+            arc: rcv,
+            token: token.clone(),
+        },
+    )
+}
+
+/// Fetches the result of a finished run, or the reason why there is none.
+///
+/// A result that was computed before the run got cancelled is still handed out. All tasks of the
+/// run are done, so receiving the result does not block.
+pub fn finish<R, O: RecvAll<R>>(token: &CancellationToken, results: &O) -> Result<R, RunError> {
+    results
+        .recv_all()
+        .map_err(|_| token.error().unwrap_or(RunError::RecvFailed { arc: None }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    #[test]
+    fn token() {
+        let token = CancellationToken::new();
+        let clone = token.clone();
+        let timed = token.with_timeout(Duration::from_secs(3600));
+        assert!(token.error().is_none());
+        assert!(!timed.is_cancelled());
+
+        clone.cancel();
+        assert!(matches!(token.error(), Some(RunError::Cancelled)));
+        assert!(matches!(timed.error(), Some(RunError::Cancelled)));
+    }
+
+    #[test]
+    fn deadline() {
+        let token = CancellationToken::new();
+        let timed = token.with_timeout(Duration::from_millis(0));
+        assert!(matches!(timed.error(), Some(RunError::TimedOut)));
+        // the timeout only applies to the clone
+        assert!(token.error().is_none());
+
+        token.cancel();
+        assert!(matches!(timed.error(), Some(RunError::Cancelled)));
+    }
+
+    #[test]
+    fn recv_is_cancelled() {
+        let token = CancellationToken::new();
+        let (_snd, rcv) = cancellable(&token, channel::<i32>());
+        let canceller = token.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(30));
+            canceller.cancel();
+        });
+
+        assert!(matches!(rcv.recv(), Err(RunError::Cancelled)));
+    }
+
+    #[test]
+    fn recv_times_out() {
+        let token = CancellationToken::new().with_timeout(Duration::from_millis(30));
+        let (_snd, rcv) = cancellable(&token, channel::<i32>());
+
+        assert!(matches!(rcv.recv(), Err(RunError::TimedOut)));
+    }
+
+    #[test]
+    fn cancelled_arcs_deliver_nothing() {
+        let token = CancellationToken::new();
+        let (snd, rcv) = cancellable(&token, channel());
+        snd.dispatch(1).unwrap();
+        snd.dispatch(2).unwrap();
+        assert_eq!(rcv.try_recv(), Ok(1));
+
+        token.cancel();
+        assert!(matches!(snd.dispatch(3), Err(RunError::Cancelled)));
+        assert_eq!(rcv.try_recv(), Err(TryRecvError::Disconnected));
+        assert_eq!(
+            rcv.recv_timeout(Duration::from_secs(1)),
+            Err(RecvTimeoutError::Disconnected)
+        );
+    }
+
+    #[test]
+    fn closed_arcs_fail_as_usual() {
+        let token = CancellationToken::new();
+        let (snd, rcv) = cancellable(&token, channel::<i32>());
+        drop(snd);
+
+        assert!(matches!(rcv.recv(), Err(RunError::RecvFailed { .. })));
+    }
+
+    #[test]
+    fn finish_hands_out_computed_results() {
+        // the tasks of a finished run have dropped their arcs
+        let token = CancellationToken::new();
+        let (_, results) = channel::<i32>();
+        assert!(matches!(
+            finish(&token, &results),
+            Err(RunError::RecvFailed { .. })
+        ));
+
+        let (results_snd, results) = channel();
+        results_snd.send(42).unwrap();
+        drop(results_snd);
+        token.cancel();
+        assert!(matches!(finish(&token, &results), Ok(42)));
+        assert!(matches!(finish(&token, &results), Err(RunError::Cancelled)));
+
+        // an algorithm returning a tuple hands out its components via separate arcs
+        let (fst_snd, fst) = channel();
+        let (snd_snd, snd) = channel();
+        fst_snd.send(1).unwrap();
+        snd_snd.send("two").unwrap();
+        drop((fst_snd, snd_snd));
+        assert!(matches!(finish(&token, &(fst, snd)), Ok((1, "two"))));
+    }
+}
@@ -0,0 +1,164 @@
+//! Algorithms that are set up once and run many times.
+use crate::arcs::{self, RecvAll};
+use crate::{join_tasks, spawn_tasks, RunError};
+use std::marker::PhantomData;
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+
+/// An algorithm whose operators stay alive between runs.
+///
+/// Instances are created via `#[ohua(instance)]`. Setting up the arcs and spawning the worker
+/// threads happens only once, every run merely hands its arguments to the running graph.
+/// The arguments of a run are passed as a tuple, e.g., `(a, b)` or `(a,)`.
+///
+/// The results are received from `O`, a tuple of receivers for an algorithm returning a tuple.
+///
+/// A failing operator stops the instance. The failure is returned by `next` once the results
+/// of the runs before have been retrieved, after which the instance refuses further runs.
+///
+/// Dropping the instance shuts the algorithm down and waits for all workers to finish.
+pub struct AlgorithmInstance<A, R, O = Receiver<R>> {
+    args: Mutex<Option<Sender<A>>>,
+    results: O,
+    handles: Mutex<Vec<JoinHandle<Result<(), RunError>>>>,
+    values: PhantomData<fn() -> R>,
+}
+
+impl<A: Send, R, O: RecvAll<R>> AlgorithmInstance<A, R, O> {
+    pub fn new(
+        args: Sender<A>,
+        results: O,
+        tasks: Vec<Box<dyn FnOnce() -> Result<(), RunError> + Send + 'static>>,
+    ) -> AlgorithmInstance<A, R, O> {
+        // an idle instance waits for arguments, which is not a deadlock, so it is not watched
+        arcs::take_run_arcs();
+
+        AlgorithmInstance {
+            args: Mutex::new(Some(args)),
+            results,
+            handles: Mutex::new(spawn_tasks(tasks)),
+            values: PhantomData,
+        }
+    }
+
+    /// Runs the algorithm on `args` and waits for the result.
+    pub fn run(&self, args: A) -> Result<R, RunError> {
+        self.feed(args)?;
+        self.next()
+    }
+
+    /// Hands the arguments of a run to the algorithm without waiting for its result.
+    ///
+    /// Runs are pipelined through the graph, their results are retrieved in order via `next`.
+    pub fn feed(&self, args: A) -> Result<(), RunError> {
+        match *self.args.lock().unwrap() {
+            Some(ref snd) => Ok(snd.send(args)?),
+            None => Err(RunError::SendFailed { arc: None }),
+        }
+    }
+
+    /// Waits for the result of the oldest run whose result has not been retrieved yet.
+    ///
+    /// If the results end because an operator failed, the instance is shut down and the failure
+    /// of the operator is returned.
+    pub fn next(&self) -> Result<R, RunError> {
+        match self.results.recv_all() {
+            Ok(result) => Ok(result),
+            Err(error) => {
+                self.shut_down()?;
+                Err(error)
+            }
+        }
+    }
+
+    /// Closes the argument arc, which makes the operators shut down one after another, and
+    /// waits for them to finish. Returns the first failure of an operator, if any.
+    fn shut_down(&self) -> Result<(), RunError> {
+        self.args.lock().unwrap().take();
+        join_tasks(self.handles.lock().unwrap().split_off(0))
+    }
+}
+
+impl<A, R, O> Drop for AlgorithmInstance<A, R, O> {
+    fn drop(&mut self) {
+        // a failure that was not retrieved via `next` does not concern anyone anymore
+        self.args.get_mut().unwrap().take();
+        let _ = join_tasks(self.handles.get_mut().unwrap().split_off(0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arcs::ArcInput;
+    use crate::task;
+    use std::sync::mpsc::channel;
+
+    /// An instance that halves even numbers and fails on odd ones.
+    fn halve() -> AlgorithmInstance<(i32,), i32> {
+        let (args_snd, args) = channel();
+        let (result_snd, results) = channel();
+        let tasks = vec![task(1, "halve", move || loop {
+            let (n,): (i32,) = args.recv()?;
+            if n % 2 != 0 {
+                return Err(RunError::failed(format!("{} is odd", n)));
+            }
+            result_snd.dispatch(n / 2)?;
+        })];
+        AlgorithmInstance::new(args_snd, results, tasks)
+    }
+
+    #[test]
+    fn runs() {
+        let instance = halve();
+        assert_eq!(instance.run((4,)).unwrap(), 2);
+        assert_eq!(instance.run((10,)).unwrap(), 5);
+
+        instance.feed((2,)).unwrap();
+        instance.feed((6,)).unwrap();
+        assert_eq!(instance.next().unwrap(), 1);
+        assert_eq!(instance.next().unwrap(), 3);
+    }
+
+    #[test]
+    fn tuple_results() {
+        let (args_snd, args) = channel();
+        let (half_snd, halves) = channel();
+        let (rest_snd, rests) = channel();
+        let tasks = vec![task(1, "divide", move || loop {
+            let (n,): (i32,) = args.recv()?;
+            half_snd.dispatch(n / 2)?;
+            rest_snd.dispatch(n % 2)?;
+        })];
+        let instance = AlgorithmInstance::new(args_snd, (halves, rests), tasks);
+
+        assert_eq!(instance.run((7,)).unwrap(), (3, 1));
+        assert_eq!(instance.run((8,)).unwrap(), (4, 0));
+    }
+
+    #[test]
+    fn failures_are_returned() {
+        let instance = halve();
+        instance.feed((8,)).unwrap();
+        instance.feed((3,)).unwrap();
+
+        // the result of the run before the failure is still handed out
+        assert_eq!(instance.next().unwrap(), 4);
+        match instance.next() {
+            Err(RunError::InOperator {
+                op_id: 1,
+                op_name: "halve",
+                error,
+            }) => assert_eq!(error.to_string(), "3 is odd"),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // the instance is shut down
+        assert!(matches!(
+            instance.feed((2,)),
+            Err(RunError::SendFailed { .. })
+        ));
+        assert!(matches!(instance.next(), Err(RunError::RecvFailed { .. })));
+    }
+}
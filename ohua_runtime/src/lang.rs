@@ -1,15 +1,73 @@
-use crate::arcs::ArcInput;
+use crate::arcs::{ArcInput, ArcOutput, DispatchAll, RecvAll};
 use crate::RunError;
 use std::any::Any;
+use std::convert::TryFrom;
 use std::iter::Iterator;
 use std::sync::mpsc::channel;
 use std::sync::mpsc::Receiver;
 
+/// A control token, steering how often a `ctrl` operator hands out its captured values.
+///
+/// Operators that open a new context (`smapFun`, `ifFun`, `seqFun`, ...) send these tokens
+/// to the `ctrl` operators of that context. On receipt of a token, a `ctrl` forwards its
+/// current values `count` times. If the token asks to renew, the `ctrl` fetches fresh
+/// values before serving the next token, otherwise the current values are kept.
+///
+/// Tokens can only be built via the constructors below, so a token never carries an invalid
+/// count.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "record", derive(serde::Serialize, serde::Deserialize))]
+pub struct CtrlToken {
+    renew_next_time: bool,
+    count: usize,
+}
+
+impl CtrlToken {
+    /// Forward the current values `count` times, then fetch fresh values for the next token.
+    pub fn renew(count: usize) -> CtrlToken {
+        CtrlToken {
+            renew_next_time: true,
+            count,
+        }
+    }
+
+    /// Forward the current values `count` times and keep them for the next token.
+    pub fn keep(count: usize) -> CtrlToken {
+        CtrlToken {
+            renew_next_time: false,
+            count,
+        }
+    }
+
+    /// Forward the current values exactly once, e.g., for the branch that was taken.
+    pub fn once() -> CtrlToken {
+        CtrlToken::renew(1)
+    }
+
+    /// Do not forward the current values at all, e.g., for the branch that was not taken.
+    pub fn skip() -> CtrlToken {
+        CtrlToken::renew(0)
+    }
+
+    /// Marks the end of a context, no values are forwarded and none are fetched anymore.
+    pub fn done() -> CtrlToken {
+        CtrlToken::keep(0)
+    }
+
+    pub fn renew_next_time(&self) -> bool {
+        self.renew_next_time
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+}
+
 #[allow(non_snake_case)]
 pub fn smapFun<T: Any + 'static + Send, S: IntoIterator<Item = T> + 'static + Send>(
-    inp: &Receiver<S>,
+    inp: &dyn ArcOutput<S>,
     data_out: &dyn ArcInput<T>,
-    ctrl_out: &dyn ArcInput<(bool, isize)>,
+    ctrl_out: &dyn ArcInput<CtrlToken>,
     collect_out: &dyn ArcInput<usize>,
 ) -> Result<(), RunError> {
     let data = inp.recv()?.into_iter();
@@ -18,7 +76,7 @@ pub fn smapFun<T: Any + 'static + Send, S: IntoIterator<Item = T> + 'static + Se
         Some(s) => {
             // known size
             collect_out.dispatch(s)?;
-            ctrl_out.dispatch((true, s as isize))?;
+            ctrl_out.dispatch(CtrlToken::renew(s))?;
             for d in data {
                 data_out.dispatch(d)?;
             }
@@ -28,19 +86,19 @@ pub fn smapFun<T: Any + 'static + Send, S: IntoIterator<Item = T> + 'static + Se
             let mut size = 0;
             for d in data {
                 data_out.dispatch(d)?;
-                ctrl_out.dispatch((false, 1))?;
+                ctrl_out.dispatch(CtrlToken::keep(1))?;
                 size = size + 1;
             }
             collect_out.dispatch(size)?;
-            ctrl_out.dispatch((true, 0))?;
+            ctrl_out.dispatch(CtrlToken::renew(0))?;
         }
     }
     Ok(())
 }
 
 pub fn collect<T: Send>(
-    n: &Receiver<usize>,
-    data: &Receiver<T>,
+    n: &dyn ArcOutput<usize>,
+    data: &dyn ArcOutput<T>,
     out: &dyn ArcInput<Vec<T>>,
 ) -> Result<(), RunError> {
     let num = n.recv()?;
@@ -52,10 +110,151 @@ pub fn collect<T: Send>(
     Ok(())
 }
 
+/// Folds the items of a `smapFun` loop into an accumulator as they arrive.
+///
+/// This is the streaming counterpart to `collect` followed by `reduce`: instead of
+/// buffering the whole `Vec`, only the accumulator is kept around. The code generator
+/// introduces this operator whenever the result of a `collect` is only consumed by `reduce`.
+///
+/// Note that `init` and `f` are received only once and reused for every reduction,
+/// hence they have to be loop invariant (which literals and function references are).
+pub fn fold<T: Send, A: Clone + Send, F: Fn(A, T) -> A>(
+    n: &dyn ArcOutput<usize>,
+    data: &dyn ArcOutput<T>,
+    init: &dyn ArcOutput<A>,
+    f: &dyn ArcOutput<F>,
+    out: &dyn ArcInput<A>,
+) -> Result<(), RunError> {
+    let init = init.recv()?;
+    let f = f.recv()?;
+    loop {
+        let num = n.recv()?;
+        let mut acc = init.clone();
+        for _x in 0..num {
+            acc = f(acc, data.recv()?);
+        }
+        out.dispatch(acc)?;
+    }
+}
+
+/// Reduces a collection into an accumulator using `f`, starting with `init`.
+pub fn reduce<T, A, F, I>(items: I, init: A, f: F) -> A
+where
+    I: IntoIterator<Item = T>,
+    F: Fn(A, T) -> A,
+{
+    items.into_iter().fold(init, f)
+}
+
+/// Like `collect`, but only keeps the `Some` results of a loop body. Introduced by the
+/// code generator whenever the result of a `collect` is only consumed by `somes`.
+pub fn collect_some<T: Send>(
+    n: &dyn ArcOutput<usize>,
+    data: &dyn ArcOutput<Option<T>>,
+    out: &dyn ArcInput<Vec<T>>,
+) -> Result<(), RunError> {
+    let num = n.recv()?;
+    let mut buffered = Vec::new();
+    for _x in 0..num {
+        if let Some(d) = data.recv()? {
+            buffered.push(d);
+        }
+    }
+    out.dispatch(buffered)?;
+    Ok(())
+}
+
+/// Drops all `None` values from a collection.
+pub fn somes<T, I: IntoIterator<Item = Option<T>>>(items: I) -> Vec<T> {
+    items.into_iter().flatten().collect()
+}
+
+/// The result of a single loop iteration in a loop that may terminate early.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "record", derive(serde::Serialize, serde::Deserialize))]
+pub enum Step<T> {
+    /// Keep the value as part of the loop result.
+    Emit(T),
+    /// Drop the value from the loop result.
+    Skip,
+    /// Stop the loop. Neither this nor any subsequent value is part of the loop result.
+    Break,
+}
+
+/// Variant of `smapFun` for loops that may be stopped early via `Step::Break`.
+///
+/// Items are handed out generator-style and every item is announced to `collect_break`
+/// individually (`true`), followed by a final `false` once no more items are sent. A
+/// `true` on the `stop` arc makes the operator stop pulling from the iterator, a `false`
+/// marks that `collect_break` has finished the current loop. The operator waits for the
+/// latter before it accepts its next input, so no stop request leaks into the next loop.
+#[allow(non_snake_case)]
+pub fn smapBreakFun<T: Any + 'static + Send, S: IntoIterator<Item = T> + 'static + Send>(
+    inp: &dyn ArcOutput<S>,
+    stop: &dyn ArcOutput<bool>,
+    data_out: &dyn ArcInput<T>,
+    ctrl_out: &dyn ArcInput<CtrlToken>,
+    collect_out: &dyn ArcInput<bool>,
+) -> Result<(), RunError> {
+    for d in inp.recv()? {
+        if let Ok(true) = stop.try_recv() {
+            break;
+        }
+        collect_out.dispatch(true)?;
+        data_out.dispatch(d)?;
+        ctrl_out.dispatch(CtrlToken::keep(1))?;
+    }
+    collect_out.dispatch(false)?;
+    ctrl_out.dispatch(CtrlToken::renew(0))?;
+
+    // wait for the collector, ignoring a stop request that came too late
+    while stop.recv()? {}
+    Ok(())
+}
+
+/// Counterpart of `smapBreakFun` that collects all `Step::Emit` values up to the first
+/// `Step::Break`. Items that were already underway when the loop was stopped are
+/// received and discarded.
+pub fn collect_break<T: Send>(
+    more: &dyn ArcOutput<bool>,
+    data: &dyn ArcOutput<Step<T>>,
+    stop_out: &dyn ArcInput<bool>,
+    out: &dyn ArcInput<Vec<T>>,
+) -> Result<(), RunError> {
+    let mut buffered = Vec::new();
+    let mut stopped = false;
+    while more.recv()? {
+        match data.recv()? {
+            _ if stopped => (),
+            Step::Emit(d) => buffered.push(d),
+            Step::Skip => (),
+            Step::Break => {
+                stopped = true;
+                stop_out.dispatch(true)?;
+            }
+        }
+    }
+    stop_out.dispatch(false)?;
+    out.dispatch(buffered)?;
+    Ok(())
+}
+
+/// Collects all `Step::Emit` values up to the first `Step::Break`.
+pub fn until_break<T, I: IntoIterator<Item = Step<T>>>(items: I) -> Vec<T> {
+    items
+        .into_iter()
+        .take_while(|s| !matches!(s, Step::Break))
+        .filter_map(|s| match s {
+            Step::Emit(d) => Some(d),
+            _ => None,
+        })
+        .collect()
+}
+
 pub fn select<T: Send>(
-    decision: &Receiver<bool>,
-    true_branch: &Receiver<T>,
-    else_branch: &Receiver<T>,
+    decision: &dyn ArcOutput<bool>,
+    true_branch: &dyn ArcOutput<T>,
+    else_branch: &dyn ArcOutput<T>,
     out: &dyn ArcInput<T>,
 ) -> Result<(), RunError> {
     let branch = if decision.recv()? {
@@ -67,39 +266,272 @@ pub fn select<T: Send>(
     Ok(())
 }
 
+/// Variant of `select` for conditionals where a branch computes its result regardless
+/// of the decision. The result of such a branch is received and discarded whenever the
+/// other branch is taken, so that stale values do not pile up and are never mistaken
+/// for the result of a later decision.
+///
+/// `drain` is a bit mask of the branches to drain (1: true branch, 2: else branch). It is
+/// provided by the code generator and received only once.
+pub fn select_drain<T: Send>(
+    decision: &dyn ArcOutput<bool>,
+    true_branch: &dyn ArcOutput<T>,
+    else_branch: &dyn ArcOutput<T>,
+    drain: &dyn ArcOutput<i32>,
+    out: &dyn ArcInput<T>,
+) -> Result<(), RunError> {
+    let drain = drain.recv()?;
+    loop {
+        let (taken, untaken, drain_untaken) = if decision.recv()? {
+            (true_branch, else_branch, drain & 2 != 0)
+        } else {
+            (else_branch, true_branch, drain & 1 != 0)
+        };
+        out.dispatch(taken.recv()?)?;
+        if drain_untaken {
+            let _ = untaken.recv()?;
+        }
+    }
+}
+
 // this does not need destructuring operators.
 #[allow(non_snake_case)]
 pub fn ifFun(
-    cond: &Receiver<bool>,
-    ctrl_true: &dyn ArcInput<(bool, isize)>,
-    ctrl_false: &dyn ArcInput<(bool, isize)>,
+    cond: &dyn ArcOutput<bool>,
+    ctrl_true: &dyn ArcInput<CtrlToken>,
+    ctrl_false: &dyn ArcInput<CtrlToken>,
 ) -> Result<(), RunError> {
     if cond.recv()? {
-        ctrl_true.dispatch((true, 1))?;
-        ctrl_false.dispatch((true, 0))?;
+        ctrl_true.dispatch(CtrlToken::once())?;
+        ctrl_false.dispatch(CtrlToken::skip())?;
     } else {
-        ctrl_true.dispatch((true, 0))?;
-        ctrl_false.dispatch((true, 1))?;
+        ctrl_true.dispatch(CtrlToken::skip())?;
+        ctrl_false.dispatch(CtrlToken::once())?;
     }
     Ok(())
 }
 
+/// Maps a value to the index of the arm of a `switchFun` that is to be taken.
+///
+/// Implement this for enums used as condition in multi-way branches.
+pub trait Discriminant {
+    /// The index of the arm, `None` if the value does not denote any arm, e.g., a negative number.
+    fn arm(&self) -> Option<usize>;
+}
+
+impl Discriminant for bool {
+    /// `true` takes the first arm, `false` the second, just like `ifFun`.
+    fn arm(&self) -> Option<usize> {
+        Some(if *self { 0 } else { 1 })
+    }
+}
+
+macro_rules! impl_discriminant {
+    ($($t:ty),*) => {
+        $(
+            impl Discriminant for $t {
+                fn arm(&self) -> Option<usize> {
+                    usize::try_from(*self).ok()
+                }
+            }
+        )*
+    };
+}
+
+impl_discriminant!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+/// The arm denoted by `discriminant`, provided that it is one of the `arms` arms of the switch.
+fn checked_arm<D: Discriminant>(discriminant: D, arms: usize) -> Result<usize, RunError> {
+    match discriminant.arm() {
+        Some(arm) if arm < arms => Ok(arm),
+        arm => Err(RunError::InvalidDiscriminant { arm, arms }),
+    }
+}
+
+/// Multi-way counterpart of `ifFun`. Sends `CtrlToken::once` to the arm selected by the
+/// discriminant and `CtrlToken::skip` to all others. A discriminant that does not denote one of
+/// the arms stops the operator with a `RunError::InvalidDiscriminant`.
+///
+/// `ohuac` does not emit multi-way branches yet. The graph of such a branch is shown by the
+/// hand-written `conditionals/algorithms/switch_test.ohuao` fixture of the testcases.
+#[allow(non_snake_case)]
+pub fn switchFun<D: Discriminant>(
+    cond: &dyn ArcOutput<D>,
+    ctrls: &[&dyn ArcInput<CtrlToken>],
+) -> Result<(), RunError> {
+    let arm = checked_arm(cond.recv()?, ctrls.len())?;
+    for (idx, ctrl) in ctrls.iter().enumerate() {
+        ctrl.dispatch(if idx == arm {
+            CtrlToken::once()
+        } else {
+            CtrlToken::skip()
+        })?;
+    }
+    Ok(())
+}
+
+/// Multi-way counterpart of `select`. Forwards the result of the arm chosen by `switchFun`.
+pub fn select_n<D: Discriminant, T: Send>(
+    decision: &dyn ArcOutput<D>,
+    arms: &[&dyn ArcOutput<T>],
+    out: &dyn ArcInput<T>,
+) -> Result<(), RunError> {
+    let arm = checked_arm(decision.recv()?, arms.len())?;
+    out.dispatch(arms[arm].recv()?)?;
+    Ok(())
+}
+
 pub fn id<T>(data: T) -> T {
     data
 }
 
+/// Builds a tuple that is not returned by the algorithm. The code generator hands in the
+/// components as a tuple already.
+pub fn tuple<T>(components: T) -> T {
+    components
+}
+
 #[allow(non_snake_case)]
-pub fn seqFun<T: Send>(_: T) -> (bool, isize) {
-    (true, 1)
+pub fn seqFun<T: Send>(_: T) -> CtrlToken {
+    CtrlToken::once()
 }
 
+/// Hands out the values captured from `vars_in` as requested by the incoming control tokens.
+///
+/// `vars_in` is a tuple of receivers and `vars_out` a tuple of arcs of the same arity, so the
+/// code generator only has to bundle the arcs of a `ctrl` in the graph instead of generating
+/// a separate operator for every arity. Contrary to most operators, `ctrl` is called only
+/// once and loops until one of its arcs is closed.
+pub fn ctrl<V, R, D>(
+    ctrl_inp: &dyn ArcOutput<CtrlToken>,
+    vars_in: &R,
+    vars_out: &D,
+) -> Result<(), RunError>
+where
+    V: Clone,
+    R: RecvAll<V>,
+    D: DispatchAll<V>,
+{
+    let mut renew = false;
+    let mut state = vars_in.recv_all()?;
+
+    loop {
+        let token = ctrl_inp.recv()?;
+        if renew {
+            state = vars_in.recv_all()?;
+        }
+
+        for _ in 0..token.count() {
+            vars_out.dispatch_all(state.clone())?;
+        }
+
+        renew = token.renew_next_time();
+    }
+}
+
+/// Entry point of an `AlgorithmInstance`. Hands the arguments of every run to the graph and
+/// triggers all functions that have no other inputs.
+pub fn feed_args<A, D>(
+    args: &Receiver<A>,
+    trigger: &dyn ArcInput<CtrlToken>,
+    vars_out: &D,
+) -> Result<(), RunError>
+where
+    D: DispatchAll<A>,
+{
+    loop {
+        let run_args = args.recv()?;
+        trigger.dispatch(CtrlToken::once())?;
+        vars_out.dispatch_all(run_args)?;
+    }
+}
+
+/// Takes apart every tuple received via `inp`, handing out each element via the arc of the same
+/// index in `outs`. Introduced by the code generator in place of several `nth`s that take apart
+/// the same tuple, which would need a clone of the tuple each.
+///
+/// Like `ctrl`, `destruct` is called only once and loops until one of its arcs is closed.
+pub fn destruct<V, D>(inp: &dyn ArcOutput<V>, outs: &D) -> Result<(), RunError>
+where
+    D: DispatchAll<V>,
+{
+    loop {
+        outs.dispatch_all(inp.recv()?)?;
+    }
+}
+
+/// Access to the `N`th element of a tuple.
+pub trait Nth<const N: usize> {
+    type Output;
+
+    fn nth(self) -> Self::Output;
+}
+
+macro_rules! impl_nth {
+    ($tuple:tt => $($idx:tt: $out:ident),+) => {
+        $(impl_nth!(@impl $tuple, $idx, $out);)+
+    };
+    (@impl ($($t:ident),+), $idx:tt, $out:ident) => {
+        impl<$($t),+> Nth<$idx> for ($($t,)+) {
+            type Output = $out;
+
+            fn nth(self) -> $out {
+                self.$idx
+            }
+        }
+    };
+}
+
+impl_nth!((T0, T1) => 0: T0, 1: T1);
+impl_nth!((T0, T1, T2) => 0: T0, 1: T1, 2: T2);
+impl_nth!((T0, T1, T2, T3) => 0: T0, 1: T1, 2: T2, 3: T3);
+impl_nth!((T0, T1, T2, T3, T4) => 0: T0, 1: T1, 2: T2, 3: T3, 4: T4);
+impl_nth!((T0, T1, T2, T3, T4, T5) => 0: T0, 1: T1, 2: T2, 3: T3, 4: T4, 5: T5);
+impl_nth!((T0, T1, T2, T3, T4, T5, T6) => 0: T0, 1: T1, 2: T2, 3: T3, 4: T4, 5: T5, 6: T6);
+impl_nth!((T0, T1, T2, T3, T4, T5, T6, T7) =>
+    0: T0, 1: T1, 2: T2, 3: T3, 4: T4, 5: T5, 6: T6, 7: T7);
+impl_nth!((T0, T1, T2, T3, T4, T5, T6, T7, T8) =>
+    0: T0, 1: T1, 2: T2, 3: T3, 4: T4, 5: T5, 6: T6, 7: T7, 8: T8);
+impl_nth!((T0, T1, T2, T3, T4, T5, T6, T7, T8, T9) =>
+    0: T0, 1: T1, 2: T2, 3: T3, 4: T4, 5: T5, 6: T6, 7: T7, 8: T8, 9: T9);
+impl_nth!((T0, T1, T2, T3, T4, T5, T6, T7, T8, T9, T10) =>
+    0: T0, 1: T1, 2: T2, 3: T3, 4: T4, 5: T5, 6: T6, 7: T7, 8: T8, 9: T9, 10: T10);
+impl_nth!((T0, T1, T2, T3, T4, T5, T6, T7, T8, T9, T10, T11) =>
+    0: T0, 1: T1, 2: T2, 3: T3, 4: T4, 5: T5, 6: T6, 7: T7, 8: T8, 9: T9, 10: T10, 11: T11);
+
+macro_rules! nth_fns {
+    ($($name:ident: $idx:tt),+) => {
+        $(
+            /// Destructuring operator, extracting a single element from a tuple.
+            pub fn $name<T: Nth<$idx>>(t: T) -> T::Output {
+                t.nth()
+            }
+        )+
+    };
+}
+
+nth_fns!(
+    nth_0: 0,
+    nth_1: 1,
+    nth_2: 2,
+    nth_3: 3,
+    nth_4: 4,
+    nth_5: 5,
+    nth_6: 6,
+    nth_7: 7,
+    nth_8: 8,
+    nth_9: 9,
+    nth_10: 10,
+    nth_11: 11
+);
+
 #[allow(non_snake_case, unused_variables)]
 pub fn recurFun<T: Send, S: Send>(
-    call_actuals_in: &Receiver<T>,
-    recur_cond_in: &Receiver<T>,
-    recur_actuals_in: &Receiver<T>,
-    recur_result_in: &Receiver<S>,
-    ctrl_out: &dyn ArcInput<(bool, isize)>,
+    call_actuals_in: &dyn ArcOutput<T>,
+    recur_cond_in: &dyn ArcOutput<T>,
+    recur_actuals_in: &dyn ArcOutput<T>,
+    recur_result_in: &dyn ArcOutput<S>,
+    ctrl_out: &dyn ArcInput<CtrlToken>,
     recur_formals_out: &dyn ArcInput<T>,
     result_out: &dyn ArcInput<S>,
 ) -> Result<(), RunError> {
@@ -122,6 +554,7 @@ pub fn send_once<T>(t: T) -> Receiver<T> {
 
 // the type
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "record", derive(serde::Serialize, serde::Deserialize))]
 pub struct Unit {}
 
 // a function that wraps around functions that receive a ().
@@ -132,3 +565,104 @@ where
 {
     f()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::Sender;
+
+    fn switch<D: Discriminant>(discriminant: D) -> Result<Vec<usize>, RunError> {
+        let (cond_snd, cond) = channel();
+        let arms: Vec<(Sender<CtrlToken>, Receiver<CtrlToken>)> =
+            (0..3).map(|_| channel()).collect();
+        let ctrls: Vec<&dyn ArcInput<CtrlToken>> = arms
+            .iter()
+            .map(|(snd, _)| snd as &dyn ArcInput<CtrlToken>)
+            .collect();
+
+        cond_snd.send(discriminant).unwrap();
+        switchFun(&cond, &ctrls)?;
+        Ok(arms
+            .iter()
+            .map(|(_, rcv)| rcv.try_recv().unwrap().count())
+            .collect())
+    }
+
+    #[test]
+    fn ctrl_tokens() {
+        let tokens = [
+            (CtrlToken::renew(3), true, 3),
+            (CtrlToken::keep(2), false, 2),
+            (CtrlToken::once(), true, 1),
+            (CtrlToken::skip(), true, 0),
+            (CtrlToken::done(), false, 0),
+        ];
+        for (token, renew_next_time, count) in tokens.iter() {
+            assert_eq!(token.renew_next_time(), *renew_next_time);
+            assert_eq!(token.count(), *count);
+        }
+    }
+
+    #[test]
+    fn ctrl_forwards_the_values_as_requested() {
+        let (token_snd, tokens) = channel();
+        let (value_snd, values) = channel();
+        let (out, forwarded) = channel();
+
+        value_snd.send(1).unwrap();
+        value_snd.send(2).unwrap();
+        drop(value_snd);
+        for token in &[CtrlToken::keep(2), CtrlToken::renew(1), CtrlToken::once()] {
+            token_snd.send(*token).unwrap();
+        }
+        drop(token_snd);
+
+        // the values are renewed only after the token asking for it has been served
+        assert!(ctrl(&tokens, &(values,), &(out,)).is_err());
+        assert_eq!(forwarded.try_iter().collect::<Vec<_>>(), vec![1, 1, 1, 2]);
+    }
+
+    #[test]
+    fn switch_takes_a_single_arm() {
+        assert_eq!(switch(1u8).unwrap(), vec![0, 1, 0]);
+        assert_eq!(switch(2i64).unwrap(), vec![0, 0, 1]);
+        assert_eq!(switch(true).unwrap(), vec![1, 0, 0]);
+
+        match switch(3usize) {
+            Err(RunError::InvalidDiscriminant {
+                arm: Some(3),
+                arms: 3,
+            }) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+        match switch(-1i32) {
+            Err(RunError::InvalidDiscriminant { arm: None, arms: 3 }) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn select_n_forwards_the_chosen_arm() {
+        let (decision_snd, decision) = channel();
+        let arms: Vec<(Sender<&str>, Receiver<&str>)> = (0..2).map(|_| channel()).collect();
+        let inputs: Vec<&dyn ArcOutput<&str>> = arms
+            .iter()
+            .map(|(_, rcv)| rcv as &dyn ArcOutput<&str>)
+            .collect();
+        let (out, result) = channel();
+
+        arms[1].0.send("second").unwrap();
+        decision_snd.send(1i32).unwrap();
+        select_n(&decision, &inputs, &out).unwrap();
+        assert_eq!(result.try_recv(), Ok("second"));
+
+        decision_snd.send(2i32).unwrap();
+        match select_n(&decision, &inputs, &out) {
+            Err(RunError::InvalidDiscriminant {
+                arm: Some(2),
+                arms: 2,
+            }) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
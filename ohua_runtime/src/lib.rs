@@ -1,43 +1,656 @@
-use std::marker::Send;
-use std::sync::mpsc::{RecvError, SendError};
-use std::thread;
+use crate::arcs::ArcOutput;
+use crate::watchdog::Deadlock;
+use std::any::Any;
+use std::error::Error;
+use std::fmt;
+use std::marker::{PhantomData, Send};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{channel, Receiver, RecvError, SendError};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
 
 pub mod arcs;
+pub mod cancel;
+pub mod instance;
 pub mod lang;
+pub mod mock;
+#[cfg(feature = "record")]
+pub mod record;
+pub mod sched;
+pub mod trace;
+pub mod watchdog;
 
 /// Error type representing possible errors when sending or receiving data via arcs.
 #[derive(Debug)]
 pub enum RunError {
-    SendFailed,
-    RecvFailed,
+    /// The receiving end of `arc` has hung up, usually because the operator there has finished.
+    SendFailed { arc: Option<&'static str> },
+    /// The sending end of `arc` has hung up, usually because the operator there has finished.
+    RecvFailed { arc: Option<&'static str> },
+    /// The discriminant of a `switchFun` or `select_n` did not denote one of its `arms` arms.
+    /// `arm` is `None` for values that denote no arm at all, e.g., negative numbers.
+    InvalidDiscriminant { arm: Option<usize>, arms: usize },
+    /// The state handed in by the caller was poisoned by a panic while it was in use.
+    StatePoisoned,
+    /// The run was aborted via its `CancellationToken`.
+    Cancelled,
+    /// The run did not finish within its timeout.
+    TimedOut,
+    /// The operator `op_id` panicked while executing `op_name`, `message` is the panic message.
+    Panicked {
+        op_id: i32,
+        op_name: &'static str,
+        message: String,
+    },
+    /// A stateful function returned an error, see `RunError::failed`.
+    Failed(Box<dyn Error + Send + Sync>),
+    /// The operator `op_id`, executing `op_name`, stopped with `error`.
+    InOperator {
+        op_id: i32,
+        op_name: &'static str,
+        error: Box<RunError>,
+    },
+    /// All live tasks of the run waited for messages that never arrive, see `watchdog`.
+    Deadlock(Deadlock),
+}
+
+impl RunError {
+    /// Wraps an error of a stateful function. Functions declared to return
+    /// `Result<_, RunError>` stop the algorithm with this error when they fail.
+    pub fn failed<E: Into<Box<dyn Error + Send + Sync>>>(error: E) -> RunError {
+        RunError::Failed(error.into())
+    }
+
+    /// Whether the error is part of an algorithm shutting down rather than a failure, i.e., a
+    /// neighbouring operator hung up or the run was aborted.
+    ///
+    /// A hang-up does not tell why the neighbour stopped. If it failed, its own error is the
+    /// failure of the run, so this only classifies the error of a single task. Whether the
+    /// algorithm as a whole succeeded is decided by `run_tasks`, which looks at all tasks.
+    pub fn is_shutdown(&self) -> bool {
+        match self {
+            RunError::SendFailed { .. }
+            | RunError::RecvFailed { .. }
+            | RunError::Cancelled
+            | RunError::TimedOut => true,
+            RunError::InOperator { error, .. } => error.is_shutdown(),
+            _ => false,
+        }
+    }
+
+    /// Names the arc a send or receive failed on, unless it is known already.
+    pub(crate) fn on_arc(self, name: &'static str) -> RunError {
+        match self {
+            RunError::SendFailed { arc: None } => RunError::SendFailed { arc: Some(name) },
+            RunError::RecvFailed { arc: None } => RunError::RecvFailed { arc: Some(name) },
+            other => other,
+        }
+    }
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RunError::SendFailed { arc: Some(arc) } => {
+                write!(f, "the receiver of arc `{}` has hung up", arc)
+            }
+            RunError::SendFailed { arc: None } => write!(f, "the receiver of an arc has hung up"),
+            RunError::RecvFailed { arc: Some(arc) } => {
+                write!(f, "the sender of arc `{}` has hung up", arc)
+            }
+            RunError::RecvFailed { arc: None } => write!(f, "the sender of an arc has hung up"),
+            RunError::InvalidDiscriminant {
+                arm: Some(arm),
+                arms,
+            } => write!(
+                f,
+                "discriminant {} does not denote one of the {} arms of the switch",
+                arm, arms
+            ),
+            RunError::InvalidDiscriminant { arm: None, arms } => write!(
+                f,
+                "the discriminant does not denote one of the {} arms of the switch",
+                arms
+            ),
+            RunError::StatePoisoned => write!(f, "the state was poisoned by a panic"),
+            RunError::Cancelled => write!(f, "the run was cancelled"),
+            RunError::TimedOut => write!(f, "the run timed out"),
+            RunError::Panicked {
+                op_id,
+                op_name,
+                message,
+            } => write!(f, "operator {} ({}) panicked: {}", op_id, op_name, message),
+            RunError::Failed(error) => error.fmt(f),
+            RunError::InOperator {
+                op_id,
+                op_name,
+                error,
+            } => write!(f, "operator {} ({}) failed: {}", op_id, op_name, error),
+            RunError::Deadlock(deadlock) => deadlock.fmt(f),
+        }
+    }
+}
+
+impl Error for RunError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RunError::Failed(error) => Some(&**error),
+            RunError::InOperator { error, .. } => Some(&**error),
+            _ => None,
+        }
+    }
 }
 
 impl<T: Send> From<SendError<T>> for RunError {
     fn from(_error: SendError<T>) -> Self {
-        RunError::SendFailed
+        RunError::SendFailed { arc: None }
+    }
+}
+
+impl<T> From<PoisonError<T>> for RunError {
+    fn from(_error: PoisonError<T>) -> Self {
+        RunError::StatePoisoned
     }
 }
 
 impl From<RecvError> for RunError {
     fn from(_error: RecvError) -> Self {
-        RunError::RecvFailed
+        RunError::RecvFailed { arc: None }
+    }
+}
+
+/// Turns the code of operator `op_id` into a task.
+///
+/// Errors of `f` are wrapped in a `RunError::InOperator`, a panic in `f` is caught and reported
+/// as `RunError::Panicked`. Unwinding drops the arcs of the operator, so its neighbours fail to
+/// send or receive and the rest of the algorithm shuts down.
+pub fn task<F>(
+    op_id: i32,
+    op_name: &'static str,
+    f: F,
+) -> Box<dyn FnOnce() -> Result<(), RunError> + Send + 'static>
+where
+    F: FnOnce() -> Result<(), RunError> + Send + 'static,
+{
+    Box::new(move || match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => result.map_err(|error| RunError::InOperator {
+            op_id,
+            op_name,
+            error: Box::new(error),
+        }),
+        Err(payload) => Err(RunError::Panicked {
+            op_id,
+            op_name,
+            message: panic_message(&*payload),
+        }),
+    })
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "Box<dyn Any>".to_string()
     }
 }
 
 /// Central function to execute an algorithm.
 ///
 /// The algorithm is provided as a set of tasks, each of which is going to be executed in a separate thread.
-pub fn run_tasks(mut tasks: Vec<Box<dyn FnOnce() -> Result<(), RunError> + Send + 'static>>) -> () {
+/// If the algorithm uses watched arcs, a run that got stuck is shut down and reported as
+/// `RunError::Deadlock` instead of waiting forever, see `watchdog`.
+///
+/// Returns the first error among the tasks that is not part of the regular shutdown of an
+/// algorithm, see `RunError::is_shutdown`.
+pub fn run_tasks(
+    tasks: Vec<Box<dyn FnOnce() -> Result<(), RunError> + Send + 'static>>,
+) -> Result<(), RunError> {
+    let arcs = arcs::take_run_arcs();
+    if arcs.is_empty() {
+        return join_tasks(spawn_tasks(tasks));
+    }
+
+    let (finished_snd, finished) = channel();
+    let watched = tasks
+        .into_iter()
+        .map(|task| {
+            let finished = watchdog::Finished(finished_snd.clone());
+            Box::new(move || {
+                let _finished = finished;
+                task()
+            }) as Box<dyn FnOnce() -> Result<(), RunError> + Send + 'static>
+        })
+        .collect();
+    drop(finished_snd);
+
+    let handles = spawn_tasks(watched);
+    let deadlock = watchdog::watch(handles.len(), arcs, finished);
+    let result = join_tasks(handles);
+    match deadlock {
+        Some(deadlock) => Err(RunError::Deadlock(deadlock)),
+        None => result,
+    }
+}
+
+/// Executes an algorithm that runs on a stream of inputs.
+///
+/// The tasks are started in the background and the results are handed out as a stream, too.
+/// The algorithm shuts down once its input stream is exhausted.
+pub fn run_stream<R>(
+    results: Receiver<R>,
+    tasks: Vec<Box<dyn FnOnce() -> Result<(), RunError> + Send + 'static>>,
+) -> ResultStream<R> {
+    ResultStream::new(results, &start_tasks(tasks))
+}
+
+/// Starts the tasks of an algorithm in the background without waiting for them to finish.
+///
+/// The returned `BackgroundTasks` are shared by the `ResultStream`s of the algorithm, which
+/// report the failure of a task once they end.
+pub fn start_tasks(
+    tasks: Vec<Box<dyn FnOnce() -> Result<(), RunError> + Send + 'static>>,
+) -> Arc<BackgroundTasks> {
+    // algorithms running in the background are not watched
+    arcs::take_run_arcs();
+
+    let (failure_snd, failures) = channel();
+    let reporting = tasks
+        .into_iter()
+        .map(|task| {
+            let failure_snd = failure_snd.clone();
+            Box::new(move || match task() {
+                Err(error) if !error.is_shutdown() => {
+                    // a closed stream does not care about the failure anymore
+                    let _ = failure_snd.send(error);
+                    Ok(())
+                }
+                result => result,
+            }) as Box<dyn FnOnce() -> Result<(), RunError> + Send + 'static>
+        })
+        .collect();
+
+    Arc::new(BackgroundTasks {
+        handles: Mutex::new(spawn_tasks(reporting)),
+        failures: Mutex::new(Some(failures)),
+    })
+}
+
+/// The tasks of an algorithm running in the background, see `start_tasks`.
+pub struct BackgroundTasks {
+    handles: Mutex<Vec<JoinHandle<Result<(), RunError>>>>,
+    failures: Mutex<Option<Receiver<RunError>>>,
+}
+
+impl BackgroundTasks {
+    /// Waits for the first failure of a task, or for all tasks to finish.
+    ///
+    /// Tasks waiting for input may not finish before the input stream is exhausted, so a failure
+    /// is reported as soon as it happens rather than after joining all tasks. The failure is
+    /// reported only once, later calls return right away.
+    fn finish(&self) -> Result<(), RunError> {
+        let failures = match self.failures.lock().unwrap().take() {
+            Some(failures) => failures,
+            None => return Ok(()),
+        };
+        match failures.recv() {
+            Ok(error) => Err(error),
+            // all tasks are done, joining them only picks up panics outside of operators
+            Err(_) => join_tasks(self.handles.lock().unwrap().split_off(0)),
+        }
+    }
+}
+
+/// The results of an algorithm running on a stream of inputs, see `run_stream`.
+///
+/// When the results end, the stream checks whether the algorithm finished regularly. A failure
+/// of one of its tasks is raised in the consumer, see `raise`, instead of silently cutting the
+/// stream short. Use `try_next` to receive the failure as an error instead.
+///
+/// An algorithm with several outputs hands out one stream per output, all of which share the
+/// tasks of the algorithm. The failure is reported by the stream that ends first.
+pub struct ResultStream<R, A = Receiver<R>> {
+    results: A,
+    tasks: Arc<BackgroundTasks>,
+    values: PhantomData<fn() -> R>,
+}
+
+impl<R, A: ArcOutput<R>> ResultStream<R, A> {
+    /// Hands out the results that arrive on `results` from the algorithm `tasks`.
+    pub fn new(results: A, tasks: &Arc<BackgroundTasks>) -> ResultStream<R, A> {
+        ResultStream {
+            results,
+            tasks: Arc::clone(tasks),
+            values: PhantomData,
+        }
+    }
+
+    /// Returns the next result, or the failure of the algorithm once the results have ended.
+    pub fn try_next(&mut self) -> Option<Result<R, RunError>> {
+        match self.results.recv() {
+            Ok(result) => Some(Ok(result)),
+            Err(_) => self.tasks.finish().err().map(Err),
+        }
+    }
+}
+
+impl<R, A: ArcOutput<R>> Iterator for ResultStream<R, A> {
+    type Item = R;
+
+    fn next(&mut self) -> Option<R> {
+        match self.try_next() {
+            Some(Ok(result)) => Some(result),
+            Some(Err(error)) => raise(error),
+            None => None,
+        }
+    }
+}
+
+fn spawn_tasks(
+    mut tasks: Vec<Box<dyn FnOnce() -> Result<(), RunError> + Send + 'static>>,
+) -> Vec<JoinHandle<Result<(), RunError>>> {
     let mut handles = Vec::with_capacity(tasks.len());
     for task in tasks.drain(..) {
-        handles.push(thread::spawn(move || {
-            let _ = task();
-        }));
+        handles.push(thread::spawn(task));
+    }
+    handles
+}
+
+fn join_tasks(handles: Vec<JoinHandle<Result<(), RunError>>>) -> Result<(), RunError> {
+    first_failure(handles.into_iter().map(JoinHandle::join))
+}
+
+/// Picks the first error among the `results` of the tasks of an algorithm that is not part of
+/// the regular shutdown, a panic counts as an error, too.
+pub(crate) fn first_failure(
+    results: impl IntoIterator<Item = thread::Result<Result<(), RunError>>>,
+) -> Result<(), RunError> {
+    let mut failure = None;
+    for result in results {
+        let error = match result {
+            Ok(Err(e)) if !e.is_shutdown() => e,
+            Ok(_) => continue,
+            // tasks that are not attributed to an operator
+            Err(payload) => RunError::Panicked {
+                op_id: -1,
+                op_name: "<unknown>",
+                message: panic_message(&*payload),
+            },
+        };
+        failure.get_or_insert(error);
+    }
+    failure.map_or(Ok(()), Err)
+}
+
+/// Passes the failure of a run on to the caller of an algorithm that cannot return it.
+///
+/// The error is reported on stderr and raised as a panic, with the `RunError` as its payload.
+pub fn raise(error: RunError) -> ! {
+    eprintln!("[Error] {}", error);
+    panic::resume_unwind(Box::new(error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display() {
+        let failed = RunError::InOperator {
+            op_id: 3,
+            op_name: "load",
+            error: Box::new(RunError::failed("no such file")),
+        };
+        assert_eq!(failed.to_string(), "operator 3 (load) failed: no such file");
+        assert_eq!(
+            RunError::RecvFailed {
+                arc: Some("sf_1_out_0__sf_2_in_0")
+            }
+            .to_string(),
+            "the sender of arc `sf_1_out_0__sf_2_in_0` has hung up"
+        );
+        assert_eq!(
+            RunError::SendFailed { arc: None }.to_string(),
+            "the receiver of an arc has hung up"
+        );
+        assert_eq!(
+            RunError::InvalidDiscriminant {
+                arm: Some(5),
+                arms: 3
+            }
+            .to_string(),
+            "discriminant 5 does not denote one of the 3 arms of the switch"
+        );
+        assert_eq!(
+            RunError::Panicked {
+                op_id: 2,
+                op_name: "divide",
+                message: "division by zero".to_string()
+            }
+            .to_string(),
+            "operator 2 (divide) panicked: division by zero"
+        );
+        assert_eq!(RunError::TimedOut.to_string(), "the run timed out");
+        assert_eq!(
+            RunError::Deadlock(Deadlock {
+                live_tasks: 2,
+                waiting_on: vec!["sf_1_out_0__sf_2_in_0", "sf_2_out_0__sf_1_in_0"],
+            })
+            .to_string(),
+            "the run is deadlocked, all 2 live tasks are waiting for messages that never arrive\n  waiting on sf_1_out_0__sf_2_in_0\n  waiting on sf_2_out_0__sf_1_in_0"
+        );
+        assert_eq!(failed.source().unwrap().to_string(), "no such file");
+    }
+
+    #[test]
+    fn errors_name_their_arc() {
+        let named = RunError::SendFailed { arc: None }.on_arc("fst");
+        assert!(matches!(named, RunError::SendFailed { arc: Some("fst") }));
+        // the innermost arc is kept
+        let renamed = named.on_arc("snd");
+        assert!(matches!(renamed, RunError::SendFailed { arc: Some("fst") }));
+        let named = RunError::RecvFailed { arc: None }.on_arc("fst");
+        assert!(matches!(named, RunError::RecvFailed { arc: Some("fst") }));
+        assert!(matches!(
+            RunError::Cancelled.on_arc("fst"),
+            RunError::Cancelled
+        ));
+    }
+
+    #[test]
+    fn shutdown_errors() {
+        assert!(RunError::SendFailed { arc: None }.is_shutdown());
+        assert!(RunError::RecvFailed { arc: Some("arc") }.is_shutdown());
+        assert!(RunError::Cancelled.is_shutdown());
+        assert!(RunError::TimedOut.is_shutdown());
+        assert!(RunError::InOperator {
+            op_id: 1,
+            op_name: "id",
+            error: Box::new(RunError::RecvFailed { arc: None }),
+        }
+        .is_shutdown());
+
+        assert!(!RunError::StatePoisoned.is_shutdown());
+        assert!(!RunError::Deadlock(Deadlock {
+            live_tasks: 1,
+            waiting_on: Vec::new(),
+        })
+        .is_shutdown());
+        assert!(!RunError::failed("broken").is_shutdown());
+        assert!(!RunError::InOperator {
+            op_id: 1,
+            op_name: "id",
+            error: Box::new(RunError::failed("broken")),
+        }
+        .is_shutdown());
+        assert!(!RunError::Panicked {
+            op_id: 1,
+            op_name: "id",
+            message: String::new()
+        }
+        .is_shutdown());
     }
 
-    for h in handles {
-        if let Err(_) = h.join() {
-            eprintln!("[Error] A worker thread of an ohua algorithm has panicked!");
+    #[test]
+    fn task_attributes_failures() {
+        let failed = task(3, "parse", || Err(RunError::failed("not a number")))();
+        match failed {
+            Err(RunError::InOperator {
+                op_id: 3,
+                op_name: "parse",
+                error,
+            }) => assert!(matches!(*error, RunError::Failed(_))),
+            other => panic!("unexpected result {:?}", other),
         }
+
+        let panicked = task(4, "divide", || panic!("division by {}", 0))();
+        match panicked {
+            Err(RunError::Panicked {
+                op_id: 4,
+                op_name: "divide",
+                message,
+            }) => assert_eq!(message, "division by 0"),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        assert!(task(5, "noop", || Ok(()))().is_ok());
+    }
+
+    #[test]
+    fn panic_messages() {
+        assert_eq!(panic_message(&"static"), "static");
+        assert_eq!(panic_message(&"owned".to_string()), "owned");
+        assert_eq!(panic_message(&42), "Box<dyn Any>");
+    }
+
+    #[test]
+    fn join_tasks_reports_the_first_failure() {
+        let handles = vec![
+            thread::spawn(|| Ok(())),
+            thread::spawn(|| Err(RunError::RecvFailed { arc: Some("arc") })),
+            thread::spawn(task(2, "lock", || Err(RunError::StatePoisoned))),
+            thread::spawn(|| -> Result<(), RunError> { panic!("unattributed") }),
+        ];
+        assert!(matches!(
+            join_tasks(handles),
+            Err(RunError::InOperator { op_id: 2, .. })
+        ));
+
+        // tasks that are not wrapped by `task` are not attributed to an operator
+        let handles = vec![thread::spawn(|| -> Result<(), RunError> {
+            panic!("unattributed")
+        })];
+        match join_tasks(handles) {
+            Err(RunError::Panicked {
+                op_id: -1,
+                op_name: "<unknown>",
+                message,
+            }) => assert_eq!(message, "unattributed"),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // hang-ups are part of the regular shutdown
+        let handles = vec![
+            thread::spawn(|| Err(RunError::SendFailed { arc: None })),
+            thread::spawn(|| Ok(())),
+        ];
+        assert!(join_tasks(handles).is_ok());
+    }
+
+    #[test]
+    fn panics_shut_the_algorithm_down() {
+        let (snd, rcv) = channel::<i32>();
+        let tasks = vec![
+            task(1, "produce", move || {
+                let _snd = snd;
+                panic!("broken")
+            }),
+            task(2, "consume", move || loop {
+                rcv.recv()?;
+            }),
+        ];
+
+        match run_tasks(tasks) {
+            Err(RunError::Panicked {
+                op_id: 1, message, ..
+            }) => assert_eq!(message, "broken"),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn stream_ends_after_regular_shutdown() {
+        let (result_snd, result_rcv) = channel();
+        let tasks = vec![task(1, "produce", move || {
+            for i in 0..3 {
+                result_snd.send(i)?;
+            }
+            Ok(())
+        })];
+
+        assert_eq!(
+            run_stream(result_rcv, tasks).collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+    }
+
+    #[test]
+    fn stream_reports_failure_once_results_end() {
+        let (result_snd, result_rcv) = channel();
+        // waits for input that never arrives, like the task feeding a stream argument
+        let (_input_snd, input) = channel::<()>();
+        let tasks = vec![
+            task(1, "produce", move || {
+                result_snd.send(1)?;
+                Err(RunError::failed("broken"))
+            }),
+            task(2, "feed", move || Ok(input.recv()?)),
+        ];
+
+        let mut results = run_stream(result_rcv, tasks);
+        assert!(matches!(results.try_next(), Some(Ok(1))));
+        assert!(matches!(
+            results.try_next(),
+            Some(Err(RunError::InOperator { op_id: 1, .. }))
+        ));
+        assert!(results.try_next().is_none());
+    }
+
+    #[test]
+    fn stream_raises_failure() {
+        let (result_snd, result_rcv) = channel::<i32>();
+        let tasks = vec![task(1, "produce", move || {
+            drop(result_snd);
+            panic!("broken")
+        })];
+
+        let mut results = run_stream(result_rcv, tasks);
+        let payload = panic::catch_unwind(AssertUnwindSafe(|| results.next())).unwrap_err();
+        match payload.downcast_ref::<RunError>() {
+            Some(RunError::Panicked {
+                op_id: 1, message, ..
+            }) => assert_eq!(message, "broken"),
+            other => panic!("unexpected payload {:?}", other),
+        }
+    }
+
+    #[test]
+    fn streams_share_failure() {
+        let (fst_snd, fst) = arcs::named_channel::<i32>("fst");
+        let (snd_snd, snd) = arcs::named_channel::<i32>("snd");
+        let tasks = vec![task(1, "produce", move || {
+            drop((fst_snd, snd_snd));
+            Err(RunError::failed("broken"))
+        })];
+
+        let stream_tasks = start_tasks(tasks);
+        let mut fst = ResultStream::new(fst, &stream_tasks);
+        let mut snd = ResultStream::new(snd, &stream_tasks);
+        assert!(matches!(
+            fst.try_next(),
+            Some(Err(RunError::InOperator { .. }))
+        ));
+        assert!(snd.try_next().is_none());
     }
 }
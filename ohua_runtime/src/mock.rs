@@ -0,0 +1,158 @@
+//! Substituting the stateful functions of an algorithm in tests.
+//!
+//! For an algorithm invoked via `#[ohua(mock)]`, the generated code looks up every stateful
+//! function in a registry before the algorithm starts, using the path the function is imported
+//! with in the `.ohuac` file. A function registered under that path via `register` is called
+//! instead of the real one:
+//!
+//! ```ignore
+//! fn fake_fetch(url: String) -> Page {
+//!     Page::empty(url)
+//! }
+//!
+//! let _mock = ohua_runtime::mock::register("crate::foo::fetch", fake_fetch as fn(String) -> Page);
+//!
+//! #[ohua(mock)]
+//! let page = foo::crawl(url);
+//! ```
+//!
+//! Mocks are function pointers of the same type as the function they replace, so closures have
+//! to be cast, which works only for closures that do not capture anything. The registry belongs
+//! to the current thread and is consulted when an algorithm is invoked on that thread, so tests
+//! running in parallel do not see each other's mocks. Methods of state objects cannot be mocked.
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+
+struct Entry {
+    id: u64,
+    path: &'static str,
+    function: Box<dyn Any>,
+}
+
+thread_local! {
+    /// The registered mocks, later registrations of a path take precedence.
+    static MOCKS: RefCell<Vec<Entry>> = const { RefCell::new(Vec::new()) };
+    static NEXT_ID: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Keeps a mock registered. The mock is removed when this is dropped.
+#[must_use = "the mock is removed again when the `Mock` is dropped"]
+pub struct Mock {
+    id: u64,
+}
+
+impl Drop for Mock {
+    fn drop(&mut self) {
+        let id = self.id;
+        // the registry is gone already if the thread is shutting down
+        let _ = MOCKS.try_with(|mocks| mocks.borrow_mut().retain(|entry| entry.id != id));
+    }
+}
+
+/// Registers `function` to be called instead of the stateful function imported as `path`, e.g.,
+/// `crate::foo::fetch`, in all algorithms invoked on the current thread.
+pub fn register<F: Copy + 'static>(path: &'static str, function: F) -> Mock {
+    let id = NEXT_ID.with(|next| {
+        let id = next.get();
+        next.set(id + 1);
+        id
+    });
+    MOCKS.with(|mocks| {
+        mocks.borrow_mut().push(Entry {
+            id,
+            path,
+            function: Box::new(function),
+        })
+    });
+    Mock { id }
+}
+
+/// The function to call for the stateful function `path`, which is `function` unless a mock is
+/// registered for it.
+///
+/// Panics if the registered mock is not of the same type as `function`.
+pub fn resolve<F: Copy + 'static>(path: &str, function: F) -> F {
+    MOCKS.with(|mocks| {
+        let mocks = mocks.borrow();
+        match mocks.iter().rev().find(|entry| entry.path == path) {
+            Some(entry) => *entry.function.downcast_ref::<F>().unwrap_or_else(|| {
+                panic!(
+                    "The mock registered for `{}` does not match the function's signature. \
+                     Mocks have to be function pointers, e.g., `fake as fn(u32) -> u32`.",
+                    path
+                )
+            }),
+            None => function,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn real(x: u32) -> u32 {
+        x
+    }
+
+    fn double(x: u32) -> u32 {
+        x * 2
+    }
+
+    fn triple(x: u32) -> u32 {
+        x * 3
+    }
+
+    const PATH: &str = "crate::foo::real";
+
+    #[test]
+    fn unmocked_functions_are_kept() {
+        let _mock = register("crate::foo::other", double as fn(u32) -> u32);
+
+        assert_eq!(resolve(PATH, real as fn(u32) -> u32)(5), 5);
+    }
+
+    #[test]
+    fn later_registrations_take_precedence() {
+        let _double = register(PATH, double as fn(u32) -> u32);
+        assert_eq!(resolve(PATH, real as fn(u32) -> u32)(5), 10);
+
+        let triple = register(PATH, triple as fn(u32) -> u32);
+        assert_eq!(resolve(PATH, real as fn(u32) -> u32)(5), 15);
+
+        drop(triple);
+        assert_eq!(resolve(PATH, real as fn(u32) -> u32)(5), 10);
+    }
+
+    #[test]
+    fn dropping_a_registration_removes_it() {
+        let double = register(PATH, double as fn(u32) -> u32);
+        let triple = register(PATH, triple as fn(u32) -> u32);
+
+        // the earlier registration goes away while the later one stays in place
+        drop(double);
+        assert_eq!(resolve(PATH, real as fn(u32) -> u32)(5), 15);
+
+        drop(triple);
+        assert_eq!(resolve(PATH, real as fn(u32) -> u32)(5), 5);
+        MOCKS.with(|mocks| assert!(mocks.borrow().is_empty()));
+    }
+
+    #[test]
+    fn mocks_belong_to_their_thread() {
+        let _mock = register(PATH, double as fn(u32) -> u32);
+
+        let other = thread::spawn(|| resolve(PATH, real as fn(u32) -> u32)(5));
+        assert_eq!(other.join().unwrap(), 5);
+        assert_eq!(resolve(PATH, real as fn(u32) -> u32)(5), 10);
+    }
+
+    #[test]
+    #[should_panic(expected = "The mock registered for `crate::foo::real` does not match")]
+    fn signature_mismatches_panic() {
+        let _mock = register(PATH, (|x: u64| x) as fn(u64) -> u64);
+
+        resolve(PATH, real as fn(u32) -> u32);
+    }
+}
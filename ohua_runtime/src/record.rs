@@ -0,0 +1,370 @@
+//! Recording the traffic of algorithms and replaying it one operator at a time.
+//!
+//! An algorithm invoked via `#[ohua(record = path)]` writes every value dispatched via one of its
+//! arcs to the trace file at `path`, one JSON object per line:
+//!
+//! ```text
+//! {"arc":"sf_0_out_0__sf_1_in_0","value":42}
+//! ```
+//!
+//! Arcs are listed with their generated `sf_X_out_Y__sf_Z_in_W` names, the result of the
+//! algorithm is sent via `sf_X_out_0__result`. Recording requires all values passed between the
+//! functions of the algorithm to implement `serde::Serialize`. State and arguments from the
+//! environment do not travel via arcs and are not recorded.
+//!
+//! A `Trace` loads such a file. `Trace::replay_fn` runs a function against the recorded inputs of
+//! an operator and compares its results with the recorded outputs, `Trace::replay` does the same
+//! for any subgraph, given the arcs entering and leaving it.
+use crate::arcs::ArcInput;
+use crate::RunError;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// The trace file the arcs of a recorded run write to.
+pub struct Recorder {
+    out: Mutex<BufWriter<File>>,
+}
+
+impl Recorder {
+    /// Creates the trace file at `path`, replacing any previous trace.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Arc<Recorder>> {
+        Ok(Arc::new(Recorder {
+            out: Mutex::new(BufWriter::new(File::create(path)?)),
+        }))
+    }
+
+    fn record<T: Serialize>(&self, arc: &'static str, value: &T) -> Result<(), RunError> {
+        let line = serde_json::to_string(&Entry {
+            arc: arc.to_string(),
+            value: serde_json::to_value(value).map_err(RunError::failed)?,
+        })
+        .map_err(RunError::failed)?;
+
+        let mut out = self.out.lock()?;
+        writeln!(out, "{}", line).map_err(RunError::failed)
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Ok(out) = self.out.get_mut() {
+            let _ = out.flush();
+        }
+    }
+}
+
+/// A line of the trace file.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    arc: String,
+    value: Value,
+}
+
+/// The sending end of an arc that writes every value to the trace file before dispatching it.
+///
+/// A value that cannot be serialized stops the sending operator with `RunError::Failed`, as a
+/// trace with gaps could not be replayed.
+pub struct Recorded<A> {
+    arc: A,
+    name: &'static str,
+    recorder: Arc<Recorder>,
+}
+
+impl<T: Serialize, A: ArcInput<T>> ArcInput<T> for Recorded<A> {
+    fn dispatch(&self, t: T) -> Result<(), RunError> {
+        self.recorder.record(self.name, &t)?;
+        self.arc.dispatch(t)
+    }
+}
+
+/// Records all values sent via `channel` as traffic of the arc `name`.
+pub fn recorded<S, R>(
+    recorder: &Arc<Recorder>,
+    name: &'static str,
+    channel: (S, R),
+) -> (Recorded<S>, R) {
+    let (snd, rcv) = channel;
+    (
+        Recorded {
+            arc: snd,
+            name,
+            recorder: Arc::clone(recorder),
+        },
+        rcv,
+    )
+}
+
+/// The traffic of a recorded run, loaded from its trace file.
+#[derive(Debug, Default)]
+pub struct Trace {
+    arcs: BTreeMap<String, Vec<Value>>,
+}
+
+impl Trace {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Trace> {
+        let mut trace = Trace::default();
+        for line in BufReader::new(File::open(path)?).lines() {
+            let entry: Entry = serde_json::from_str(&line?)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            trace.arcs.entry(entry.arc).or_default().push(entry.value);
+        }
+        Ok(trace)
+    }
+
+    /// The names of all arcs that carried values.
+    pub fn arcs(&self) -> impl Iterator<Item = &str> {
+        self.arcs.keys().map(String::as_str)
+    }
+
+    /// The values sent via `arc`, in the order they were sent.
+    pub fn values<T: DeserializeOwned>(&self, arc: &str) -> serde_json::Result<Vec<T>> {
+        self.raw_values(arc)
+            .iter()
+            .map(|value| T::deserialize(value))
+            .collect()
+    }
+
+    fn raw_values(&self, arc: &str) -> &[Value] {
+        self.arcs.get(arc).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    /// Calls `f` on the values recorded on the `inputs` arcs and compares the results with the
+    /// values recorded on `output`.
+    ///
+    /// The n-th call of `f` receives a tuple of the n-th values of all `inputs`, in the given
+    /// order, so a subgraph taking one input is passed a 1-tuple. `f` is called as long as every
+    /// input has a value left. Results are compared in their serialized form.
+    pub fn replay<A, R, F>(
+        &self,
+        inputs: &[&str],
+        output: &str,
+        mut f: F,
+    ) -> serde_json::Result<Replay>
+    where
+        A: DeserializeOwned,
+        R: Serialize,
+        F: FnMut(A) -> R,
+    {
+        let inputs: Vec<&[Value]> = inputs.iter().map(|arc| self.raw_values(arc)).collect();
+        let expected = self.raw_values(output);
+        let calls = inputs.iter().map(|values| values.len()).min().unwrap_or(0);
+
+        let mut mismatches = Vec::new();
+        for call in 0..calls {
+            let args = Value::Array(inputs.iter().map(|values| values[call].clone()).collect());
+            let actual = serde_json::to_value(f(A::deserialize(&args)?))?;
+            let expected = expected.get(call).cloned().unwrap_or(Value::Null);
+            if actual != expected {
+                mismatches.push(Mismatch {
+                    call,
+                    expected,
+                    actual,
+                });
+            }
+        }
+
+        Ok(Replay { calls, mismatches })
+    }
+
+    /// Replays the function executed by operator `op_id`, see `Trace::replay`.
+    ///
+    /// The inputs are the recorded arcs arriving at the operator, ordered by their input port.
+    /// Arguments from the environment and literals do not travel via arcs, so they are left out
+    /// of the tuple passed to `f`, which has to supply them itself. An operator without any
+    /// recorded input or without a recorded output cannot be replayed and is rejected.
+    pub fn replay_fn<A, R, F>(&self, op_id: i32, f: F) -> serde_json::Result<Replay>
+    where
+        A: DeserializeOwned,
+        R: Serialize,
+        F: FnMut(A) -> R,
+    {
+        let target = format!("__sf_{}_in_", op_id);
+        let mut inputs: Vec<(i32, &str)> = self
+            .arcs()
+            .filter_map(|arc| {
+                let port = &arc[arc.find(&target)? + target.len()..];
+                Some((port.parse().ok()?, arc))
+            })
+            .collect();
+        inputs.sort();
+        let inputs: Vec<&str> = inputs.into_iter().map(|(_, arc)| arc).collect();
+
+        // functions have a single output port, all arcs leaving it carry the same values
+        let source = format!("sf_{}_out_0__", op_id);
+        let output = self.arcs().find(|arc| arc.starts_with(&source));
+
+        match output {
+            Some(output) if !inputs.is_empty() => self.replay(&inputs, output, f),
+            _ => Err(serde::de::Error::custom(format!(
+                "operator {} has no recorded inputs or outputs",
+                op_id
+            ))),
+        }
+    }
+}
+
+/// The outcome of a replay.
+#[derive(Debug)]
+pub struct Replay {
+    /// The number of times the replayed function was called.
+    pub calls: usize,
+    /// The calls whose result differed from the recording.
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl Replay {
+    /// Whether all calls reproduced the recorded results.
+    pub fn is_ok(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+impl fmt::Display for Replay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} calls reproduced the recording",
+            self.calls - self.mismatches.len(),
+            self.calls
+        )?;
+        for m in &self.mismatches {
+            write!(
+                f,
+                "\n  call {}: expected {}, got {}",
+                m.call, m.expected, m.actual
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A call of a replayed function whose result differed from the recording.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    /// The index of the call, counting from 0.
+    pub call: usize,
+    /// The recorded result, `null` if the recording ended before this call.
+    pub expected: Value,
+    pub actual: Value,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::sync::mpsc::channel;
+    use tempdir::TempDir;
+
+    /// Writes a trace with the values `arcs` carried.
+    fn trace(dir: &TempDir, arcs: &[(&str, &[i32])]) -> Trace {
+        let path = dir.path().join("run.trace");
+        let lines: Vec<String> = arcs
+            .iter()
+            .flat_map(|(arc, values)| {
+                values
+                    .iter()
+                    .map(move |v| format!(r#"{{"arc":"{}","value":{}}}"#, arc, v))
+            })
+            .collect();
+        fs::write(&path, lines.join("\n")).unwrap();
+        Trace::load(path).unwrap()
+    }
+
+    #[test]
+    fn record_and_load() {
+        let dir = TempDir::new("ohua-record").unwrap();
+        let path = dir.path().join("run.trace");
+        {
+            let recorder = Recorder::create(&path).unwrap();
+            let (snd, rcv) = recorded(&recorder, "sf_0_out_0__sf_1_in_0", channel());
+            let (result_snd, _result_rcv) = recorded(&recorder, "sf_1_out_0__result", channel());
+            snd.dispatch((1, "one".to_string())).unwrap();
+            snd.dispatch((2, "two".to_string())).unwrap();
+            result_snd.dispatch(3).unwrap();
+            assert_eq!(rcv.recv().unwrap(), (1, "one".to_string()));
+        }
+
+        let trace = Trace::load(&path).unwrap();
+        let arcs: Vec<&str> = trace.arcs().collect();
+        assert_eq!(arcs, vec!["sf_0_out_0__sf_1_in_0", "sf_1_out_0__result"]);
+        let values: Vec<(i32, String)> = trace.values("sf_0_out_0__sf_1_in_0").unwrap();
+        assert_eq!(values, vec![(1, "one".to_string()), (2, "two".to_string())]);
+        assert_eq!(trace.values::<i32>("sf_1_out_0__result").unwrap(), vec![3]);
+        assert!(trace.values::<i32>("unknown").unwrap().is_empty());
+    }
+
+    #[test]
+    fn malformed_traces_are_rejected() {
+        let dir = TempDir::new("ohua-record").unwrap();
+        let path = dir.path().join("run.trace");
+        fs::write(&path, "{\"arc\":\"sf_0_out_0__sf_1_in_0\"}").unwrap();
+
+        let error = Trace::load(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn replay() {
+        let dir = TempDir::new("ohua-record").unwrap();
+        let trace = trace(
+            &dir,
+            &[
+                ("sf_0_out_0__sf_2_in_0", &[1, 2, 3]),
+                ("sf_1_out_0__sf_2_in_1", &[10, 20, 30, 40]),
+                ("sf_2_out_0__sf_3_in_0", &[11, 22, 34]),
+            ],
+        );
+
+        let replay = trace
+            .replay(
+                &["sf_0_out_0__sf_2_in_0", "sf_1_out_0__sf_2_in_1"],
+                "sf_2_out_0__sf_3_in_0",
+                |(a, b): (i32, i32)| a + b,
+            )
+            .unwrap();
+        assert_eq!(replay.calls, 3);
+        assert_eq!(
+            replay.mismatches,
+            vec![Mismatch {
+                call: 2,
+                expected: Value::from(34),
+                actual: Value::from(33),
+            }]
+        );
+        assert!(!replay.is_ok());
+        assert_eq!(
+            replay.to_string(),
+            "2 of 3 calls reproduced the recording\n  call 2: expected 34, got 33"
+        );
+    }
+
+    #[test]
+    fn replay_fn() {
+        let dir = TempDir::new("ohua-record").unwrap();
+        // the arc to port 1 comes first in the trace
+        let trace = trace(
+            &dir,
+            &[
+                ("sf_1_out_0__sf_5_in_1", &[10, 20]),
+                ("sf_4_out_0__sf_5_in_0", &[3, 4]),
+                ("sf_5_out_0__sf_6_in_0", &[-7, -16]),
+                ("sf_5_out_0__sf_7_in_2", &[-7, -16]),
+            ],
+        );
+
+        let replay = trace.replay_fn(5, |(a, b): (i32, i32)| a - b).unwrap();
+        assert_eq!(replay.calls, 2);
+        assert!(replay.is_ok(), "{}", replay);
+
+        // operator 4 only takes arguments from the environment, operator 6 sends nothing
+        assert!(trace.replay_fn(4, |(a,): (i32,)| a).is_err());
+        assert!(trace.replay_fn(6, |(a,): (i32,)| a).is_err());
+    }
+}
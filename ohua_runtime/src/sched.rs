@@ -0,0 +1,381 @@
+//! Deterministic execution of algorithms.
+//!
+//! Usually every task of an algorithm runs on a thread of its own and the operating system
+//! decides how the tasks interleave. A run with a `Schedule` executes all of its tasks on the
+//! calling thread instead, each one as a coroutine with a stack of its own: a task hands control
+//! back to the scheduler whenever it receives from an arc, and the scheduler picks the task to
+//! continue with according to the schedule. Runs with the same schedule execute the same
+//! interleaving, so a failure can be reproduced, and `Schedule::Random` with different seeds
+//! explores different interleavings.
+//!
+//! Turns are only handed over when a task receives from an arc. A task that blocks anywhere
+//! else, e.g., on the lock of a state that is shared with other runs, blocks the whole run until
+//! it gets going again.
+//!
+//! Scheduled runs are created via `#[ohua(schedule = ...)]`.
+use crate::watchdog::Deadlock;
+use crate::{arcs, first_failure, RunError};
+use corosensei::stack::DefaultStack;
+use corosensei::{Coroutine, CoroutineResult, Yielder};
+use std::cell::Cell;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::thread;
+
+/// The size of the stack of a task, the same as for the threads of a regular run.
+const STACK_SIZE: usize = 2 * 1024 * 1024;
+
+/// The order in which the tasks of a scheduled run take turns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Schedule {
+    /// Always continue with the first task that can make progress, in the order the tasks were
+    /// created. The generated code creates the tasks of all stateful functions before those of
+    /// the runtime operators, each in the order they appear in the dataflow graph.
+    InOrder,
+    /// Continue with a random task among those that can make progress. Runs with the same
+    /// seed take the same turns.
+    Random(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Status {
+    /// The task has not started yet or wants to go on.
+    Runnable,
+    /// The task found its input arc empty, when `progress` had the given value.
+    Blocked(u64),
+    Done,
+}
+
+struct Scheduler {
+    schedule: Schedule,
+    /// The state of the pseudo random number generator for `Schedule::Random`.
+    rng: u64,
+    statuses: Vec<Status>,
+    /// Counts the turns in which a task got anything done. A blocked task is worth another
+    /// try only once some other task made progress, as it may have sent the missing message.
+    progress: u64,
+}
+
+impl Scheduler {
+    fn new(num_tasks: usize, schedule: Schedule) -> Scheduler {
+        let rng = match schedule {
+            Schedule::Random(seed) => seed,
+            Schedule::InOrder => 0,
+        };
+        Scheduler {
+            schedule,
+            rng,
+            statuses: vec![Status::Runnable; num_tasks],
+            progress: 0,
+        }
+    }
+
+    fn next_random(&mut self) -> u64 {
+        // splitmix64
+        self.rng = self.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Picks the task to take the next turn, `None` if no task can make progress.
+    fn pick(&mut self) -> Option<usize> {
+        let progress = self.progress;
+        let candidates: Vec<usize> = self
+            .statuses
+            .iter()
+            .enumerate()
+            .filter(|(_, status)| match status {
+                Status::Runnable => true,
+                Status::Blocked(seen) => *seen < progress,
+                Status::Done => false,
+            })
+            .map(|(idx, _)| idx)
+            .collect();
+
+        match self.schedule {
+            Schedule::InOrder => candidates.first().cloned(),
+            Schedule::Random(_) if candidates.is_empty() => None,
+            Schedule::Random(_) => {
+                let pick = self.next_random() % candidates.len() as u64;
+                Some(candidates[pick as usize])
+            }
+        }
+    }
+
+    /// Ends the turn of task `me`, which continues with `status`.
+    fn end_turn(&mut self, me: usize, status: Status) {
+        self.statuses[me] = match status {
+            Status::Blocked(_) => Status::Blocked(self.progress),
+            _ => {
+                self.progress += 1;
+                status
+            }
+        };
+    }
+
+    fn live_tasks(&self) -> usize {
+        self.statuses
+            .iter()
+            .filter(|status| **status != Status::Done)
+            .count()
+    }
+}
+
+/// The `Yielder` a task suspends itself with, resumed with `false` if the run is deadlocked and
+/// the task has to give up.
+type Turns = Yielder<bool, Status>;
+
+/// A task of a scheduled run, which ends like a thread running the task.
+type Task = Coroutine<bool, Status, thread::Result<Result<(), RunError>>>;
+
+thread_local! {
+    /// The `Turns` of the task of a scheduled run that is running on this thread, if any.
+    static CURRENT: Cell<*const Turns> = const { Cell::new(ptr::null()) };
+}
+
+/// Whether the current thread runs a task of a scheduled run.
+pub(crate) fn is_running() -> bool {
+    CURRENT.with(|current| !current.get().is_null())
+}
+
+/// Receives from `rcv` in turns, if the current thread runs a task of a scheduled run.
+pub(crate) fn recv<T>(rcv: &Receiver<T>) -> Option<Result<T, RunError>> {
+    let turns = CURRENT.with(Cell::get);
+    if turns.is_null() {
+        return None;
+    }
+    // SAFETY: `CURRENT` points to the yielder of the running task, which lives on the stack of
+    // the task until the task is done, see `run_tasks`.
+    let turns = unsafe { &*turns };
+
+    let mut status = Status::Runnable;
+    loop {
+        let go_on = turns.suspend(status);
+        // other tasks ran in the meantime
+        CURRENT.with(|current| current.set(turns));
+        if !go_on {
+            return Some(Err(RunError::RecvFailed { arc: None }));
+        }
+        match rcv.try_recv() {
+            Ok(t) => return Some(Ok(t)),
+            Err(TryRecvError::Disconnected) => {
+                return Some(Err(RunError::RecvFailed { arc: None }))
+            }
+            Err(TryRecvError::Empty) => status = Status::Blocked(0),
+        }
+    }
+}
+
+/// Executes an algorithm like `crate::run_tasks`, with its tasks taking turns on the calling
+/// thread according to `schedule`.
+///
+/// A run in which all remaining tasks wait for messages that never arrive is shut down: the
+/// waiting tasks fail to receive like after a hang-up, and the run returns `RunError::Deadlock`.
+pub fn run_tasks(
+    tasks: Vec<Box<dyn FnOnce() -> Result<(), RunError> + Send + 'static>>,
+    schedule: Schedule,
+) -> Result<(), RunError> {
+    // a scheduled run detects deadlocks itself
+    arcs::take_run_arcs();
+
+    let mut coroutines: Vec<Task> = tasks
+        .into_iter()
+        .map(|task| {
+            let stack = DefaultStack::new(STACK_SIZE).expect("Unable to allocate a task stack.");
+            Coroutine::with_stack(stack, move |turns: &Turns, _| {
+                CURRENT.with(|current| current.set(turns));
+                panic::catch_unwind(AssertUnwindSafe(task))
+            })
+        })
+        .collect();
+    let mut results: Vec<Option<thread::Result<Result<(), RunError>>>> =
+        coroutines.iter().map(|_| None).collect();
+
+    // the task of an enclosing scheduled run, if any
+    let enclosing = CURRENT.with(Cell::get);
+    let mut scheduler = Scheduler::new(coroutines.len(), schedule);
+    let mut deadlock = None;
+    loop {
+        let me = match scheduler.pick() {
+            Some(me) => me,
+            None if scheduler.live_tasks() == 0 => break,
+            None => {
+                deadlock.get_or_insert(Deadlock {
+                    live_tasks: scheduler.live_tasks(),
+                    waiting_on: Vec::new(),
+                });
+                // the blocked tasks give up one after another
+                scheduler
+                    .statuses
+                    .iter()
+                    .position(|status| *status != Status::Done)
+                    .unwrap()
+            }
+        };
+
+        let status = match coroutines[me].resume(deadlock.is_none()) {
+            CoroutineResult::Yield(status) => status,
+            CoroutineResult::Return(result) => {
+                results[me] = Some(result);
+                Status::Done
+            }
+        };
+        CURRENT.with(|current| current.set(ptr::null()));
+        scheduler.end_turn(me, status);
+    }
+    CURRENT.with(|current| current.set(enclosing));
+
+    let result = first_failure(results.into_iter().flatten());
+    match deadlock {
+        Some(deadlock) => Err(RunError::Deadlock(deadlock)),
+        None => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arcs::{named_channel, ArcInput, ArcOutput};
+    use crate::task;
+    use std::sync::{Arc, Mutex};
+
+    /// Runs two tasks that receive the values queued in their input arcs, returning the order
+    /// in which the values were received as pairs of task and value.
+    fn trace(schedule: Schedule) -> Vec<(usize, i32)> {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let tasks = (0..2)
+            .map(|me| {
+                let (snd, rcv) = named_channel("input");
+                for v in 0..3 {
+                    snd.dispatch(v).unwrap();
+                }
+                let log = Arc::clone(&log);
+                task(me as i32, "drain", move || {
+                    while let Ok(v) = rcv.recv() {
+                        log.lock().unwrap().push((me, v));
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+
+        run_tasks(tasks, schedule).unwrap();
+        let trace = log.lock().unwrap().clone();
+        trace
+    }
+
+    #[test]
+    fn in_order() {
+        // every receive hands over the turn, the first task gets it back until it is done
+        assert_eq!(
+            trace(Schedule::InOrder),
+            vec![(0, 0), (0, 1), (0, 2), (1, 0), (1, 1), (1, 2)]
+        );
+    }
+
+    #[test]
+    fn random() {
+        let traces: Vec<Vec<(usize, i32)>> =
+            (0..8).map(|seed| trace(Schedule::Random(seed))).collect();
+        for (seed, taken) in traces.iter().enumerate() {
+            assert_eq!(*taken, trace(Schedule::Random(seed as u64)));
+            // the values of a task still arrive in order
+            for me in 0..2 {
+                let values: Vec<i32> = taken
+                    .iter()
+                    .filter(|(t, _)| *t == me)
+                    .map(|(_, v)| *v)
+                    .collect();
+                assert_eq!(values, vec![0, 1, 2]);
+            }
+        }
+        assert!(traces.iter().any(|trace| *trace != traces[0]));
+    }
+
+    #[test]
+    fn single_thread() {
+        let caller = thread::current().id();
+        let ids = Arc::new(Mutex::new(Vec::new()));
+        let (snd, rcv) = named_channel("input");
+        let tasks = vec![
+            {
+                let ids = Arc::clone(&ids);
+                task(1, "produce", move || {
+                    ids.lock().unwrap().push(thread::current().id());
+                    snd.dispatch(1)
+                })
+            },
+            {
+                let ids = Arc::clone(&ids);
+                task(2, "consume", move || {
+                    rcv.recv()?;
+                    ids.lock().unwrap().push(thread::current().id());
+                    Ok(())
+                })
+            },
+        ];
+
+        run_tasks(tasks, Schedule::InOrder).unwrap();
+        assert_eq!(*ids.lock().unwrap(), vec![caller, caller]);
+        assert!(!is_running());
+    }
+
+    #[test]
+    fn failures() {
+        let (snd, rcv) = named_channel::<i32>("input");
+        let tasks = vec![
+            task(1, "consume", move || loop {
+                rcv.recv()?;
+            }),
+            task(2, "produce", move || {
+                snd.dispatch(1)?;
+                panic!("broken")
+            }),
+        ];
+
+        match run_tasks(tasks, Schedule::InOrder) {
+            Err(RunError::Panicked {
+                op_id: 2, message, ..
+            }) => assert_eq!(message, "broken"),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn deadlock() {
+        let (fst_snd, fst) = named_channel::<i32>("sf_1_out_0__sf_2_in_0");
+        let (snd_snd, snd) = named_channel::<i32>("sf_2_out_0__sf_1_in_0");
+        let gave_up = Arc::new(Mutex::new(Vec::new()));
+        // both tasks wait for the other one to send first
+        let tasks = vec![
+            {
+                let gave_up = Arc::clone(&gave_up);
+                task(1, "ping", move || {
+                    let res = snd.recv();
+                    gave_up.lock().unwrap().push(1);
+                    fst_snd.dispatch(res?)
+                })
+            },
+            {
+                let gave_up = Arc::clone(&gave_up);
+                task(2, "pong", move || {
+                    let res = fst.recv();
+                    gave_up.lock().unwrap().push(2);
+                    snd_snd.dispatch(res?)
+                })
+            },
+        ];
+
+        match run_tasks(tasks, Schedule::Random(7)) {
+            Err(RunError::Deadlock(deadlock)) => assert_eq!(deadlock.live_tasks, 2),
+            other => panic!("unexpected result {:?}", other),
+        }
+        // the tasks are not left behind
+        let mut gave_up = gave_up.lock().unwrap().clone();
+        gave_up.sort();
+        assert_eq!(gave_up, vec![1, 2]);
+    }
+}
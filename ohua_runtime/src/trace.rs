@@ -0,0 +1,373 @@
+//! Tracing of operator execution.
+//!
+//! When `ohua_codegen` is built with the `trace` feature, the generated code reports what every
+//! operator does to the installed `Subscriber`. The events follow the span model of the
+//! [`tracing`](https://docs.rs/tracing) crate: every invocation of an operator is a span
+//! delimited by `CallStart` and `CallEnd`, input arrivals and output dispatches are events.
+//! The `MemorySubscriber` keeps all events in memory, the `TracingSubscriber` (behind the
+//! `tracing` feature) forwards them to `tracing`.
+//!
+//! Runtime operators like `smapFun` get a span per invocation, which includes waiting for their
+//! inputs. Operators that serve a whole run at once, like `ctrl`, get a single span. Inputs and
+//! outputs of runtime operators are not reported individually.
+//!
+//! Recorded events can be exported with `write_chrome_trace` and inspected in `chrome://tracing`
+//! or [Perfetto](https://ui.perfetto.dev).
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, ThreadId};
+use std::time::Instant;
+
+/// What happened at an operator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    /// An invocation of the operator has started.
+    CallStart,
+    /// An invocation of the operator has finished.
+    CallEnd,
+    /// A value arrived at input `port`, sent by output `source.1` of operator `source.0`.
+    InputArrived { port: i32, source: (i32, i32) },
+    /// A value was dispatched via output `port`.
+    OutputDispatched { port: i32 },
+}
+
+/// A single event, tagged with the operator it belongs to.
+#[derive(Debug, Clone)]
+pub struct Event {
+    /// The id of the operator in the dataflow graph.
+    pub op_id: i32,
+    /// The name of the function or operator, as found in the `OperatorType`.
+    pub op_name: &'static str,
+    pub kind: EventKind,
+    pub time: Instant,
+    pub thread: ThreadId,
+}
+
+/// Receives the events of all running algorithms.
+pub trait Subscriber: Send + Sync {
+    fn record(&self, event: Event);
+}
+
+/// A subscriber that collects all events in memory.
+#[derive(Default)]
+pub struct MemorySubscriber {
+    events: Mutex<Vec<Event>>,
+}
+
+impl MemorySubscriber {
+    /// All events recorded so far, in the order they were recorded.
+    pub fn events(&self) -> Vec<Event> {
+        self.events.lock().unwrap().clone()
+    }
+
+    /// All events recorded so far for the operator called `op_name`.
+    pub fn events_of(&self, op_name: &str) -> Vec<Event> {
+        self.events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|e| e.op_name == op_name)
+            .cloned()
+            .collect()
+    }
+
+    /// Writes all events recorded so far to `path`, see `write_chrome_trace`.
+    pub fn write_chrome_trace<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        write_chrome_trace(&self.events.lock().unwrap(), &mut out)?;
+        out.flush()
+    }
+}
+
+impl Subscriber for MemorySubscriber {
+    fn record(&self, event: Event) {
+        self.events.lock().unwrap().push(event);
+    }
+}
+
+/// A subscriber that forwards all events to the `tracing` crate.
+#[cfg(feature = "tracing")]
+pub struct TracingSubscriber;
+
+#[cfg(feature = "tracing")]
+impl Subscriber for TracingSubscriber {
+    fn record(&self, event: Event) {
+        tracing::trace!(
+            target: "ohua",
+            op_id = event.op_id,
+            op_name = event.op_name,
+            kind = ?event.kind,
+        );
+    }
+}
+
+static SUBSCRIBER: RwLock<Option<Arc<dyn Subscriber>>> = RwLock::new(None);
+
+/// Installs the subscriber that receives the events of all algorithms from now on.
+pub fn set_subscriber(subscriber: Arc<dyn Subscriber>) {
+    *SUBSCRIBER.write().unwrap() = Some(subscriber);
+}
+
+/// Removes the installed subscriber, events are discarded from now on.
+pub fn clear_subscriber() {
+    *SUBSCRIBER.write().unwrap() = None;
+}
+
+fn emit(op_id: i32, op_name: &'static str, kind: EventKind) {
+    if let Some(ref subscriber) = *SUBSCRIBER.read().unwrap() {
+        subscriber.record(Event {
+            op_id,
+            op_name,
+            kind,
+            time: Instant::now(),
+            thread: thread::current().id(),
+        });
+    }
+}
+
+/// The span of a single invocation. The invocation ends when the `Call` is dropped.
+pub struct Call {
+    op_id: i32,
+    op_name: &'static str,
+    #[cfg(feature = "tracing")]
+    _span: tracing::span::EnteredSpan,
+}
+
+impl Call {
+    pub fn start(op_id: i32, op_name: &'static str) -> Call {
+        emit(op_id, op_name, EventKind::CallStart);
+        Call {
+            op_id,
+            op_name,
+            #[cfg(feature = "tracing")]
+            _span: tracing::trace_span!(target: "ohua", "call", op_id, op_name).entered(),
+        }
+    }
+}
+
+impl Drop for Call {
+    fn drop(&mut self) {
+        emit(self.op_id, self.op_name, EventKind::CallEnd);
+    }
+}
+
+/// Reports the arrival of `value` at input `port` and passes it on. `source` is the operator id
+/// and output port the value was sent from.
+pub fn input<T>(op_id: i32, op_name: &'static str, port: i32, source: (i32, i32), value: T) -> T {
+    emit(op_id, op_name, EventKind::InputArrived { port, source });
+    value
+}
+
+/// Reports that a value was dispatched via output `port`.
+pub fn output(op_id: i32, op_name: &'static str, port: i32) {
+    emit(op_id, op_name, EventKind::OutputDispatched { port });
+}
+
+/// Quotes `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Writes `events` in the Chrome Trace Event format.
+///
+/// Every thread gets a track of its own, named after the first operator seen on it. Calls
+/// become slices on that track and every value sent from one traced function to another is
+/// shown as a flow from the producing call to the call that consumes the value.
+pub fn write_chrome_trace<W: Write>(events: &[Event], out: &mut W) -> io::Result<()> {
+    let start = match events.iter().map(|e| e.time).min() {
+        Some(start) => start,
+        None => return writeln!(out, "[]"),
+    };
+    let ts = |event: &Event| (event.time - start).as_nanos() as f64 / 1000.0;
+
+    let mut records = Vec::new();
+    let mut tids: HashMap<ThreadId, usize> = HashMap::new();
+    // the start of the last call of every operator, flows are attached to it
+    let mut last_call: HashMap<i32, f64> = HashMap::new();
+    // per output port: the call and track every dispatched value came from, in order
+    let mut dispatched: HashMap<(i32, i32), Vec<(f64, usize)>> = HashMap::new();
+    // per input port: the number of values that have arrived so far
+    let mut arrived: HashMap<(i32, i32), usize> = HashMap::new();
+    let mut arrivals = Vec::new();
+
+    for event in events {
+        let next_tid = tids.len() + 1;
+        let tid = *tids.entry(event.thread).or_insert_with(|| {
+            records.push(format!(
+                r#"{{"ph":"M","pid":1,"tid":{},"name":"thread_name","args":{{"name":{}}}}}"#,
+                next_tid,
+                json_string(&format!("{} ({})", event.op_name, event.op_id))
+            ));
+            next_tid
+        });
+        let slice = |ph: &str| {
+            format!(
+                r#"{{"ph":"{}","pid":1,"tid":{},"ts":{:.3},"name":{},"args":{{"op_id":{}}}}}"#,
+                ph,
+                tid,
+                ts(event),
+                json_string(event.op_name),
+                event.op_id
+            )
+        };
+
+        match event.kind {
+            EventKind::CallStart => {
+                last_call.insert(event.op_id, ts(event));
+                records.push(slice("B"));
+            }
+            EventKind::CallEnd => records.push(slice("E")),
+            EventKind::OutputDispatched { port } => {
+                let call = last_call
+                    .get(&event.op_id)
+                    .cloned()
+                    .unwrap_or_else(|| ts(event));
+                dispatched
+                    .entry((event.op_id, port))
+                    .or_default()
+                    .push((call, tid));
+            }
+            EventKind::InputArrived { port, source } => {
+                let count = arrived.entry((event.op_id, port)).or_insert(0);
+                arrivals.push((source, *count, tid, ts(event)));
+                *count += 1;
+            }
+        }
+    }
+
+    // channels deliver in order, so the n-th value arriving via an arc is the n-th value
+    // dispatched on the output port the arc starts at
+    for (id, (source, n, tid, arrival)) in arrivals.into_iter().enumerate() {
+        if let Some(&(call, src_tid)) = dispatched.get(&source).and_then(|d| d.get(n)) {
+            records.push(format!(
+                r#"{{"ph":"s","pid":1,"tid":{},"ts":{:.3},"id":{},"name":"arc","cat":"arc"}}"#,
+                src_tid, call, id
+            ));
+            records.push(format!(
+                r#"{{"ph":"f","pid":1,"tid":{},"ts":{:.3},"id":{},"name":"arc","cat":"arc"}}"#,
+                tid, arrival, id
+            ));
+        }
+    }
+
+    writeln!(out, "[")?;
+    for (idx, record) in records.iter().enumerate() {
+        let sep = if idx + 1 < records.len() { "," } else { "" };
+        writeln!(out, "  {}{}", record, sep)?;
+    }
+    writeln!(out, "]")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn memory_subscriber() {
+        let subscriber = Arc::new(MemorySubscriber::default());
+        set_subscriber(subscriber.clone());
+        let value = input(1, "traced", 0, (0, 0), 42);
+        {
+            let _call = Call::start(1, "traced");
+            output(1, "traced", 0);
+        }
+        clear_subscriber();
+        // discarded
+        output(1, "traced", 0);
+
+        assert_eq!(value, 42);
+        let kinds: Vec<EventKind> = subscriber.events().iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                EventKind::InputArrived {
+                    port: 0,
+                    source: (0, 0)
+                },
+                EventKind::CallStart,
+                EventKind::OutputDispatched { port: 0 },
+                EventKind::CallEnd,
+            ]
+        );
+        assert_eq!(subscriber.events_of("traced").len(), 4);
+        assert!(subscriber.events_of("untraced").is_empty());
+    }
+
+    #[test]
+    fn chrome_trace() {
+        let start = Instant::now();
+        let producer = thread::current().id();
+        let consumer = thread::spawn(|| thread::current().id()).join().unwrap();
+        let event = |op_id, op_name, kind, micros, thread| Event {
+            op_id,
+            op_name,
+            kind,
+            time: start + Duration::from_micros(micros),
+            thread,
+        };
+        let arrived = EventKind::InputArrived {
+            port: 0,
+            source: (1, 0),
+        };
+        let dispatched = EventKind::OutputDispatched { port: 0 };
+        let quoted = "say \"hi\"\\";
+        let events = vec![
+            event(1, quoted, EventKind::CallStart, 0, producer),
+            event(1, quoted, dispatched, 1, producer),
+            event(1, quoted, EventKind::CallEnd, 2, producer),
+            event(1, quoted, EventKind::CallStart, 10, producer),
+            event(1, quoted, dispatched, 11, producer),
+            event(1, quoted, EventKind::CallEnd, 12, producer),
+            event(2, "consume", arrived, 5, consumer),
+            event(2, "consume", arrived, 15, consumer),
+        ];
+
+        let mut out = Vec::new();
+        write_chrome_trace(&events, &mut out).unwrap();
+        let records: Vec<serde_json::Value> = serde_json::from_slice(&out).unwrap();
+        let with_ph = |ph: &str| -> Vec<&serde_json::Value> {
+            records.iter().filter(|r| r["ph"] == ph).collect()
+        };
+
+        let names: Vec<&str> = with_ph("M")
+            .iter()
+            .map(|r| r["args"]["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, vec!["say \"hi\"\\ (1)", "consume (2)"]);
+        assert!(with_ph("B").iter().all(|r| r["name"] == quoted));
+        assert_eq!(with_ph("B").len(), 2);
+        assert_eq!(with_ph("E").len(), 2);
+
+        // the n-th value arriving starts at the call that dispatched the n-th value
+        let starts = with_ph("s");
+        let finishes = with_ph("f");
+        assert_eq!(starts.len(), 2);
+        assert_eq!(finishes.len(), 2);
+        for ((s, f), (call, arrival)) in starts
+            .iter()
+            .zip(&finishes)
+            .zip(&[(0.0, 5.0), (10.0, 15.0)])
+        {
+            assert_eq!(s["id"], f["id"]);
+            assert_eq!(s["tid"], 1);
+            assert_eq!(f["tid"], 2);
+            assert_eq!(s["ts"].as_f64(), Some(*call));
+            assert_eq!(f["ts"].as_f64(), Some(*arrival));
+        }
+    }
+}
@@ -0,0 +1,178 @@
+//! Detection of algorithms that got stuck.
+//!
+//! A run is stuck when every task that is still alive waits for a message on an arc that
+//! nobody is going to send to anymore. This happens, e.g., when a `ctrl` receives fewer
+//! control tokens than its consumers expect values. Watched arcs (see `arcs::watched_channel`)
+//! reveal whether a task is waiting, so only runs over watched arcs are watched. The code
+//! generator creates watched arcs when `ohua_codegen` is built with the `watchdog` feature.
+//! Tasks that block elsewhere, e.g., on the lock of a state, are never considered stuck.
+//!
+//! A stuck run is disconnected: its waiting tasks fail to receive like after a hang-up, so they
+//! finish, and `run_tasks` returns `RunError::Deadlock`.
+use crate::arcs::ArcState;
+use std::fmt;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long a run has to be stuck before it is reported.
+const STUCK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The state of a run in which all live tasks wait for messages that never arrive.
+#[derive(Debug, Clone)]
+pub struct Deadlock {
+    /// The number of tasks that have not finished.
+    pub live_tasks: usize,
+    /// The names of the arcs the tasks are waiting on. The `sf_Z_in_W` part of a name
+    /// identifies the waiting operator `Z` and the input `W` it waits on.
+    pub waiting_on: Vec<&'static str>,
+}
+
+impl fmt::Display for Deadlock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "the run is deadlocked, all {} live tasks are waiting for messages that never arrive",
+            self.live_tasks
+        )?;
+        for arc in &self.waiting_on {
+            write!(f, "\n  waiting on {}", arc)?;
+        }
+        Ok(())
+    }
+}
+
+/// Notifies the watchdog when a task finishes, also if the task panics.
+pub(crate) struct Finished(pub(crate) Sender<()>);
+
+impl Drop for Finished {
+    fn drop(&mut self) {
+        let _ = self.0.send(());
+    }
+}
+
+/// Checks whether the run is stuck, given the number of tasks that are still alive.
+fn stuck(arcs: &[Arc<ArcState>], live_tasks: usize) -> Option<Deadlock> {
+    let waiting_on: Vec<&ArcState> = arcs
+        .iter()
+        .filter(|a| a.is_waiting())
+        .map(|a| &**a)
+        .collect();
+
+    // every arc has a single receiver, so each waiting arc is a different task
+    if live_tasks > 0 && waiting_on.len() == live_tasks && waiting_on.iter().all(|a| a.depth() == 0)
+    {
+        Some(Deadlock {
+            live_tasks,
+            waiting_on: waiting_on.iter().map(|a| a.name()).collect(),
+        })
+    } else {
+        None
+    }
+}
+
+/// Waits for `live_tasks` tasks to finish, returning the `Deadlock` they got stuck in, if any.
+///
+/// `finished` receives a notification for every task that is done. A stuck run is disconnected
+/// and waited for, too, so no task is left behind.
+pub(crate) fn watch(
+    mut live_tasks: usize,
+    arcs: Vec<Arc<ArcState>>,
+    finished: Receiver<()>,
+) -> Option<Deadlock> {
+    // the number of messages sent when the run was first seen stuck
+    let mut stuck_since = None;
+
+    while live_tasks > 0 {
+        match finished.recv_timeout(STUCK_INTERVAL) {
+            Ok(()) => {
+                live_tasks -= 1;
+                stuck_since = None;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+            Err(RecvTimeoutError::Timeout) => {
+                let messages: usize = arcs.iter().map(|a| a.messages()).sum();
+                match stuck(&arcs, live_tasks) {
+                    // a task may have received its message without having cleared its
+                    // waiting flag yet, so the run has to be stuck for a whole interval
+                    Some(deadlock) if stuck_since == Some(messages) => {
+                        for arc in &arcs {
+                            arc.disconnect();
+                        }
+                        // every task is done once the last notification is sent
+                        while finished.recv().is_ok() {}
+                        return Some(deadlock);
+                    }
+                    Some(_) => stuck_since = Some(messages),
+                    None => stuck_since = None,
+                }
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arcs::{watched_channel, ArcInput, ArcOutput};
+    use crate::{run_tasks, task, RunError};
+
+    #[test]
+    fn stuck_run() {
+        let arcs = vec![
+            ArcState::waiting_with("sf_1_out_0__sf_2_in_0", 0),
+            ArcState::waiting_with("sf_2_out_0__sf_1_in_0", 0),
+        ];
+
+        let deadlock = stuck(&arcs, 2).unwrap();
+        assert_eq!(deadlock.live_tasks, 2);
+        assert_eq!(
+            deadlock.waiting_on,
+            vec!["sf_1_out_0__sf_2_in_0", "sf_2_out_0__sf_1_in_0"]
+        );
+        // a third task is busy and may still send something
+        assert!(stuck(&arcs, 3).is_none());
+        assert!(stuck(&[], 0).is_none());
+    }
+
+    #[test]
+    fn message_in_flight() {
+        // the second task is about to receive its message
+        let arcs = vec![
+            ArcState::waiting_with("sf_1_out_0__sf_2_in_0", 0),
+            ArcState::waiting_with("sf_2_out_0__sf_1_in_0", 1),
+        ];
+
+        assert!(stuck(&arcs, 2).is_none());
+    }
+
+    #[test]
+    fn deadlocked_run_fails() {
+        let (fst_snd, fst) = watched_channel::<i32>("sf_1_out_0__sf_2_in_0");
+        let (snd_snd, snd) = watched_channel::<i32>("sf_2_out_0__sf_1_in_0");
+        // both tasks wait for the other one to send first
+        let tasks = vec![
+            task(1, "ping", move || fst_snd.dispatch(snd.recv()?)),
+            task(2, "pong", move || snd_snd.dispatch(fst.recv()?)),
+        ];
+
+        match run_tasks(tasks) {
+            Err(RunError::Deadlock(deadlock)) => {
+                assert_eq!(deadlock.live_tasks, 2);
+                let mut waiting_on = deadlock.waiting_on.clone();
+                waiting_on.sort();
+                assert_eq!(
+                    waiting_on,
+                    vec!["sf_1_out_0__sf_2_in_0", "sf_2_out_0__sf_1_in_0"]
+                );
+                assert_eq!(
+                    deadlock.to_string().lines().next().unwrap(),
+                    "the run is deadlocked, all 2 live tasks are waiting for messages that never arrive"
+                );
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
@@ -44,3 +44,17 @@ pub fn get_vec_input() -> Vec<i32> {
 pub fn times_2(num: i32) -> i32 {
     num * 2
 }
+
+// for if_in_smap_const_branches
+pub fn is_small(num: i32) -> bool {
+    num < 7
+}
+
+// for switch_test, whose graph is written by hand as `ohuac` has no multi-way branches yet
+pub fn get_variant() -> i32 {
+    2
+}
+
+pub fn modify_string_unknown(a: String) -> String {
+    a + "maybe"
+}
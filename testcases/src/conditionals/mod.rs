@@ -60,3 +60,21 @@ fn smap_in_if_passthrough() {
 
     assert!(res == vec![4, 8, 12, 16, 20, 24]);
 }
+
+// branches that do not depend on any input must only run when taken
+#[test]
+fn if_in_smap_const_branches() {
+    #[ohua]
+    let res = conditionals::algorithms::if_in_smap_const_branches();
+
+    assert!(
+        res == vec![
+            "executed: yes",
+            "executed: yes",
+            "executed: yes",
+            "executed: no",
+            "executed: no",
+            "executed: no"
+        ]
+    );
+}
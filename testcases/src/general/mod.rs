@@ -3,6 +3,10 @@ mod produce_consume;
 mod strings;
 
 use ohua_codegen::ohua;
+use ohua_runtime::cancel::CancellationToken;
+use ohua_runtime::record::Trace;
+use ohua_runtime::sched::Schedule;
+use ohua_runtime::RunError;
 
 #[test]
 fn ohua_macro_test() {
@@ -25,6 +29,31 @@ fn automatic_arg_cloning() {
     general::algorithms::argument_clone();
 }
 
+#[test]
+fn borrowed_args() {
+    #[ohua]
+    let x = general::algorithms::borrowed_args();
+
+    assert!(x == "3 words, longest: everywhere");
+}
+
+#[test]
+fn multiple_outputs() {
+    #[ohua]
+    let (text, number) = general::algorithms::multiple_outputs();
+
+    assert!(text == "This string contains the number 42");
+    assert!(number == 42);
+}
+
+#[test]
+fn destructuring() {
+    #[ohua]
+    let x = general::algorithms::destructuring();
+
+    assert!(x == "This string contains the number 42");
+}
+
 #[test]
 fn custom_types() {
     #[ohua]
@@ -36,3 +65,138 @@ fn lambdas() {
     #[ohua]
     general::algorithms::lambdas();
 }
+
+#[test]
+fn timeouts() {
+    #[ohua(timeout_ms = 5000)]
+    let quick = general::algorithms::cancellation(0);
+    assert!(quick.unwrap() == 43);
+
+    #[ohua(timeout_ms = 50)]
+    let slow = general::algorithms::cancellation(500);
+    assert!(matches!(slow, Err(RunError::TimedOut)));
+}
+
+#[test]
+fn cancellation() {
+    let token = CancellationToken::new();
+    let canceller = token.clone();
+    std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        canceller.cancel();
+    });
+
+    #[ohua(cancel = token)]
+    let result = general::algorithms::cancellation(500);
+    assert!(matches!(result, Err(RunError::Cancelled)));
+}
+
+#[test]
+fn panics() {
+    #[ohua(timeout_ms = 5000)]
+    let fine = general::algorithms::panics(1);
+    assert!(fine.unwrap() == 2);
+
+    #[ohua(timeout_ms = 5000)]
+    let failed = general::algorithms::panics(0);
+    match failed {
+        Err(RunError::Panicked { op_name, message, .. }) => {
+            assert!(op_name == "fail_on_zero");
+            assert!(message == "zero is not allowed");
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    // without a `Result` to return, the error is passed on as a panic
+    let payload = std::panic::catch_unwind(|| {
+        #[ohua]
+        let x = general::algorithms::panics(0);
+        x
+    })
+    .unwrap_err();
+    assert!(matches!(
+        payload.downcast_ref::<RunError>(),
+        Some(RunError::Panicked { op_name: "fail_on_zero", .. })
+    ));
+}
+
+#[test]
+fn user_errors() {
+    #[ohua(timeout_ms = 5000)]
+    let even = general::algorithms::user_errors(4);
+    assert!(even.unwrap() == 3);
+
+    #[ohua(timeout_ms = 5000)]
+    let odd = general::algorithms::user_errors(3);
+    let error = odd.unwrap_err();
+    assert!(error.to_string().ends_with("(halve_even) failed: 3 is odd"));
+    assert!(!error.is_shutdown());
+}
+
+#[test]
+fn deterministic_schedules() {
+    #[ohua(schedule = Schedule::InOrder)]
+    let (text, number) = general::algorithms::multiple_outputs();
+    assert!(text == "This string contains the number 42");
+    assert!(number == 42);
+
+    // every interleaving of the functions has to lead to the same result
+    for seed in 0..50 {
+        #[ohua(schedule = Schedule::Random(seed))]
+        let (text, number) = general::algorithms::multiple_outputs();
+        assert!(
+            text == "This string contains the number 42" && number == 42,
+            "wrong result with seed {}",
+            seed
+        );
+    }
+}
+
+#[test]
+fn record_and_replay() {
+    let path = std::env::temp_dir().join("ohua_record_and_replay.trace");
+
+    #[ohua(record = &path)]
+    let x = general::algorithms::recording();
+    assert!(x == 43);
+
+    let trace = Trace::load(&path).unwrap();
+    let result_arc = trace.arcs().find(|arc| arc.ends_with("__result")).unwrap();
+    assert!(trace.values::<u32>(result_arc).unwrap() == vec![43]);
+
+    // the operator sending the result is the one executing `add_one`
+    let op_id: i32 = result_arc["sf_".len()..result_arc.find("_out_").unwrap()]
+        .parse()
+        .unwrap();
+    let replay = trace
+        .replay_fn(op_id, |(v,): (u32,)| produce_consume::add_one(v))
+        .unwrap();
+    assert!(replay.calls == 1 && replay.is_ok());
+
+    let replay = trace.replay_fn(op_id, |(v,): (u32,)| v + 2).unwrap();
+    assert!(!replay.is_ok());
+    assert!(replay.mismatches[0].actual == 44);
+}
+
+#[test]
+fn mocked_functions() {
+    fn accept_all(v: u32) -> Result<u32, RunError> {
+        Ok(v)
+    }
+
+    {
+        let _mock = ohua_runtime::mock::register(
+            "crate::general::produce_consume::halve_even",
+            accept_all as fn(u32) -> Result<u32, RunError>,
+        );
+
+        #[ohua(mock, timeout_ms = 5000)]
+        let odd = general::algorithms::user_errors(3);
+        assert!(odd.unwrap() == 4);
+    }
+
+    // the real function is back once the mock is dropped
+    #[ohua(mock, timeout_ms = 5000)]
+    let odd = general::algorithms::user_errors(3);
+    assert!(odd.is_err());
+}
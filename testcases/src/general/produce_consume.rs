@@ -1,5 +1,7 @@
 #![allow(dead_code)]
 
+use ohua_runtime::RunError;
+
 pub fn produce() -> u32 {
     println!("producing");
     42
@@ -8,3 +10,26 @@ pub fn produce() -> u32 {
 pub fn consume(_v: u32) {
     println!("consuming");
 }
+
+pub fn produce_slowly(delay_ms: u64) -> u32 {
+    std::thread::sleep(std::time::Duration::from_millis(delay_ms));
+    42
+}
+
+pub fn add_one(v: u32) -> u32 {
+    v + 1
+}
+
+pub fn fail_on_zero(v: u32) -> u32 {
+    if v == 0 {
+        panic!("zero is not allowed");
+    }
+    v
+}
+
+pub fn halve_even(v: u32) -> Result<u32, RunError> {
+    if v % 2 == 1 {
+        return Err(RunError::failed(format!("{} is odd", v)));
+    }
+    Ok(v / 2)
+}
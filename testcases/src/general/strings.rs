@@ -29,3 +29,30 @@ pub fn combine(s: String, num: i32) -> String {
 pub fn printout(s: String) {
     println!("{}", s);
 }
+
+// for the `destructuring` testcase
+
+pub fn generate_pair() -> (String, i32) {
+    (generate_string(), recv_number())
+}
+
+// for the `borrowed_args` testcase
+
+/// Deliberately not `Clone`: it can only be handed to several functions by reference.
+pub struct Words(Vec<String>);
+
+pub fn gen_words() -> Words {
+    Words(vec!["giant".into(), "spiders".into(), "everywhere".into()])
+}
+
+pub fn count_words(words: &Words) -> usize {
+    words.0.len()
+}
+
+pub fn longest_word(words: &Words) -> String {
+    words.0.iter().max_by_key(|w| w.len()).cloned().unwrap_or_default()
+}
+
+pub fn describe(count: usize, longest: String) -> String {
+    format!("{} words, longest: {}", count, longest)
+}
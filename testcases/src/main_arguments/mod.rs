@@ -27,6 +27,64 @@ fn main_arguments_across_ops() {
     println!("Computation result: {}", result);
 }
 
+#[test]
+fn algorithm_instance() {
+    #[ohua(instance)]
+    let algo = main_arguments::algorithms::reuse();
+
+    for word in &["fox", "dog"] {
+        let result = algo.run((word.to_string(),)).unwrap();
+        assert!(result == format!("{} over the hill{}", word, word));
+    }
+
+    // runs can be pipelined, results arrive in order
+    algo.feed(("cat".to_string(),)).unwrap();
+    algo.feed(("cow".to_string(),)).unwrap();
+    assert!(algo.next().unwrap() == "cat over the hillcat");
+    assert!(algo.next().unwrap() == "cow over the hillcow");
+}
+
+#[test]
+fn streaming_arguments() {
+    let words = vec![String::from("fox"), String::from("dog")];
+
+    #[ohua(stream)]
+    let results = main_arguments::algorithms::reuse(words);
+
+    let results: Vec<String> = results.collect();
+    assert!(results == vec!["fox over the hillfox", "dog over the hilldog"]);
+}
+
+#[test]
+fn streaming_arguments_from_channel() {
+    let (sender, words) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        for word in &["cat", "cow"] {
+            sender.send(word.to_string()).unwrap();
+        }
+    });
+
+    #[ohua(stream)]
+    let results = main_arguments::algorithms::reuse(words);
+
+    let results: Vec<String> = results.collect();
+    assert!(results == vec!["cat over the hillcat", "cow over the hillcow"]);
+}
+
+#[test]
+fn streaming_multiple_outputs() {
+    let words = vec![String::from("fox"), String::from("dog")];
+
+    #[ohua(stream)]
+    let (expanded, spliced) = main_arguments::algorithms::reuse_outputs(words);
+
+    // every output is a stream of its own
+    let expanded: Vec<String> = expanded.collect();
+    assert!(expanded == vec!["fox over the hill", "dog over the hill"]);
+    let spliced: Vec<String> = spliced.collect();
+    assert!(spliced == vec!["fox over the hillfox", "dog over the hilldog"]);
+}
+
 #[test]
 fn custom_types_via_envarcs() {
     use self::house::{EmotionalState, House, Human};
@@ -15,3 +15,10 @@ fn test_simple_tl_recursion() {
     let result = recursion::algorithms::simple_tl_recur();
     assert!(result == 0);
 }
+
+#[test]
+fn test_recursion_on_argument() {
+    #[ohua]
+    let result = recursion::algorithms::count_up(0);
+    assert!(result == 12);
+}
@@ -87,3 +87,27 @@ fn smap_in_smap() {
         ]
     )
 }
+
+#[test]
+fn smap_fold() {
+    #[ohua]
+    let x = smap::algorithms::fold_test();
+
+    assert!(x == 6264);
+}
+
+#[test]
+fn smap_filter() {
+    #[ohua]
+    let x = smap::algorithms::filter_test();
+
+    assert!(x == vec![2, 42, 12]);
+}
+
+#[test]
+fn smap_break() {
+    #[ohua]
+    let x = smap::algorithms::break_test();
+
+    assert!(x == vec![2, 42, 7, 12]);
+}
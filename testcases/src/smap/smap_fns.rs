@@ -1,3 +1,5 @@
+use ohua_runtime::lang::Step;
+
 // for `smap` test case
 
 pub fn gen_input() -> Vec<String> {
@@ -43,3 +45,26 @@ pub fn gen_nested_vec() -> Vec<Vec<String>> {
         vec!["modified".into(), "original".into()],
     ]
 }
+
+// for `smap_fold`
+pub fn add(acc: i32, x: i32) -> i32 {
+    acc + x
+}
+
+// for `smap_filter`
+pub fn keep_even(x: i32) -> Option<i32> {
+    if x % 2 == 0 {
+        Some(x)
+    } else {
+        None
+    }
+}
+
+// for `smap_break`
+pub fn below_100(x: i32) -> Step<i32> {
+    if x < 100 {
+        Step::Emit(x)
+    } else {
+        Step::Break
+    }
+}